@@ -198,7 +198,7 @@ def plot_all(sc_data, sim_data, xs):
 
     subplot_plot_twin_axis(
         3, 1, 3, xs, [
-            ('ang vel (deg/sec)', (rad2deg(sc_data.cur['ang_vel']), rad2deg(sim_data.cur['ang_vel'])), 0),
+            ('ang vel z (deg/sec)', (rad2deg(sc_data.cur['ang_vel_z']), rad2deg(sim_data.cur['ang_vel_z'])), 0),
             ('ang pos (deg)', (rad2deg(sc_data.cur['ang_pos']), rad2deg(sim_data.cur['ang_pos'])), 90),
         ],
     )
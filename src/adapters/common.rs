@@ -1,37 +1,158 @@
+use std::marker::PhantomData;
+
 use serde::{Serialize, Deserialize};
+use uom::si::{Dimension, Quantity, SI};
 use uom::si::f64::*;
 
-use crate::utils::math::Vec2;
+use crate::utils::math::{Vec3, Quaternion};
+use crate::utils::spacecraft::SpacecraftDynamic;
 
 
 pub trait Adapter {
+    /// Initial state vector, to initialize the navigation: true state for a
+    /// simulator, vessel state for KSP
+    fn read_state(&mut self) -> StateVector;
     fn read_sensors(&mut self) -> SensorsValues;
     fn write_actuators(&mut self, control: ActuatorsValues);
     fn export_to_csv_conf(&self);
     fn export_to_csv_cur(&self);
+    /// Engine off, run until the spacecraft is at rest on the ground. None if
+    /// the adapter can not tell (ie. not a simulator)
+    fn settle(&mut self) -> Option<Touchdown>;
+    /// Compare the navigation to the truth, every step (simulator only)
+    fn check_nav(&mut self, nav: &SpacecraftDynamic);
+    /// Navigation error over the flight. None if the adapter can not tell
+    fn nav_report(&self) -> Option<NavReport>;
 }
 
-/// Note: Sim is 2D, KSP will project on the (velocity vector, local vertical) plane
+/// Note: local frame is x downrange, y up, z cross-range, cf. utils::frames
 #[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct StateVector {
+    pub pos: Vec3<Length>,          // ground distance and altitude
+    pub vel: Vec3<Velocity>,        // surface-relative
+    pub att: Quaternion,            // body to local frame
+}
+
+/// Note: local frame is x downrange, y up, z cross-range. KSP maps x to east
+/// and z to south.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
 #[derive(Serialize, Deserialize)]
 pub struct SensorsValues {
     pub dt_step: Time,
 
-    // from accelerometers
-    pub spacecraft_acc: Vec2<Acceleration>,
-    pub spacecraft_ang_acc: AngularAcceleration,
-    // from radar altimeter
+    // from accelerometers, local frame. None if no new sample (cf.
+    // adapters::ksp)
+    pub spacecraft_acc: Option<Vec3<Acceleration>>,
+    // from gyroscopes
+    pub spacecraft_ang_vel: Vec3<AngularVelocity>,      // body frame, inertial
+    // from radar altimeter, None if no echo (out of range, tilted, or between
+    // two measurements). Slant range when tilted, cf. utils::sensors
     pub spacecraft_altitude: Option<Length>,
+    // from Doppler velocimeter, surface-relative, body frame. None if no
+    // valid measurement
+    pub spacecraft_vel: Option<Vec3<Velocity>>,
+    // from a compass / star tracker, cf. Quaternion::to_euler()
+    pub spacecraft_heading: Option<Angle>,
+    // from the landing legs contact probes, latched
+    pub spacecraft_contact: bool,
+}
+
+/// Touchdown, from the first contact of the landing legs to rest
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct Touchdown {
+    pub t: Time,                    // first contact
+    pub pos: Vec3<Length>,          // local frame, at first contact
+    pub vel: Vec3<Velocity>,        // local frame, surface-relative, at first contact
+    pub slope: Angle,               // of the terrain, at first contact
+    pub safe: bool,                 // slope below the terrain max slope
+    pub leg_loads: Vec<Force>,      // peak, per leg
+    pub bounce: Length,             // highest clearance of the footpads, after the first contact
+    pub t_rest: Option<Time>,
+    pub heading: Angle,             // latest attitude, cf. Quaternion::to_euler()
+    pub pitch: Angle,
+    pub roll: Angle,
+    pub tilt: Angle,                // latest, between the thrust axis and the terrain normal
+    pub tipped_over: bool,          // tilt beyond the legs footprint
+}
 
-    // TODO heading
+/// Navigation error: navigation minus truth
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct NavError {
+    pub t: Time,
+    pub pos: Vec3<Length>,          // local frame
+    pub vel: Vec3<Velocity>,        // local frame, surface-relative
+    pub att: Angle,                 // rotation between the navigation and the true attitudes
+    pub fuel_mass: Mass,
 }
 
-/// Note: Sim is 2D, KSP will project on the (velocity vector, local vertical) plane
+/// Statistics of an error over the flight, of its norm for a vector
 #[derive(Debug)]
+#[derive(Clone, Copy)]
 #[derive(Serialize, Deserialize)]
-pub struct ActuatorsValues {
-    pub engine_throttle: Ratio, // range: [0; 1] of max (nominal) thrust
-    pub engine_gimbal: Ratio,   // range: [-1; +1] of max gimbal
+pub struct ErrorStats<T> {
+    pub rms: T,
+    pub max: T,
+    pub last: T,                    // at the end of the flight
+}
 
-    // TODO heading
+/// Navigation error over the flight
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct NavReport {
+    pub steps: usize,
+    pub pos: ErrorStats<Length>,
+    pub vel: ErrorStats<Velocity>,
+    pub att: ErrorStats<Angle>,
+    pub fuel_mass: ErrorStats<Mass>,
+}
+
+impl<D: Dimension + ?Sized> ErrorStats<Quantity<D, SI<f64>, f64>> {
+    /// Statistics of the absolute values (there is at least one value)
+    pub fn new(values: &[Quantity<D, SI<f64>, f64>]) -> Self {
+        let abs: Vec<f64> = values.iter().map(|v| v.value.abs()).collect();
+        let rms = (abs.iter().map(|v| v*v).sum::<f64>()/(abs.len() as f64)).sqrt();
+        let max = abs.iter().fold(0.0, |acc: f64, v| acc.max(*v));
+        let q = |value: f64| Quantity {dimension: PhantomData, units: PhantomData, value: value};
+
+        ErrorStats {
+            rms: q(rms),
+            max: q(max),
+            last: q(abs[abs.len()-1]),
+        }
+    }
+}
+
+impl NavReport {
+    pub fn new(errors: &[NavError]) -> Option<Self> {
+        if errors.is_empty() {
+            return None;
+        }
+
+        Some(NavReport {
+            steps: errors.len(),
+            pos: ErrorStats::new(&errors.iter().map(|e| e.pos.norm()).collect::<Vec<Length>>()),
+            vel: ErrorStats::new(&errors.iter().map(|e| e.vel.norm()).collect::<Vec<Velocity>>()),
+            att: ErrorStats::new(&errors.iter().map(|e| e.att).collect::<Vec<Angle>>()),
+            fuel_mass: ErrorStats::new(&errors.iter().map(|e| e.fuel_mass).collect::<Vec<Mass>>()),
+        })
+    }
+}
+
+/// Note: engine gimbal pitch is around body z, yaw is around body y, roll
+/// (RCS) is around body x (thrust axis)
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct ActuatorsValues {
+    pub engine_throttle: Ratio,     // range: [0; 1] of max (nominal) thrust
+    pub engine_gimbal: Ratio,       // range: [-1; +1] of max gimbal, pitch
+    pub engine_gimbal_yaw: Ratio,   // range: [-1; +1] of max gimbal
+    pub rcs_roll: Ratio,            // range: [-1; +1] of max RCS roll torque
 }
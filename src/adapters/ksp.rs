@@ -1,29 +1,39 @@
+use std::{thread, time};
+
 use pyo3::prelude::*;
 use serde::{Serialize, Deserialize};
 use uom::si::f64::*;
-use uom::si::acceleration::meter_per_second_squared;
 use uom::si::angle::{degree, radian};
-use uom::si::angular_velocity::radian_per_second;
-use uom::si::angular_acceleration::radian_per_second_squared;
 use uom::si::ratio::ratio;
 use uom::si::time::second;
 use uom::si::velocity::meter_per_second;
-use crate::adapters::common::{Adapter, SensorsValues, ActuatorsValues};
-use crate::squared;
-use crate::utils::math::Vec2;
+use uom::si::length::meter;
+use crate::adapters::common::{Adapter, StateVector, SensorsValues, ActuatorsValues, Touchdown, NavReport};
+use crate::conf::Conf;
+use crate::utils::math::{Vec3, Quaternion, heading_pitch};
+use crate::utils::sensors::Sensors;
+use crate::utils::spacecraft::SpacecraftDynamic;
+
+
+/// Wait between two reads of the mission elapsed time, ms
+const KSP_TICK_WAIT: u64 = 5;
+
+/// No acceleration differentiated over a longer step, s
+const KSP_ACC_DT_MAX: f64 = 1.0;
 
 
 pub struct AdapterKSP<'py> {
     conn: &'py PyAny,       // krpc connection object
     vessel: &'py PyAny,
     body_ref_frame: &'py PyAny,
+    inertial_ref_frame: &'py PyAny,             // body's non-rotating reference frame
     surface_ref_frame: &'py PyAny,
+    sensors: Sensors,                           // noise injected in the KSP values
+    initial_sc_pos: Vec3<Length>,               // the landing site is unknown to KSP, cf. read_state()
+    dt_step: Time,                              // nominal, when the game goes back in time
 
     last_met: Time,
-    last_vel_vert: Velocity,
-    last_vel_horiz: Velocity,
-    last_ang_pos: Angle,                        // spacecraft's pitch, not velocity's
-    last_ang_vel: AngularVelocity,
+    last_vel: Vec3<Velocity>,                   // local frame
 }
 
 
@@ -34,6 +44,9 @@ struct RawSensorsValues {
     vel_north: Velocity,
     vel_east: Velocity,
     ang_pos: Angle,
+    heading: Angle,
+    roll: Angle,
+    surface_altitude: Length,
 }
 
 
@@ -44,8 +57,8 @@ impl RawSensorsValues {
 }
 
 
-pub fn init<'py>(py: &'py Python) -> Result<AdapterKSP<'py>, &'static str> {
-    match init_(py) {
+pub fn init<'py>(py: &'py Python, conf: &Conf) -> Result<AdapterKSP<'py>, &'static str> {
+    match init_(py, conf) {
         Err(e) => {
             // TODO: handle errors better
             println!("Python error:");
@@ -58,29 +71,118 @@ pub fn init<'py>(py: &'py Python) -> Result<AdapterKSP<'py>, &'static str> {
     }
 }
 
-fn init_<'py>(py: &'py Python) -> PyResult<AdapterKSP<'py>> {
+fn init_<'py>(py: &'py Python, conf: &Conf) -> PyResult<AdapterKSP<'py>> {
     let krpc = py.import("krpc")?;
     let conn = krpc.call_method0("connect")?;
 
     let vessel = conn.getattr("space_center")?.getattr("active_vessel")?;
     let body_ref_frame = vessel.getattr("orbit")?.getattr("body")?.getattr("reference_frame")?;
+    let inertial_ref_frame = vessel.getattr("orbit")?.getattr("body")?.getattr("non_rotating_reference_frame")?;
     let surface_ref_frame = vessel.getattr("surface_reference_frame")?;
 
     Ok(AdapterKSP {
         conn: conn,
         vessel: vessel,
         body_ref_frame: body_ref_frame,
+        inertial_ref_frame: inertial_ref_frame,
         surface_ref_frame: surface_ref_frame,
+        sensors: Sensors::new(&conf.s.sensors),
+        initial_sc_pos: conf.s.initial_sc_pos,
+        dt_step: conf.dt_step,
 
         last_met: Time::new::<second>(0.0),
-        last_vel_vert: Velocity::new::<meter_per_second>(0.0),
-        last_vel_horiz: Velocity::new::<meter_per_second>(0.0),
-        last_ang_pos: Angle::new::<radian>(0.0),
-        last_ang_vel: AngularVelocity::new::<radian_per_second>(0.0),
+        last_vel: Vec3::from_value(Vec3::zero()),
     })
 }
 
+impl AdapterKSP<'_> {
+    /// Mission elapsed time, of the latest physics tick
+    fn read_met(&self) -> Time {
+        Time::new::<second>(self.vessel
+            .getattr("met").unwrap()
+            .extract().unwrap())
+    }
+
+    /// Surface-relative velocity: vertical, north, east (cf. the kRPC velocity
+    /// bug, read_sensors())
+    fn read_velocity(&self) -> (Velocity, Velocity, Velocity) {
+        let vel_ = self.vessel
+            .call_method1("flight", (self.body_ref_frame, )).unwrap()
+            .getattr("velocity").unwrap();
+        let vels: (f64, f64, f64) = self.conn
+            .getattr("space_center").unwrap()
+            .call_method1("transform_direction", (vel_, self.body_ref_frame, self.surface_ref_frame)).unwrap()
+            .extract().unwrap();
+
+        (
+            Velocity::new::<meter_per_second>(vels.0),
+            Velocity::new::<meter_per_second>(vels.1),
+            Velocity::new::<meter_per_second>(vels.2),
+        )
+    }
+}
+
+
+/// Attitude (body to local frame) and our own heading, from KSP's pitch,
+/// heading and roll
+fn attitude(ang_pos: Angle, heading: Angle, roll: Angle) -> (Quaternion, Angle) {
+    // thrust axis from KSP's pitch and heading, then our own Euler angles
+    let (ang_pos_rad, heading_rad) = (ang_pos.get::<radian>(), heading.get::<radian>());
+    let axis = Vec3 {
+        x: ang_pos_rad.cos()*heading_rad.sin(),
+        y: ang_pos_rad.sin(),
+        z: -ang_pos_rad.cos()*heading_rad.cos(),
+    };
+    let (sc_heading, sc_pitch) = heading_pitch(axis);
+
+    (Quaternion::from_euler(sc_heading, sc_pitch, roll), sc_heading)
+}
+
+
+/// Body rates, from the kRPC angular velocity in the surface reference frame
+/// (x up, y north, z east)
+///
+/// kRPC frames are left-handed, ours is right-handed (x east, y up, z south):
+/// the axes mapping flips the handedness, hence the sign of an axial vector.
+fn body_angular_velocity(ang_vel_surface: (f64, f64, f64), att: Quaternion) -> Vec3<AngularVelocity> {
+    let (up, north, east) = ang_vel_surface;
+    let ang_vel_local = Vec3 {x: -east, y: -up, z: north};
+
+    Vec3::from_value(att.rotate_inv(ang_vel_local))
+}
+
+
 impl Adapter for AdapterKSP<'_> {
+    /// The altitude is above the mean radius (kRPC flight.mean_altitude). KSP
+    /// does not know the landing site: the ground position (relative to it)
+    /// is the scenario's.
+    fn read_state(&mut self) -> StateVector {
+        let met = self.read_met();
+
+        let (vel_vert, vel_north, vel_east) = self.read_velocity();
+        let vel = Vec3 {x: vel_east, y: vel_vert, z: -vel_north};
+
+        let flight = self.vessel
+            .call_method1("flight", (self.surface_ref_frame, )).unwrap();
+        let ang_pos = Angle::new::<degree>(flight.getattr("pitch").unwrap().extract().unwrap());
+        let heading = Angle::new::<degree>(flight.getattr("heading").unwrap().extract().unwrap());
+        let roll = Angle::new::<degree>(flight.getattr("roll").unwrap().extract().unwrap());
+        let altitude = Length::new::<meter>(flight.getattr("mean_altitude").unwrap().extract().unwrap());
+
+        let (att, _heading) = attitude(ang_pos, heading, roll);
+
+        // the first accelerations are differentiated from this state
+
+        self.last_met = met;
+        self.last_vel = vel;
+
+        StateVector {
+            pos: Vec3 {x: self.initial_sc_pos.x, y: altitude, z: self.initial_sc_pos.z},
+            vel: vel,
+            att: att,
+        }
+    }
+
     /// ## kRPC API and conventions:
     ///
     /// flight.pitch: The pitch of the vessel relative to the horizon, in
@@ -89,8 +191,17 @@ impl Adapter for AdapterKSP<'_> {
     /// flight.heading: The heading of the vessel (its angle relative to north),
     ///     in degrees. A value between 0° and 360°.
     ///
+    /// flight.roll: The roll of the vessel relative to the horizon, in
+    ///     degrees. A value between -180° and +180°.
+    ///
     /// Source: https://krpc.github.io/krpc/python/api/space-center/flight.html
     ///
+    /// vessel.angular_velocity(reference_frame): The angular velocity of the
+    ///     vessel in the given reference frame, in radians per second. The
+    ///     direction of the vector indicates the axis of rotation.
+    ///
+    /// Source: https://krpc.github.io/krpc/python/api/space-center/vessel.html
+    ///
     /// ## kRPC velocity bug
     ///
     /// Note canonical method shown below has a bug, using workaround.
@@ -103,11 +214,14 @@ impl Adapter for AdapterKSP<'_> {
     /// velocity = vessel.flight(ex1_ref_frame).velocity
     fn read_sensors(&mut self) -> SensorsValues {
 
-        // Get raw data
+        // Get raw data, of a new physics tick: the game may be paused, or this
+        // loop faster than the physics
 
-        let met = Time::new::<second>(self.vessel
-            .getattr("met").unwrap()
-            .extract().unwrap());
+        let mut met = self.read_met();
+        while met == self.last_met {
+            thread::sleep(time::Duration::from_millis(KSP_TICK_WAIT));
+            met = self.read_met();
+        }
 
         /*
             vel.heading
@@ -116,23 +230,28 @@ impl Adapter for AdapterKSP<'_> {
             flight.pitch
         */
 
-        let vel_ = self.vessel
-            .call_method1("flight", (self.body_ref_frame, )).unwrap()
-            .getattr("velocity").unwrap();
-        let vels: (f64, f64, f64) = self.conn
+        let (vel_vert, vel_north, vel_east) = self.read_velocity();
+
+        let flight = self.vessel
+            .call_method1("flight", (self.surface_ref_frame, )).unwrap();
+        let ang_pos = Angle::new::<degree>(flight.getattr("pitch").unwrap().extract().unwrap());
+        let heading = Angle::new::<degree>(flight.getattr("heading").unwrap().extract().unwrap());
+        let roll = Angle::new::<degree>(flight.getattr("roll").unwrap().extract().unwrap());
+
+        let ang_vel_ = self.vessel
+            .call_method1("angular_velocity", (self.inertial_ref_frame, )).unwrap();
+        let ang_vels: (f64, f64, f64) = self.conn
             .getattr("space_center").unwrap()
-            .call_method1("transform_direction", (vel_, self.body_ref_frame, self.surface_ref_frame)).unwrap()
+            .call_method1("transform_direction", (ang_vel_, self.inertial_ref_frame, self.surface_ref_frame)).unwrap()
             .extract().unwrap();
-        let vel_vert = Velocity::new::<meter_per_second>(vels.0);
-        let vel_north = Velocity::new::<meter_per_second>(vels.1);
-        let vel_east = Velocity::new::<meter_per_second>(vels.2);
+        let surface_altitude = Length::new::<meter>(flight.getattr("surface_altitude").unwrap().extract().unwrap());
 
-        let vel_horiz = (squared!(vel_north) + squared!(vel_east)).sqrt();
-
-        let ang_pos = Angle::new::<degree>(self.vessel
-            .call_method1("flight", (self.surface_ref_frame, )).unwrap()
-            .getattr("pitch").unwrap()
-            .extract().unwrap());
+        // no contact probes, but the vessel situation
+        let situation: String = self.vessel
+            .getattr("situation").unwrap()
+            .getattr("name").unwrap()
+            .extract().unwrap();
+        let contact = (situation == "landed") || (situation == "splashed");
 
         let rsv = RawSensorsValues {
             met: met,
@@ -140,39 +259,55 @@ impl Adapter for AdapterKSP<'_> {
             vel_north: vel_north,
             vel_east: vel_east,
             ang_pos: ang_pos,
+            heading: heading,
+            roll: roll,
+            surface_altitude: surface_altitude,
         };
 
         rsv.export_to_csv();
 
         // Compute sensor data
 
-        let mut dt: Time = met - self.last_met;
-        let mut acc_x: Acceleration = (vel_horiz-self.last_vel_horiz)/dt;
-        let mut acc_y: Acceleration = (vel_vert-self.last_vel_vert)/dt;
-        let mut ang_vel: AngularVelocity = ((ang_pos-self.last_ang_pos)/dt).into();
-        let mut ang_acc: AngularAcceleration = ((ang_vel-self.last_ang_vel)/dt).into();
-
-        if (dt.abs() < Time::new::<second>(0.001)) || (dt.abs() > Time::new::<second>(1.000)) {
-            dt = Time::new::<second>(1.0);
-            acc_x = Acceleration::new::<meter_per_second_squared>(0.0);
-            acc_y = Acceleration::new::<meter_per_second_squared>(0.0);
-            ang_vel = AngularVelocity::new::<radian_per_second>(0.0);
-            ang_acc = AngularAcceleration::new::<radian_per_second_squared>(0.0);
-        }
+        // local frame: x east, y up, z south
+
+        let vel = Vec3 {x: vel_east, y: vel_vert, z: -vel_north};
+
+        let (att, sc_heading) = attitude(ang_pos, heading, roll);
+
+        // accelerations differentiated from the velocities, none after a
+        // hitch, or when the game went back in time (revert, quickload)
+        let dt_met: Time = met - self.last_met;
+        let (dt, acc) = if dt_met <= Time::new::<second>(0.0) {
+            (self.dt_step, None)
+        } else if dt_met > Time::new::<second>(KSP_ACC_DT_MAX) {
+            (dt_met, None)
+        } else {
+            (dt_met, Some((vel-self.last_vel)/dt_met))
+        };
+
+        // gyroscopes: body rates, inertial
+        let ang_vel = body_angular_velocity(ang_vels, att);
 
         // Update internal state and return
 
         self.last_met = met;
-        self.last_vel_vert = vel_vert;
-        self.last_vel_horiz = vel_horiz;
-        self.last_ang_pos = ang_pos;
-        self.last_ang_vel = ang_vel;
+        self.last_vel = vel;
+
+        // Doppler velocimeter and radar altimeter: flight velocity in the
+        // body frame, and height above the terrain, with noise
+
+        let vel_body = Vec3::from_value(att.rotate_inv(vel.value()));
+        let vel_meas = self.sensors.doppler_velocimeter(dt, Some(vel_body), Some(surface_altitude), att);
+        let alt_meas = self.sensors.radar_altimeter(dt, Some(surface_altitude), att);
 
         SensorsValues {
             dt_step: dt,
-            spacecraft_acc: Vec2 {x: acc_x, y: acc_y},
-            spacecraft_ang_acc: ang_acc,
-            spacecraft_altitude: None,  // TODO
+            spacecraft_acc: acc,
+            spacecraft_ang_vel: ang_vel,
+            spacecraft_altitude: alt_meas,
+            spacecraft_vel: vel_meas,
+            spacecraft_heading: Some(sc_heading),
+            spacecraft_contact: contact,
         }
     }
 
@@ -192,12 +327,8 @@ impl Adapter for AdapterKSP<'_> {
 
         ves_control.setattr("throttle", control.engine_throttle.get::<ratio>()).unwrap();
         ves_control.setattr("pitch", -control.engine_gimbal.get::<ratio>()).unwrap();
-
-        /*
-            pitch
-            yaw
-            (roll)
-        */
+        ves_control.setattr("yaw", control.engine_gimbal_yaw.get::<ratio>()).unwrap();
+        ves_control.setattr("roll", control.rcs_roll.get::<ratio>()).unwrap();
     }
 
     fn export_to_csv_conf(&self) {
@@ -207,4 +338,49 @@ impl Adapter for AdapterKSP<'_> {
     fn export_to_csv_cur(&self) {
         // Not implemented
     }
+
+    fn settle(&mut self) -> Option<Touchdown> {
+        // KSP simulates the touchdown itself
+        None
+    }
+
+    fn check_nav(&mut self, _nav: &SpacecraftDynamic) {
+        // No truth
+    }
+
+    fn nav_report(&self) -> Option<NavReport> {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::angular_velocity::radian_per_second;
+
+    use crate::assert_approx_eq;
+
+    #[test]
+    fn test_ksp_1_body_angular_velocity() {
+        let deg = |angle: f64| Angle::new::<degree>(angle);
+        let rate = |w: f64| AngularVelocity::new::<radian_per_second>(w);
+
+        // horizontal, heading east: the body axes are the local ones, a pitch
+        // up (east toward up) is around north in kRPC, +z in the body frame
+        let (att, _heading) = attitude(deg(0.0), deg(90.0), deg(0.0));
+        let ang_vel = body_angular_velocity((0.0, 0.1, 0.0), att);
+        assert_approx_eq!(ang_vel.x, rate(0.0), rate(1e-12));
+        assert_approx_eq!(ang_vel.y, rate(0.0), rate(1e-12));
+        assert_approx_eq!(ang_vel.z, rate(0.1), rate(1e-12));
+
+        // vertical: a turn around up is around the thrust axis, north toward
+        // east (clockwise seen from above) is negative
+        let (att, _heading) = attitude(deg(90.0), deg(90.0), deg(0.0));
+        let ang_vel = body_angular_velocity((0.1, 0.0, 0.0), att);
+        assert_approx_eq!(ang_vel.x, rate(-0.1), rate(1e-12));
+        assert_approx_eq!(ang_vel.y, rate(0.0), rate(1e-12));
+        assert_approx_eq!(ang_vel.z, rate(0.0), rate(1e-12));
+    }
 }
@@ -1,17 +1,21 @@
-use crate::adapters::common::{Adapter, SensorsValues, ActuatorsValues};
+use crate::adapters::common::{Adapter, StateVector, SensorsValues, ActuatorsValues, Touchdown, NavReport};
 use crate::conf::Conf;
 use crate::sim::Sim;
+use crate::utils::math::Vec3;
+use crate::utils::sensors::Sensors;
+use crate::utils::spacecraft::SpacecraftDynamic;
 
 
 pub struct AdapterSim {
     sim: Sim,
+    sensors: Sensors,
 }
 
 
 pub fn init(conf: Conf) -> Result<AdapterSim, &'static str> {
     match init_(conf) {
-        Err(_) => {
-            println!("Error adapter::sim::init()");
+        Err(e) => {
+            println!("Error adapter::sim::init(): {}", e);
             Err("Error adapter::sim::init()")
         },
         Ok(sim) => {
@@ -20,25 +24,45 @@ pub fn init(conf: Conf) -> Result<AdapterSim, &'static str> {
     }
 }
 
-fn init_(conf: Conf) -> Result<AdapterSim, &'static str> {
-    let sim = Sim::new(conf);
+fn init_(conf: Conf) -> Result<AdapterSim, String> {
+    let sensors = Sensors::new(&conf.s.sensors);
+    let sim = Sim::new(conf)?;
 
     Ok(AdapterSim {
         sim: sim,
+        sensors: sensors,
     })
 }
 
 impl Adapter for AdapterSim {
+    fn read_state(&mut self) -> StateVector {
+        self.sim.read_state()
+    }
+
     fn read_sensors(&mut self) -> SensorsValues {
         let mut ret = self.sim.read_sensors();
 
-        // inject noise and bias
+        // inject noise and bias, the accelerometers are mounted along the body axes
+        // and only sense the specific force (the gravity is added back, as known)
+
+        let att = self.sim.attitude();
+        let gravity = self.sim.gravity();
+        ret.spacecraft_acc = ret.spacecraft_acc.map(|acc| {
+            let acc_body = Vec3::from_value(att.rotate_inv((acc - gravity).value()));
+            let acc_meas = self.sensors.accelerometer(ret.dt_step, acc_body);
+            Vec3::from_value(att.rotate(acc_meas.value())) + gravity
+        });
+
+        ret.spacecraft_ang_vel = self.sensors.gyro(ret.dt_step, ret.spacecraft_ang_vel);
+
+        ret.spacecraft_vel = self.sensors.doppler_velocimeter(ret.dt_step, ret.spacecraft_vel, ret.spacecraft_altitude, att);
+        ret.spacecraft_altitude = self.sensors.radar_altimeter(ret.dt_step, ret.spacecraft_altitude, att);
 
         ret
     }
 
     fn write_actuators(&mut self, control: ActuatorsValues) {
-        let mut c = control;
+        let c = control;
 
         // inject noise and bias
         // c.engine_throttle *= 0.98;
@@ -53,4 +77,112 @@ impl Adapter for AdapterSim {
     fn export_to_csv_cur(&self) {
         self.sim.export_to_csv_cur();
     }
+
+    fn settle(&mut self) -> Option<Touchdown> {
+        self.sim.settle()
+    }
+
+    fn check_nav(&mut self, nav: &SpacecraftDynamic) {
+        let err = self.sim.check_nav(nav);
+        println!("[LOGD:Sim::check_nav] CSV={}", serde_json::to_string(&err).unwrap());
+    }
+
+    fn nav_report(&self) -> Option<NavReport> {
+        self.sim.nav_report()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::f64::*;
+    use uom::si::acceleration::meter_per_second_squared;
+    use uom::si::angle::degree;
+    use uom::si::length::meter;
+    use uom::si::mass::kilogram;
+    use uom::si::ratio::ratio;
+    use uom::si::time::second;
+    use uom::si::velocity::meter_per_second;
+
+    use crate::conf::{Scenario, SubCommand};
+    use crate::gnc::common::Spacecraft;
+    use crate::gnc::navigation::{nav, nav_init};
+    use crate::utils::sensors::ImuErrorConf;
+
+    #[test]
+    fn test_adapter_sim_1_nav_regression() {
+        // navigation against the truth, over a flat terrain (the navigation
+        // does not know the craters) and with a fixed control
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.sim_terrain = None;
+        scenario.nav_init = None;
+
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(0.1), Time::new::<second>(0.0), scenario);
+        let mut adapter = init(conf.clone()).unwrap();
+        let mut sc = Spacecraft::new(conf);
+        let state = adapter.read_state();
+        nav_init(&mut sc, &state);
+
+        for _ in 0..600 {
+            nav(&mut sc, &adapter.read_sensors());
+
+            sc.cur.eng_throttle = Ratio::new::<ratio>(0.8);
+            adapter.write_actuators(ActuatorsValues {
+                engine_throttle: sc.cur.eng_throttle,
+                engine_gimbal: Ratio::new::<ratio>(0.02),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+
+            adapter.check_nav(&sc.cur);
+        }
+
+        let report = adapter.nav_report().unwrap();
+        assert_eq!(report.steps, 600);
+        assert!(sc.fdir.events.is_empty());
+        assert!(report.pos.max < Length::new::<meter>(5.0));
+        assert!(report.vel.max < Velocity::new::<meter_per_second>(0.5));  // mostly the lag
+        assert!(report.att.max < Angle::new::<degree>(2.0));
+        assert!(report.fuel_mass.max < Mass::new::<kilogram>(50.0));
+    }
+
+    #[test]
+    fn test_adapter_sim_2_specific_force() {
+        // engine off, falling from rest: the accelerometers sense nothing,
+        // their scale factor and misalignment do not apply to the gravity
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.sim_terrain = None;
+        scenario.initial_sc_vel = Vec3::from_value(Vec3 {x: 0.0, y: 0.0, z: 0.0});
+        scenario.sensors.accelerometer = Some(ImuErrorConf {
+            noise_density: 0.0,
+            bias: 0.0,
+            bias_instability: 0.0,
+            bias_time: Time::new::<second>(300.0),
+            scale_factor: Ratio::new::<ratio>(0.1),
+            misalignment: Angle::new::<degree>(5.0),
+            quantization: 0.0,
+        });
+
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(0.1), Time::new::<second>(0.0), scenario);
+        let mut adapter = init(conf).unwrap();
+
+        for _ in 0..10 {
+            adapter.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.0),
+                engine_gimbal: Ratio::new::<ratio>(0.0),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+        }
+
+        let truth = adapter.sim.read_sensors().spacecraft_acc.unwrap();
+        let measured = adapter.read_sensors().spacecraft_acc.unwrap();
+
+        assert!(truth.y < Acceleration::new::<meter_per_second_squared>(-1.0));
+        assert!((measured - truth).norm() < Acceleration::new::<meter_per_second_squared>(1e-3));
+    }
 }
@@ -3,8 +3,19 @@ use uom::si::f64::*;
 use uom::si::ratio::ratio;
 use uom::si::time::second;
 
-use crate::utils::math::Vec2;
+use crate::gnc::divert::DivertConf;
+use crate::gnc::engine::EngineEstimatorConf;
+use crate::gnc::fdir::FdirConf;
+use crate::gnc::gfold::GfoldConf;
+use crate::gnc::kalman::KalmanConf;
+use crate::gnc::navigation::NavInitConf;
+use crate::gnc::peg::PegConf;
+use crate::gnc::sequencer::PhaseConf;
+use crate::sim::SimEngineConf;
+use crate::utils::math::Vec3;
 use crate::utils::bodies::Body;
+use crate::utils::sensors::SensorsConf;
+use crate::utils::terrain::TerrainConf;
 
 
 #[derive(Clone, Copy)]
@@ -20,38 +31,66 @@ pub enum SubCommand {
 #[derive(Clone, Copy)]
 #[derive(PartialEq)]
 #[derive(Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum TgoEstimate {
     TgoGivenFixed,
     TgoEstimateFixed,
     TgoEstimateUpdating,
 }
 
+/// Navigation method: integrate the IMU only, or fuse it with the radar
+/// altimeter and the Doppler velocimeter (cf. gnc::kalman)
 #[derive(Clone, Copy)]
 #[derive(PartialEq)]
 #[derive(Serialize, Deserialize)]
+pub enum NavSpacecraft {
+    NavDeadReckoning,
+    NavKalman,
+}
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum GuiSpacecraft {
     GuiDescent,
     GuiAscentToOrbit,
     GuiAscentToHover,
+    GuiVerticalDescent,             // terminal descent, until touchdown
+    GuiAscentPeg,                   // ascent to orbit, linear tangent steering (cf. gnc::peg)
+    GuiGfold,                       // powered descent, tracks a fuel optimal trajectory (cf. gnc::gfold)
 }
 
+#[derive(Debug)]
 #[derive(Clone, Copy)]
 #[derive(PartialEq)]
 #[derive(Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum CtrSpacecraft {
     CtrSpacecraftDescent,
     CtrSpacecraftAscentToOrbit,
     CtrSpacecraftAscentToHover,
 }
 
-
+/// Numerical integration method used by the simulator
 #[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum SimIntegrator {
+    SimEuler,
+    SimRk4,
+    SimDormandPrince,
+}
+
+
+#[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct Scenario {
     pub body: Body,                     // orbited body, containing info about gravity, atmospheric, etc
 
-    pub initial_sc_pos: Vec2<Length>,
-    pub initial_sc_vel: Vec2<Velocity>,
+    pub initial_sc_pos: Vec3<Length>,      // x: downrange, y: altitude, z: cross-range
+    pub initial_sc_vel: Vec3<Velocity>,
     pub initial_sc_ang_pos: Angle,
     pub initial_sc_fuel_mass: Mass,
 
@@ -59,10 +98,19 @@ pub struct Scenario {
     pub sc_height: Length,
     pub sc_dry_mass: Mass,
     pub sc_cd: Ratio,                       // drag coefficient (https://en.wikipedia.org/wiki/Drag_coefficient)
+    pub sc_cp_offset: Length,               // center of pressure, on the thrust axis, from the center of mass
 
     pub sc_nominal_thrust: Force,
     pub sc_nominal_isp: Time,
     pub sc_nominal_mass_flow: MassRate,
+    pub sc_rcs_roll_torque: Torque,         // max roll torque of the RCS
+
+    pub sc_leg_count: usize,
+    pub sc_leg_radius: Length,              // footpads, from the thrust axis
+    pub sc_leg_length: Length,              // footpads, below the center of mass (along the thrust axis)
+    pub sc_leg_stiffness: f64,              // N/m, per leg
+    pub sc_leg_damping: f64,                // N/(m/s), per leg
+    pub sc_leg_friction: Ratio,             // Coulomb friction coefficient
 
     pub gui_af_x: Acceleration,
     pub gui_vf_x: Velocity,
@@ -72,6 +120,16 @@ pub struct Scenario {
     pub gui_vf_y: Velocity,
     pub gui_pf_y: Length,
 
+    pub gui_af_z: Acceleration,
+    pub gui_vf_z: Velocity,
+    pub gui_pf_z: Length,
+
+    pub landing_radius: Length,             // max horizontal distance of the true touchdown from the landing site
+
+    pub gui_vertical_tau: Time,             // used only with GuiSpacecraft::GuiVerticalDescent
+    pub gui_pitchover: Time,                // used only with GuiSpacecraft::GuiAscentToOrbit
+                                            //    | GuiSpacecraft::GuiAscentPeg, constant pitch rate from the vertical
+
     pub ctr_eng_gimbal_tau: Time,
     pub ctr_eng_gimbal_kp: Option<Ratio>,
     pub ctr_eng_gimbal_kd: Option<Time>,
@@ -88,8 +146,25 @@ pub struct Scenario {
                                         //    but on average you want the  engine to run at X %
     pub tgo_stop: Time,                 // stop the loop a few seconds before touchdown, to prevent guidance
                                         //    from diverging to +/- inf
+    pub nav_init: Option<NavInitConf>,  // initial navigation errors, none if null
+    pub nav_spacecraft: NavSpacecraft,
+    pub nav_kalman: Option<KalmanConf>,     // used only with NavSpacecraft::NavKalman
+    pub nav_engine: Option<EngineEstimatorConf>,    // nominal mass flow, thrust and Isp if null
+    pub nav_fdir: Option<FdirConf>,     // only non-finite values are discarded if null
     pub gui_spacecraft: GuiSpacecraft,
+    pub gui_peg: Option<PegConf>,       // used only with GuiSpacecraft::GuiAscentPeg
+    pub gui_gfold: Option<GfoldConf>,   // used only with GuiSpacecraft::GuiGfold
     pub ctr_spacecraft: CtrSpacecraft,
+    pub seq_phases: Option<Vec<PhaseConf>>, // single phase (gui_*, gui_spacecraft, ctr_spacecraft) if null
+    pub seq_divert: Option<DivertConf>, // fixed landing site if null
+
+    // Simulator
+
+    pub sim_integrator: SimIntegrator,
+    pub sim_integrator_tol: f64,        // used only with SimIntegrator::SimDormandPrince
+    pub sim_terrain: Option<TerrainConf>,   // flat (mean radius) if null
+    pub sim_engine: Option<SimEngineConf>,  // nominal if null
+    pub sensors: SensorsConf,           // simulated sensors errors
 }
 
 impl Scenario {
@@ -110,12 +185,36 @@ impl Scenario {
             kd_scale*scenario.ctr_eng_gimbal_tau.get::<second>().powf(kd_exponent)
         ));
 
+        if scenario.nav_spacecraft == NavSpacecraft::NavKalman {
+            assert!(scenario.nav_kalman.is_some(), "nav_kalman is required with NavKalman");
+        }
+
+        let peg = (
+            (scenario.gui_spacecraft == GuiSpacecraft::GuiAscentPeg)
+            || scenario.seq_phases.iter().flatten().any(|phase| phase.gui_spacecraft == GuiSpacecraft::GuiAscentPeg)
+        );
+        if peg {
+            assert!(scenario.gui_peg.is_some(), "gui_peg is required with GuiAscentPeg");
+        }
+
+        let gfold = (
+            (scenario.gui_spacecraft == GuiSpacecraft::GuiGfold)
+            || scenario.seq_phases.iter().flatten().any(|phase| phase.gui_spacecraft == GuiSpacecraft::GuiGfold)
+        );
+        if gfold {
+            assert!(scenario.gui_gfold.is_some(), "gui_gfold is required with GuiGfold");
+        }
+
+        if scenario.seq_divert.is_some() {
+            assert!(scenario.gui_gfold.is_some(), "gui_gfold is required with seq_divert (reachability)");
+        }
+
         scenario
     }
 }
 
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Conf {
     pub subcommand: SubCommand,     // sim | ksp
     pub dt_step: Time,
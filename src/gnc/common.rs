@@ -10,6 +10,9 @@ use uom::si::time::{minute, second};
 use uom::si::velocity::meter_per_second;
 
 use crate::conf::{Conf, SubCommand};
+use crate::gnc::fdir::Fdir;
+use crate::gnc::gfold::Gfold;
+use crate::gnc::peg::Peg;
 use crate::utils::spacecraft::SpacecraftDynamic;
 
 
@@ -17,14 +20,20 @@ pub struct Spacecraft {
     pub conf: Conf,                     // spacecraft configuration / static properties
     pub cur: SpacecraftDynamic,         // latest changing properties
     pub all: Vec<SpacecraftDynamic>,    // all changing properties
+    pub fdir: Fdir,                     // sensors health
+    pub peg: Peg,                       // ascent guidance state, used only with GuiSpacecraft::GuiAscentPeg
+    pub gfold: Gfold,                   // descent guidance state, used only with GuiSpacecraft::GuiGfold
 }
 
 
 impl Spacecraft {
     pub fn new(conf: Conf) -> Spacecraft {
         Spacecraft {
-            conf: conf,
             cur: SpacecraftDynamic::new(&conf.s),
+            fdir: Fdir::new(conf.s.nav_fdir),
+            peg: Peg::new(),
+            gfold: Gfold::new(),
+            conf: conf,
             all: vec![],
         }
     }
@@ -35,6 +44,11 @@ impl Spacecraft {
             return;
         }
 
+        // the contact forces of the touchdown are not bounded
+        if self.cur.contact {
+            return;
+        }
+
         assert!(Time::new::<second>(0.0) <= self.cur.t);
         assert!(self.cur.t < Time::new::<minute>(15.0));
 
@@ -50,20 +64,28 @@ impl Spacecraft {
         assert!(Length::new::<meter>(0.0) <= self.cur.pos.y);
         assert!(self.cur.pos.y <= Length::new::<meter>(1_000_000.0));
 
+        assert!(self.cur.pos.z.abs() <= Length::new::<meter>(1_000_000.0));
+
         assert!(self.cur.vel.x.abs() < Velocity::new::<meter_per_second>(10_000.0));
         assert!(self.cur.vel.y.abs() < Velocity::new::<meter_per_second>(1_000.0));
+        assert!(self.cur.vel.z.abs() < Velocity::new::<meter_per_second>(1_000.0));
 
         assert!(self.cur.acc.x.abs() < Acceleration::new::<meter_per_second_squared>(100.0));
         assert!(self.cur.acc.y.abs() < Acceleration::new::<meter_per_second_squared>(100.0));
+        assert!(self.cur.acc.z.abs() < Acceleration::new::<meter_per_second_squared>(100.0));
 
         assert!(Angle::new::<degree>(-180.1) <= self.cur.ang_pos);
         assert!(self.cur.ang_pos <= Angle::new::<degree>(180.1));
 
-        assert!(AngularVelocity::new::<degree_per_second>(-5.0) <= self.cur.ang_vel);
-        assert!(self.cur.ang_vel <= AngularVelocity::new::<degree_per_second>(5.0));
+        for ang_vel in [self.cur.ang_vel.x, self.cur.ang_vel.y, self.cur.ang_vel.z].iter() {
+            assert!(AngularVelocity::new::<degree_per_second>(-5.0) <= *ang_vel);
+            assert!(*ang_vel <= AngularVelocity::new::<degree_per_second>(5.0));
+        }
 
-        assert!(AngularAcceleration::new::<degree_per_second_squared>(-5.0) <= self.cur.ang_acc);
-        assert!(self.cur.ang_acc <= AngularAcceleration::new::<degree_per_second_squared>(5.0));
+        for ang_acc in [self.cur.ang_acc.x, self.cur.ang_acc.y, self.cur.ang_acc.z].iter() {
+            assert!(AngularAcceleration::new::<degree_per_second_squared>(-5.0) <= *ang_acc);
+            assert!(*ang_acc <= AngularAcceleration::new::<degree_per_second_squared>(5.0));
+        }
     }
 
     pub fn export_to_csv_conf(&self) {
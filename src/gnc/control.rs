@@ -1,68 +1,79 @@
-use std::f64::consts::PI;
-
 use uom::si::f64::*;
 use uom::si::acceleration::meter_per_second_squared;
 use uom::si::angle::{degree, radian};
 use uom::si::ratio::ratio;
 use uom::si::time::second;
 
-use crate::{sqrt, squared, modulo};
+use crate::{squared, modulo};
 use crate::adapters::common::ActuatorsValues;
 use crate::conf::{Scenario, CtrSpacecraft};
 use crate::gnc::common::Spacecraft;
-use crate::utils::math::{Vec2, sign, saturate};
+use crate::utils::math::{Vec3, Quaternion, sign, saturate};
 
 
 /// Main control function
 pub fn ctr(spacecraft: &mut Spacecraft) -> ActuatorsValues {
-    let conf = spacecraft.conf.s;
+    let conf = &spacecraft.conf.s;
     let sc_mass = conf.sc_dry_mass + spacecraft.cur.fuel_mass;
-    let sc_nom_thrust = conf.sc_nominal_thrust;
-    let sc_ang_pos = spacecraft.cur.ang_pos;
+    let sc_nom_thrust = spacecraft.cur.thrust;  // full throttle, estimated
+    let sc_att = spacecraft.cur.att;
     let sc_ang_vel = spacecraft.cur.ang_vel;
-    let eng_gimbal_cur: Angle = (spacecraft.cur.eng_gimbal*conf.ctr_eng_gimbal_pos_max).into();
+    let eng_gimbal_pitch_cur: Angle = (spacecraft.cur.eng_gimbal*conf.ctr_eng_gimbal_pos_max).into();
+    let eng_gimbal_yaw_cur: Angle = (spacecraft.cur.eng_gimbal_yaw*conf.ctr_eng_gimbal_pos_max).into();
     let goal_acc = spacecraft.cur.gui;
 
-    let (ctr_sc_thrust, ctr_ang_pos): (Force, Angle) = match conf.ctr_spacecraft {
+    let (ctr_sc_thrust, ctr_dir): (Force, Vec3<f64>) = match conf.ctr_spacecraft {
         CtrSpacecraft::CtrSpacecraftDescent | CtrSpacecraft::CtrSpacecraftAscentToHover => {
             control_translation(goal_acc, sc_mass, sc_nom_thrust)
         },
         CtrSpacecraft::CtrSpacecraftAscentToOrbit => {
-            let (_thrust, ctr_ang_pos_optim): (Force, Angle) = control_translation(goal_acc, sc_mass, sc_nom_thrust);
-
-            // to avoid a dangerously big angular command, perform a nice constant pitch rate
-            let tf = Time::new::<second>(50.0);
+            // full thrust, along the guidance (which pitches over after lift-off, cf. gnc::guidance)
+            (sc_nom_thrust, goal_acc.value().unit())
+        },
+    };
 
-            if spacecraft.cur.t > tf {
-                (sc_nom_thrust, ctr_ang_pos_optim)
-            } else {
-                let af = ctr_ang_pos_optim;
+    // attitude error: rotation from the current thrust axis to the commanded one,
+    // expressed in the body frame (z: pitch, y: yaw)
 
-                let na: Angle = (spacecraft.cur.t/tf*(Angle::new::<degree>(90.0)-af)).into();
+    let sc_dir = sc_att.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0});
+    let err_axis = sc_dir.cross(&ctr_dir);
+    let err_angle = err_axis.norm().atan2(sc_dir.dot(&ctr_dir));
+    let err_body = sc_att.rotate_inv(err_axis.unit()*err_angle);
 
-                let ctr_ang_pos: Angle = Angle::new::<degree>(90.0) - na;
-                (sc_nom_thrust, ctr_ang_pos)
-            }
-        },
-    };
+    let ctr_eng_gimbal_pitch: Angle = control_angular(
+        conf,
+        spacecraft.cur.dt,
+        sc_mass,
+        ctr_sc_thrust,
+        Angle::new::<radian>(0.0),
+        sc_ang_vel.z,
+        eng_gimbal_pitch_cur,
+        Angle::new::<radian>(err_body.z),
+    );
 
-    let ctr_eng_gimbal: Angle = control_angular(
-        &conf,
+    let ctr_eng_gimbal_yaw: Angle = control_angular(
+        conf,
         spacecraft.cur.dt,
         sc_mass,
         ctr_sc_thrust,
-        sc_ang_pos,
-        sc_ang_vel,
-        eng_gimbal_cur,
-        ctr_ang_pos,
+        Angle::new::<radian>(0.0),
+        sc_ang_vel.y,
+        eng_gimbal_yaw_cur,
+        Angle::new::<radian>(err_body.y),
     );
 
+    let ctr_rcs_roll: Ratio = control_roll(conf, sc_mass, roll_cross_range(sc_att), sc_ang_vel.x);
+
     spacecraft.cur.eng_throttle = ctr_sc_thrust / sc_nom_thrust;
-    spacecraft.cur.eng_gimbal = ctr_eng_gimbal / conf.ctr_eng_gimbal_pos_max;
+    spacecraft.cur.eng_gimbal = ctr_eng_gimbal_pitch / conf.ctr_eng_gimbal_pos_max;
+    spacecraft.cur.eng_gimbal_yaw = ctr_eng_gimbal_yaw / conf.ctr_eng_gimbal_pos_max;
+    spacecraft.cur.rcs_roll = ctr_rcs_roll;
 
     ActuatorsValues {
         engine_throttle: spacecraft.cur.eng_throttle,
         engine_gimbal: spacecraft.cur.eng_gimbal,
+        engine_gimbal_yaw: spacecraft.cur.eng_gimbal_yaw,
+        rcs_roll: spacecraft.cur.rcs_roll,
     }
 }
 
@@ -70,44 +81,105 @@ pub fn ctr(spacecraft: &mut Spacecraft) -> ActuatorsValues {
 /// Spacecraft control function (high level control)
 ///
 /// Input:
-///     goal_acc_x, goal_acc_y
+///     goal_acc (local frame)
 ///     spacecraft mass
 ///     engine max_thrust
 /// Output:
 ///     commanded thrust (respecting engine constraints)
-///     commanded (ideal) (spacecraft) attitude angle
-fn control_translation(goal_acc: Vec2<Acceleration>, sc_mass: Mass, sc_thrust: Force) -> (Force, Angle) {
+///     commanded (ideal) (spacecraft) thrust direction, unit vector in the local frame
+fn control_translation(goal_acc: Vec3<Acceleration>, sc_mass: Mass, sc_thrust: Force) -> (Force, Vec3<f64>) {
     // instead of wasting propelant, let gravity work
     if goal_acc.y < Acceleration::new::<meter_per_second_squared>(0.0) {
         println!("WARN: gravity");
-        (sc_thrust, Angle::new::<radian>(PI))
+        (sc_thrust, Vec3 {x: -1.0, y: 0.0, z: 0.0})
     } else {
-        let mut ctr_thrust: Force = Acceleration::new::<meter_per_second_squared>(
-            sqrt!(squared!(goal_acc.x) + squared!(goal_acc.y))
-        ) * sc_mass;
+        let mut ctr_thrust: Force = goal_acc.norm() * sc_mass;
 
-        let mut ctr_angle;
+        let ctr_dir;
 
         // best case, control is possible
         if ctr_thrust < sc_thrust {
-            ctr_angle = goal_acc.y.atan2(goal_acc.x);
+            ctr_dir = goal_acc.value().unit();
         }
-        // else, try to save what can be saved (fulfill y, best effort x)
+        // else, try to save what can be saved (fulfill y, best effort horizontal)
         else {
             println!("WARN: thrust norm {} times the available thrust", (ctr_thrust/sc_thrust).get::<ratio>());
             ctr_thrust = sc_thrust;
 
-            ctr_angle = (goal_acc.y*sc_mass/sc_thrust).asin();
-            if goal_acc.x < Acceleration::new::<meter_per_second_squared>(0.0) {
-                ctr_angle = Angle::new::<radian>(PI)-ctr_angle;
-            }
+            let sin_angle: Ratio = goal_acc.y*sc_mass/sc_thrust;
+            let ctr_angle: Angle = saturate(sin_angle, Ratio::new::<ratio>(-1.0), Ratio::new::<ratio>(1.0)).asin();
+
+            // horizontal direction of the command (+x if none)
+            let horiz = Vec3 {x: goal_acc.x.value, y: 0.0, z: goal_acc.z.value};
+            let horiz = if horiz.norm() == 0.0 {
+                Vec3 {x: 1.0, y: 0.0, z: 0.0}
+            } else {
+                horiz.unit()
+            };
+
+            ctr_dir = Vec3 {
+                x: horiz.x*ctr_angle.cos().value,
+                y: ctr_angle.sin().value,
+                z: horiz.z*ctr_angle.cos().value,
+            };
         }
 
-        (ctr_thrust, ctr_angle)
+        (ctr_thrust, ctr_dir)
     }
 }
 
 
+/// Roll around the thrust axis, from the cross-range direction to the body z axis
+///
+/// Same as the Euler roll when the heading is 0, but still defined with a
+/// vertical thrust axis, where the Euler heading and roll are degenerate (cf.
+/// Quaternion::to_euler()) and a small sensor error swaps one for the other.
+///
+/// It is degenerate in turn with the thrust axis along the cross-range (not a
+/// landing attitude): the reference is then the horizontal, like the Euler roll.
+fn roll_cross_range(att: Quaternion) -> Angle {
+    let body_x = att.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0});
+    let body_z = att.rotate(Vec3 {x: 0.0, y: 0.0, z: 1.0});
+    let cross_range = Vec3 {x: 0.0, y: 0.0, z: 1.0};
+    let up = Vec3 {x: 0.0, y: 1.0, z: 0.0};
+
+    let z_ref = cross_range - body_x*cross_range.dot(&body_x);
+    let z_ref = if z_ref.norm() < 1e-9 {
+        body_x.cross(&up)
+    } else {
+        z_ref
+    }.unit();
+    let roll = z_ref.cross(&body_z).dot(&body_x).atan2(z_ref.dot(&body_z));
+
+    Angle::new::<radian>(roll)
+}
+
+
+/// Roll controller function
+///
+/// Same PD law as the engine gimbal controller, actuated by the RCS. Keeps the
+/// roll at 0.
+///
+/// Output:
+///     commanded RCS roll torque, range: [-1; +1] of max torque
+fn control_roll(conf: &Scenario, sc_mass: Mass, sc_roll: Angle, sc_roll_vel: AngularVelocity) -> Ratio {
+    let kp: Ratio = conf.ctr_eng_gimbal_kp.unwrap();
+    let kd: Time = conf.ctr_eng_gimbal_kd.unwrap();
+
+    // small hack to respect Dimensional analysis (cf. control_angular())
+    let control_transfer_function = 1.0 / squared!(Time::new::<second>(1.0));
+
+    let err: Angle = modulo!(-sc_roll, Angle::new::<degree>(360.0));
+    let control: Angle = (kp*err + kd*sc_roll_vel).into();
+    let ctr_ang_acc: AngularAcceleration = (control * control_transfer_function).into();
+
+    let sc_moment_of_inertia = 0.5 * sc_mass * squared!(conf.sc_width/2.0);  // 1/2*m*r**2 = kg.m**2
+    let ctr_torque: Torque = (ctr_ang_acc * sc_moment_of_inertia).into();
+
+    saturate(ctr_torque/conf.sc_rcs_roll_torque, Ratio::new::<ratio>(-1.0), Ratio::new::<ratio>(1.0))
+}
+
+
 /// Engine controller function (low level control)
 ///
 /// Controller implemented as a PID to control the spacecraft ang_pos via its
@@ -120,6 +192,7 @@ fn control_translation(goal_acc: Vec2<Acceleration>, sc_mass: Mass, sc_thrust: F
 /// Output:
 ///     commanded (engine) gimbal_angle (respecting engine constraints)
 ///
+#[allow(clippy::too_many_arguments)]
 fn control_angular(
     conf: &Scenario,
     dt: Time,
@@ -179,6 +252,8 @@ fn control_angular(
 
 #[cfg(test)]
 mod tests {
+    use std::f64::consts::PI;
+
     use super::*;
 
     use uom::si::angular_velocity::degree_per_second;
@@ -188,60 +263,70 @@ mod tests {
     use crate::assert_approx_eq;
     use crate::conf::Scenario;
 
+    /// Angle of a direction in the (x, y) plane
+    fn angle(dir: Vec3<f64>) -> Angle {
+        Angle::new::<radian>(dir.y.atan2(dir.x))
+    }
+
     #[test]
     fn test_gui_1_spacecraft_angle() {
         let mass = Mass::new::<kilogram>(1_000.0);
         let thrust = Force::new::<newton>(20_000.0);
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (_, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(10.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
-        assert_eq!(ctr_angle, Angle::new::<radian>(0.0));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(0.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (_, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(7.071067811865475),
                 y: Acceleration::new::<meter_per_second_squared>(7.071067811865475),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
-        assert_eq!(ctr_angle, Angle::new::<radian>(PI/4.0));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(PI/4.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (_, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: Acceleration::new::<meter_per_second_squared>(10.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
-        assert_eq!(ctr_angle, Angle::new::<radian>(PI/2.0));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(PI/2.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (_, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(-7.071067811865475),
                 y: Acceleration::new::<meter_per_second_squared>(7.071067811865475),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
-        assert_eq!(ctr_angle, Angle::new::<radian>(3.0*PI/4.0));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(3.0*PI/4.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (_, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(-10.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
-        assert_eq!(ctr_angle, Angle::new::<radian>(PI));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(PI));
     }
 
     #[test]
@@ -251,30 +336,33 @@ mod tests {
 
         // +x
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(10.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, Force::new::<newton>(10_000.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(20.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, Force::new::<newton>(20_000.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(30.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
@@ -283,30 +371,33 @@ mod tests {
 
         // -x
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(-10.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, Force::new::<newton>(10_000.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(-20.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, Force::new::<newton>(20_000.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(-30.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
@@ -315,30 +406,33 @@ mod tests {
 
         // +y
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: Acceleration::new::<meter_per_second_squared>(10.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, Force::new::<newton>(10_000.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: Acceleration::new::<meter_per_second_squared>(20.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, Force::new::<newton>(20_000.0));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, _ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: Acceleration::new::<meter_per_second_squared>(30.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
@@ -347,38 +441,41 @@ mod tests {
 
         // -y -> gravity
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: -Acceleration::new::<meter_per_second_squared>(10.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, thrust);
-        assert_eq!(ctr_angle, Angle::new::<radian>(PI));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(PI));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: -Acceleration::new::<meter_per_second_squared>(20.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, thrust);
-        assert_eq!(ctr_angle, Angle::new::<radian>(PI));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(PI));
 
-        let (ctr_thrust, ctr_angle) = control_translation(
-            Vec2 {
+        let (ctr_thrust, ctr_dir) = control_translation(
+            Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: -Acceleration::new::<meter_per_second_squared>(30.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
             mass,
             thrust,
         );
         assert_eq!(ctr_thrust, thrust);
-        assert_eq!(ctr_angle, Angle::new::<radian>(PI));
+        assert_eq!(angle(ctr_dir), Angle::new::<radian>(PI));
     }
 
     #[test]
@@ -495,4 +592,27 @@ mod tests {
     fn test_gui_4_ctr() {
         // TODO test ctr()
     }
+
+    #[test]
+    fn test_gui_5_roll_cross_range() {
+        let deg = |angle: f64| Angle::new::<degree>(angle);
+
+        // same as the Euler roll, with a 0 heading
+        let att = Quaternion::from_euler(deg(0.0), deg(30.0), deg(20.0));
+        assert_approx_eq!(roll_cross_range(att), deg(20.0), deg(1e-9));
+
+        // vertical thrust axis, tilted by a small (sensor) error in any
+        // direction: the Euler heading takes all or part of the roll
+        let vertical = Quaternion::from_euler(deg(0.0), deg(90.0), deg(20.0));
+        for i in 0..8 {
+            let axis_angle = 2.0*PI*(i as f64)/8.0;
+            let axis = Vec3 {x: axis_angle.cos(), y: 0.0, z: axis_angle.sin()};
+            let att = Quaternion::from_axis_angle(axis, deg(0.01)) * vertical;
+            assert_approx_eq!(roll_cross_range(att), deg(20.0), deg(0.02));
+        }
+
+        // thrust axis along the cross-range
+        let att = Quaternion::from_euler(deg(90.0), deg(0.0), deg(20.0));
+        assert_approx_eq!(roll_cross_range(att), deg(20.0), deg(1e-9));
+    }
 }
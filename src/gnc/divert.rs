@@ -0,0 +1,185 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::length::meter;
+use uom::si::mass::kilogram;
+use uom::si::time::second;
+
+use crate::gnc::common::Spacecraft;
+use crate::gnc::gfold;
+use crate::gnc::guidance::gfold_descent;
+use crate::utils::math::Vec3;
+
+
+/// Distance of the site moved out of a hazard, beyond its edge
+const DIVERT_HAZARD_CLEARANCE: f64 = 1.0;
+
+/// Candidate sites around the edge of a hazard, besides the projection of the site
+const DIVERT_HAZARD_DIRECTIONS: usize = 36;
+
+
+/// Landing site retargeting, cf. Sequencer::divert()
+///
+/// Yaml serialized equivalent:
+///
+/// seq_divert:
+///   fuel_reserve: 100.0               # kg, not available to a divert, kept for the vertical descent
+///   scheduled:                        # in time order
+///     - {t: 500.0, site: [200.0, 0.0, 150.0]}
+///   hazards:                          # hazard map, checked when entering a phase
+///     - {x: 350.0, z: 200.0, radius: 160.0}
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DivertConf {
+    pub fuel_reserve: Mass,
+    pub scheduled: Vec<ScheduledDivert>,
+    pub hazards: Vec<Hazard>,
+}
+
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledDivert {
+    pub t: Time,
+    pub site: Vec3<Length>,             // new landing site
+}
+
+
+/// Keep-out area on the ground, its radius includes the margins (navigation, dispersions)
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hazard {
+    pub x: Length,
+    pub z: Length,
+    pub radius: Length,
+}
+
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum DivertSource {
+    Scheduled,
+    HazardMap,
+    Command,        // external, cf. Sequencer::divert()
+}
+
+
+/// A divert, accepted or rejected
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct DivertEvent {
+    pub t: Time,
+    pub source: DivertSource,
+    pub site: Vec3<Length>,
+    pub accepted: bool,
+}
+
+
+impl Hazard {
+    fn contains(&self, site: Vec3<Length>) -> bool {
+        let dx = (site.x - self.x).get::<meter>();
+        let dz = (site.z - self.z).get::<meter>();
+        dx.hypot(dz) < self.radius.get::<meter>()
+    }
+}
+
+
+/// Closest site out of the hazards, None if the site is already safe or if there is none
+///
+/// The candidates are on the edges of all the hazards (they may overlap): the
+/// projection of the site, and evenly spaced points. The height of the site is
+/// kept.
+pub fn safe_site(hazards: &[Hazard], site: Vec3<Length>) -> Option<Vec3<Length>> {
+    if !hazards.iter().any(|hazard| hazard.contains(site)) {
+        return None;
+    }
+
+    let mut candidates = vec![];
+    for hazard in hazards {
+        let (dx, dz) = ((site.x - hazard.x).get::<meter>(), (site.z - hazard.z).get::<meter>());
+        let r = hazard.radius.get::<meter>() + DIVERT_HAZARD_CLEARANCE;
+
+        let projection = dz.atan2(dx);
+        let angles = (0..DIVERT_HAZARD_DIRECTIONS).map(|i| 2.0*std::f64::consts::PI*(i as f64)/(DIVERT_HAZARD_DIRECTIONS as f64));
+
+        for angle in Some(projection).into_iter().chain(angles) {
+            candidates.push(Vec3 {
+                x: hazard.x + Length::new::<meter>(r*angle.cos()),
+                y: site.y,
+                z: hazard.z + Length::new::<meter>(r*angle.sin()),
+            });
+        }
+    }
+
+    let distance = |candidate: &Vec3<Length>| (*candidate - site).norm().get::<meter>();
+
+    candidates.into_iter()
+        .filter(|candidate| !hazards.iter().any(|hazard| hazard.contains(*candidate)))
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))
+}
+
+
+/// Whether the spacecraft can reach pf at vf in tgo, from its current state
+///
+/// Feasibility of the powered descent optimization (cf. gnc::gfold): within
+/// the gui_gfold thrust, tilt and glide slope bounds, with the fuel above
+/// fuel_reserve.
+pub fn reachable(spacecraft: &Spacecraft, conf: &DivertConf, pf: Vec3<Length>, vf: Vec3<Velocity>, tgo: Time) -> bool {
+    let gfold_conf = spacecraft.conf.s.gui_gfold.as_ref().unwrap();
+
+    let mut descent = gfold_descent(spacecraft);
+    descent.pf = pf;
+    descent.vf = vf;
+    descent.fuel = spacecraft.cur.fuel_mass - conf.fuel_reserve;
+
+    if (descent.fuel <= Mass::new::<kilogram>(0.0)) || (tgo <= Time::new::<second>(0.0)) {
+        return false;
+    }
+
+    gfold::solve(gfold_conf, &descent, spacecraft.cur.t, tgo).is_some()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_approx_eq;
+
+    fn hazard(x: f64, z: f64, radius: f64) -> Hazard {
+        Hazard {
+            x: Length::new::<meter>(x),
+            z: Length::new::<meter>(z),
+            radius: Length::new::<meter>(radius),
+        }
+    }
+
+    #[test]
+    fn test_divert_1_safe_site() {
+        let site = Vec3::from_value(Vec3 {x: 0.0, y: 10.0, z: 0.0});
+
+        // already safe
+        assert!(safe_site(&[hazard(350.0, 200.0, 120.0)], site).is_none());
+
+        // on the edge, away from the center
+        let safe = safe_site(&[hazard(-50.0, 0.0, 100.0)], site).unwrap();
+        assert_approx_eq!(safe.x.get::<meter>(), 51.0, 1e-9);
+        assert_approx_eq!(safe.y.get::<meter>(), 10.0, 1e-9);
+        assert_approx_eq!(safe.z.get::<meter>(), 0.0, 1e-9);
+
+        // the closest edge is in another hazard: around the first one
+        let hazards = [hazard(-50.0, 0.0, 100.0), hazard(100.0, 0.0, 60.0)];
+        let safe = safe_site(&hazards, site).unwrap();
+        assert!(hazards.iter().all(|hazard| !hazard.contains(safe)));
+        assert!((safe - site).norm() < Length::new::<meter>(60.0));
+        assert!(safe.z.abs() > Length::new::<meter>(20.0));
+    }
+}
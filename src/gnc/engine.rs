@@ -0,0 +1,238 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::acceleration::meter_per_second_squared;
+use uom::si::force::newton;
+use uom::si::mass::kilogram;
+use uom::si::ratio::ratio;
+use uom::si::time::second;
+
+use crate::conf::Scenario;
+use crate::utils::space::mass_flow;
+
+
+/// Error state of the engine estimator: fuel mass (kg), full throttle thrust
+/// (N) and Isp (s)
+pub const ENGINE_STATES: usize = 3;
+
+/// Covariance of the engine estimate
+pub type EngineCovariance = [[f64; ENGINE_STATES]; ENGINE_STATES];
+
+const MASS: usize = 0;
+const THRUST: usize = 1;
+const ISP: usize = 2;
+
+
+/// Tuning of the engine estimator
+///
+/// Values are 1-sigma, relative to the nominal values (initial mass, thrust
+/// and Isp of the scenario).
+///
+/// The estimator infers the actual mass, thrust and Isp from the measured
+/// thrust acceleration and the commanded throttle:
+///     mass' = -throttle*thrust/(Isp*g0)
+///     acc_thrust = throttle*thrust/mass
+/// Only thrust/mass and the Isp are observable, the initial mass uncertainty
+/// decides how the error is split between the mass and the thrust. Guidance
+/// and control only need thrust/mass anyway.
+///
+/// Yaml serialized equivalent:
+///
+/// nav_engine:
+///     init_mass: 0.005            # 0.5%, the fuel loaded is well known
+///     init_thrust: 0.01
+///     init_isp: 0.005
+///     acc_noise: 0.05             # m/s**2, accelerometers and unmodeled accelerations
+///     min_throttle: 0.1           # no update below
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EngineEstimatorConf {
+    pub init_mass: Ratio,
+    pub init_thrust: Ratio,
+    pub init_isp: Ratio,
+    pub acc_noise: Acceleration,
+    pub min_throttle: Ratio,
+}
+
+
+/// Initial covariance
+pub fn init(conf: &Scenario, estimator: &EngineEstimatorConf) -> EngineCovariance {
+    let mass = (conf.sc_dry_mass + conf.initial_sc_fuel_mass).get::<kilogram>();
+
+    let mut p = [[0.0; ENGINE_STATES]; ENGINE_STATES];
+    p[MASS][MASS] = (estimator.init_mass.get::<ratio>()*mass).powi(2);
+    p[THRUST][THRUST] = (estimator.init_thrust.get::<ratio>()*conf.sc_nominal_thrust.get::<newton>()).powi(2);
+    p[ISP][ISP] = (estimator.init_isp.get::<ratio>()*conf.sc_nominal_isp.get::<second>()).powi(2);
+    p
+}
+
+
+/// Propagate the covariance over dt
+///
+/// The fuel mass itself is propagated by the navigation, with the estimated
+/// mass flow.
+pub fn predict(conf: &Scenario, p: &EngineCovariance, thrust: Force, isp: Time, throttle: Ratio, dt: Time) -> EngineCovariance {
+    let flow = (throttle*mass_flow(conf, thrust, isp)*dt).get::<kilogram>();
+
+    // F = I + [0 -flow/thrust flow/isp; 0 0 0; 0 0 0]
+    let mut f = [[0.0; ENGINE_STATES]; ENGINE_STATES];
+    for (i, row) in f.iter_mut().enumerate() {
+        row[i] = 1.0;
+    }
+    f[MASS][THRUST] = -flow/thrust.get::<newton>();
+    f[MASS][ISP] = flow/isp.get::<second>();
+
+    let mut fpf = [[0.0; ENGINE_STATES]; ENGINE_STATES];
+    for i in 0..ENGINE_STATES {
+        for j in 0..ENGINE_STATES {
+            fpf[i][j] = (0..ENGINE_STATES).map(|k| {
+                (0..ENGINE_STATES).map(|l| f[i][k]*p[k][l]*f[j][l]).sum::<f64>()
+            }).sum();
+        }
+    }
+
+    fpf
+}
+
+
+/// Thrust acceleration measurement update
+///
+/// Returns false if the throttle is too low for the measurement to be useful.
+#[allow(clippy::too_many_arguments)]
+pub fn update(
+    conf: &Scenario,
+    estimator: &EngineEstimatorConf,
+    p: &mut EngineCovariance,
+    fuel_mass: &mut Mass,
+    thrust: &mut Force,
+    isp: &mut Time,
+    throttle: Ratio,
+    acc_thrust: Acceleration,
+) -> bool {
+    let u = throttle.get::<ratio>();
+    if u < estimator.min_throttle.get::<ratio>() {
+        return false;
+    }
+
+    let m = (conf.sc_dry_mass + *fuel_mass).get::<kilogram>();
+    let f = thrust.get::<newton>();
+
+    let h = [-u*f/(m*m), u/m, 0.0];
+    let innovation = acc_thrust.get::<meter_per_second_squared>() - u*f/m;
+    let r = estimator.acc_noise.get::<meter_per_second_squared>().powi(2);
+
+    let ph: Vec<f64> = (0..ENGINE_STATES).map(|i| (0..ENGINE_STATES).map(|j| p[i][j]*h[j]).sum()).collect();
+    let s = (0..ENGINE_STATES).map(|i| h[i]*ph[i]).sum::<f64>() + r;
+    let k: Vec<f64> = ph.iter().map(|v| v/s).collect();
+
+    // the tanks were full: the fuel mass cannot be more than the initial one
+    *fuel_mass += Mass::new::<kilogram>(k[MASS]*innovation);
+    *fuel_mass = fuel_mass.min(conf.initial_sc_fuel_mass);
+    *thrust += Force::new::<newton>(k[THRUST]*innovation);
+    *isp += Time::new::<second>(k[ISP]*innovation);
+
+    // P = (I-KH)*P*(I-KH)' + K*R*K'
+    let mut ikh = [[0.0; ENGINE_STATES]; ENGINE_STATES];
+    for i in 0..ENGINE_STATES {
+        for j in 0..ENGINE_STATES {
+            let identity = if i == j { 1.0 } else { 0.0 };
+            ikh[i][j] = identity - k[i]*h[j];
+        }
+    }
+    let mut next = [[0.0; ENGINE_STATES]; ENGINE_STATES];
+    for i in 0..ENGINE_STATES {
+        for j in 0..ENGINE_STATES {
+            next[i][j] = (0..ENGINE_STATES).map(|a| {
+                (0..ENGINE_STATES).map(|b| ikh[i][a]*p[a][b]*ikh[j][b]).sum::<f64>()
+            }).sum::<f64>() + k[i]*r*k[j];
+        }
+    }
+    *p = next;
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_approx_eq;
+
+    fn scenario() -> Scenario {
+        let f = std::fs::File::open("conf/tests/default.yaml").unwrap();
+        serde_yaml::from_reader(f).unwrap()
+    }
+
+    fn estimator() -> EngineEstimatorConf {
+        EngineEstimatorConf {
+            init_mass: Ratio::new::<ratio>(0.005),
+            init_thrust: Ratio::new::<ratio>(0.01),
+            init_isp: Ratio::new::<ratio>(0.005),
+            acc_noise: Acceleration::new::<meter_per_second_squared>(0.01),
+            min_throttle: Ratio::new::<ratio>(0.1),
+        }
+    }
+
+    #[test]
+    fn test_engine_1_converge() {
+        let conf = scenario();
+        let estimator = estimator();
+        let dt = Time::new::<second>(0.1);
+        let throttle = Ratio::new::<ratio>(0.6);
+
+        // actual engine: 3% less thrust, 2% less Isp
+        let thrust_true = conf.sc_nominal_thrust*0.97;
+        let isp_true = conf.sc_nominal_isp*0.98;
+        let mut fuel_true = conf.initial_sc_fuel_mass;
+
+        let mut p = init(&conf, &estimator);
+        let mut fuel = conf.initial_sc_fuel_mass;
+        let mut thrust = conf.sc_nominal_thrust;
+        let mut isp = conf.sc_nominal_isp;
+
+        for _ in 0..3000 {
+            fuel_true -= throttle*mass_flow(&conf, thrust_true, isp_true)*dt;
+            let acc: Acceleration = throttle*thrust_true/(conf.sc_dry_mass + fuel_true);
+
+            fuel -= throttle*mass_flow(&conf, thrust, isp)*dt;
+            p = predict(&conf, &p, thrust, isp, throttle, dt);
+            assert!(update(&conf, &estimator, &mut p, &mut fuel, &mut thrust, &mut isp, throttle, acc));
+        }
+
+        // thrust/mass is what matters for guidance and control
+        let acc_max_true: Acceleration = thrust_true/(conf.sc_dry_mass + fuel_true);
+        let acc_max: Acceleration = thrust/(conf.sc_dry_mass + fuel);
+        assert_approx_eq!((acc_max/acc_max_true).get::<ratio>(), 1.0, 1e-3);
+
+        // most of the error is in the thrust, the mass is better known
+        assert!((thrust - thrust_true).abs() < 0.5*(conf.sc_nominal_thrust - thrust_true).abs());
+        assert!((isp - isp_true).abs() < (conf.sc_nominal_isp - isp_true).abs());
+        assert!(p[THRUST][THRUST].sqrt() < estimator.init_thrust.get::<ratio>()*conf.sc_nominal_thrust.get::<newton>());
+    }
+
+    #[test]
+    fn test_engine_2_low_throttle() {
+        let conf = scenario();
+        let estimator = estimator();
+
+        let mut p = init(&conf, &estimator);
+        let p_init = p;
+        let mut fuel = conf.initial_sc_fuel_mass;
+        let mut thrust = conf.sc_nominal_thrust;
+        let mut isp = conf.sc_nominal_isp;
+
+        // engine off: nothing to learn from the accelerometers
+        let acc = Acceleration::new::<meter_per_second_squared>(0.5);
+        assert!(!update(&conf, &estimator, &mut p, &mut fuel, &mut thrust, &mut isp, Ratio::new::<ratio>(0.0), acc));
+        assert_eq!(p, p_init);
+        assert_eq!(thrust, conf.sc_nominal_thrust);
+
+        // the fuel estimate never exceeds the loaded fuel
+        let acc = Acceleration::new::<meter_per_second_squared>(0.5);
+        assert!(update(&conf, &estimator, &mut p, &mut fuel, &mut thrust, &mut isp, Ratio::new::<ratio>(0.2), acc));
+        assert!(fuel <= conf.initial_sc_fuel_mass);
+        assert!(thrust < conf.sc_nominal_thrust);
+    }
+}
@@ -0,0 +1,485 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::ratio::ratio;
+use uom::si::time::second;
+
+use crate::adapters::common::SensorsValues;
+use crate::utils::math::Vec3;
+
+
+/// Sensors consistency checks, cf. Fdir
+///
+/// Values are in SI units. The jumps are the changes over a time step, they
+/// include the sensors noise. They are checked against the last valid sample,
+/// hence the limit grows with the time since (dropouts, rejected samples). The
+/// accelerations are not checked for jumps: they change with the throttle.
+///
+/// Yaml serialized equivalent:
+///
+/// nav_fdir:
+///     acc_max: 50.0                   # m/s**2, range checks
+///     ang_vel_max: 0.35               # rad/s, 20 deg/s
+///     altitude_max: 50000.0           # m, slant range
+///     vel_max: 2000.0                 # m/s
+///     ang_vel_jump_max: 0.05          # rad/s, rate checks
+///     altitude_jump_max: 1000.0       # m, the slant range changes with the attitude
+///     vel_jump_max: 100.0             # m/s, body frame: rotates with the spacecraft
+///     stuck_steps: 10                 # identical samples, 0 to disable (ideal sensors)
+///     gate_steps: 50                  # consecutive measurements rejected by the Kalman filter
+///     fault_steps: 3                  # consecutive faulty samples before the sensor is failed
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FdirConf {
+    pub acc_max: Acceleration,
+    pub ang_vel_max: AngularVelocity,
+    pub altitude_max: Length,
+    pub vel_max: Velocity,
+    pub ang_vel_jump_max: AngularVelocity,
+    pub altitude_jump_max: Length,
+    pub vel_jump_max: Velocity,
+    pub stuck_steps: usize,
+    pub gate_steps: usize,
+    pub fault_steps: usize,
+}
+
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Sensor {
+    Accelerometer,
+    Gyro,
+    RadarAltimeter,
+    DopplerVelocimeter,
+    Heading,
+}
+
+const SENSORS: usize = 5;
+
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Fault {
+    NotFinite,
+    OutOfRange,
+    Rate,
+    Stuck,
+    Innovation,     // rejected by the Kalman filter
+}
+
+
+/// A sensor declared failed
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct FaultEvent {
+    pub t: Time,
+    pub sensor: Sensor,
+    pub fault: Fault,
+}
+
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+struct Health {
+    failed: bool,
+    valid: bool,                        // last sample usable
+    faults: usize,                      // consecutive faulty samples
+    same: usize,                        // consecutive identical samples
+    rejected: usize,                    // consecutive measurements rejected by the Kalman filter
+    prev: Option<Vec3<f64>>,            // last sample
+    last: Option<Vec3<f64>>,            // last valid sample, for the rate checks
+    t_last: Time,                       // of last
+}
+
+
+impl Health {
+    fn new() -> Self {
+        Health {
+            failed: false,
+            valid: true,
+            faults: 0,
+            same: 0,
+            rejected: 0,
+            prev: None,
+            last: None,
+            t_last: Time::new::<second>(0.0),
+        }
+    }
+}
+
+
+/// Sensors fault detection, isolation and recovery
+///
+/// Every SensorsValues is checked before the navigation uses it: non-finite
+/// values (always), then range, stuck and rate checks (with nav_fdir). A
+/// faulty sample is discarded, and a sensor with fault_steps consecutive
+/// faulty samples, or gate_steps consecutive measurements rejected by the
+/// Kalman filter, is failed for the rest of the flight.
+///
+/// The navigation degrades gracefully, cf. gnc::navigation::nav(): the
+/// altimeter, velocimeter and heading measurements are just dropped, the
+/// accelerations are modeled from the estimated thrust and the attitude
+/// propagated with the last valid body rates (none once the gyroscopes are
+/// failed).
+pub struct Fdir {
+    conf: Option<FdirConf>,
+    health: [Health; SENSORS],
+    pub events: Vec<FaultEvent>,
+}
+
+
+impl Fdir {
+    pub fn new(conf: Option<FdirConf>) -> Self {
+        Fdir {
+            conf: conf,
+            health: [Health::new(); SENSORS],
+            events: vec![],
+        }
+    }
+
+    /// Whether the last sample of a sensor can be used
+    pub fn valid(&self, sensor: Sensor) -> bool {
+        let health = &self.health[sensor as usize];
+        health.valid && !health.failed
+    }
+
+    pub fn failed(&self, sensor: Sensor) -> bool {
+        self.health[sensor as usize].failed
+    }
+
+    /// Check the sensors values at time t
+    ///
+    /// Returns the values without the invalid measurements: the optional ones
+    /// are None, the body rates are zero (cf. valid()). An invalid time step is
+    /// replaced by the nominal one. No sample (None) is not a fault.
+    pub fn check(&mut self, t: Time, dt_nominal: Time, vals: &SensorsValues) -> SensorsValues {
+        let conf = self.conf;
+        let mut ret = *vals;
+
+        if !(ret.dt_step.value.is_finite() && ret.dt_step.value > 0.0) {
+            ret.dt_step = dt_nominal;
+        }
+
+        let acc = vals.spacecraft_acc.map(|a| a.value());
+        let in_range = match (conf, acc) {
+            (Some(c), Some(a)) => a.norm() <= c.acc_max.value,
+            _ => true,
+        };
+        if !self.sample(t, ret.dt_step, Sensor::Accelerometer, acc, in_range, f64::INFINITY, true) {
+            ret.spacecraft_acc = None;
+        }
+
+        let ang_vel = vals.spacecraft_ang_vel.value();
+        let in_range = conf.is_none_or(|c| ang_vel.norm() <= c.ang_vel_max.value);
+        let jump_max = conf.map_or(f64::INFINITY, |c| c.ang_vel_jump_max.value);
+        if !self.sample(t, ret.dt_step, Sensor::Gyro, Some(ang_vel), in_range, jump_max, true) {
+            ret.spacecraft_ang_vel = Vec3::from_value(Vec3::zero());
+        }
+
+        let altitude = vals.spacecraft_altitude.map(|a| Vec3 {x: a.value, y: 0.0, z: 0.0});
+        let in_range = match (conf, vals.spacecraft_altitude) {
+            (Some(c), Some(a)) => (a.value >= 0.0) && (a <= c.altitude_max),
+            _ => true,
+        };
+        let jump_max = conf.map_or(f64::INFINITY, |c| c.altitude_jump_max.value);
+        if !self.sample(t, ret.dt_step, Sensor::RadarAltimeter, altitude, in_range, jump_max, true) {
+            ret.spacecraft_altitude = None;
+        }
+
+        let vel = vals.spacecraft_vel.map(|v| v.value());
+        let in_range = match (conf, vel) {
+            (Some(c), Some(v)) => v.norm() <= c.vel_max.value,
+            _ => true,
+        };
+        let jump_max = conf.map_or(f64::INFINITY, |c| c.vel_jump_max.value);
+        if !self.sample(t, ret.dt_step, Sensor::DopplerVelocimeter, vel, in_range, jump_max, true) {
+            ret.spacecraft_vel = None;
+        }
+
+        // any heading is in range, and it is not stuck when the spacecraft does not turn
+        let heading = vals.spacecraft_heading.map(|h| Vec3 {x: h.value, y: 0.0, z: 0.0});
+        if !self.sample(t, ret.dt_step, Sensor::Heading, heading, true, f64::INFINITY, false) {
+            ret.spacecraft_heading = None;
+        }
+
+        ret
+    }
+
+    /// Kalman filter innovation gating of a measurement
+    pub fn gate(&mut self, t: Time, sensor: Sensor, accepted: bool) {
+        let gate_steps = match self.conf {
+            Some(conf) if conf.gate_steps > 0 => conf.gate_steps,
+            _ => return,
+        };
+
+        let health = &mut self.health[sensor as usize];
+        health.rejected = if accepted { 0 } else { health.rejected + 1 };
+
+        if health.rejected >= gate_steps {
+            self.fail(t, sensor, Fault::Innovation);
+        }
+    }
+
+    /// Check one sample (None if no measurement), returns whether it is valid
+    ///
+    /// Only a noisy sensor can be detected stuck: it is never exactly constant.
+    #[allow(clippy::too_many_arguments)]
+    fn sample(&mut self, t: Time, dt: Time, sensor: Sensor, val: Option<Vec3<f64>>, in_range: bool, jump_max: f64, noisy: bool) -> bool {
+        let val = match val {
+            Some(val) => val,
+            None => return true,
+        };
+
+        let stuck_steps = if noisy { self.conf.map_or(0, |c| c.stuck_steps) } else { 0 };
+        let fault_steps = self.conf.map_or(usize::MAX, |c| c.fault_steps.max(1));
+        let health = &mut self.health[sensor as usize];
+
+        let same = match health.prev {
+            Some(prev) => (prev.x == val.x) && (prev.y == val.y) && (prev.z == val.z),
+            None => false,
+        };
+        health.same = if same { health.same + 1 } else { 0 };
+        health.prev = Some(val);
+
+        // per time step since the last valid sample
+        let jump = match health.last {
+            Some(last) => (val - last).norm()/((t - health.t_last)/dt).get::<ratio>().max(1.0),
+            None => 0.0,
+        };
+
+        let fault = if !(val.x.is_finite() && val.y.is_finite() && val.z.is_finite()) {
+            Some(Fault::NotFinite)
+        } else if !in_range {
+            Some(Fault::OutOfRange)
+        } else if (stuck_steps > 0) && (health.same >= stuck_steps) {
+            Some(Fault::Stuck)
+        } else if jump > jump_max {
+            Some(Fault::Rate)
+        } else {
+            None
+        };
+
+        match fault {
+            None => {
+                health.valid = true;
+                health.faults = 0;
+                health.last = Some(val);
+                health.t_last = t;
+            },
+            Some(fault) => {
+                health.valid = false;
+                health.faults += 1;
+                if health.faults >= fault_steps {
+                    self.fail(t, sensor, fault);
+                }
+            },
+        }
+
+        self.valid(sensor)
+    }
+
+    fn fail(&mut self, t: Time, sensor: Sensor, fault: Fault) {
+        let health = &mut self.health[sensor as usize];
+        if health.failed {
+            return;
+        }
+        health.failed = true;
+
+        let event = FaultEvent {t: t, sensor: sensor, fault: fault};
+        println!("[LOGD:Fdir::fail] FaultEvent={}", serde_json::to_string(&event).unwrap());
+        self.events.push(event);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::acceleration::meter_per_second_squared;
+    use uom::si::angular_velocity::radian_per_second;
+    use uom::si::length::meter;
+    use uom::si::velocity::meter_per_second;
+
+    fn conf() -> FdirConf {
+        FdirConf {
+            acc_max: Acceleration::new::<meter_per_second_squared>(50.0),
+            ang_vel_max: AngularVelocity::new::<radian_per_second>(0.35),
+            altitude_max: Length::new::<meter>(50000.0),
+            vel_max: Velocity::new::<meter_per_second>(2000.0),
+            ang_vel_jump_max: AngularVelocity::new::<radian_per_second>(0.05),
+            altitude_jump_max: Length::new::<meter>(1000.0),
+            vel_jump_max: Velocity::new::<meter_per_second>(100.0),
+            stuck_steps: 10,
+            gate_steps: 50,
+            fault_steps: 3,
+        }
+    }
+
+    fn sensors_vals(i: usize) -> SensorsValues {
+        // a bit of noise, never twice the same values
+        let noise = 1e-4*(i as f64).sin();
+
+        SensorsValues {
+            dt_step: Time::new::<second>(0.1),
+            spacecraft_acc: Some(Vec3::from_value(Vec3 {x: 1.0 + noise, y: -1.62, z: 0.0})),
+            spacecraft_ang_vel: Vec3::from_value(Vec3 {x: 0.0, y: 0.0, z: 0.01 + noise}),
+            spacecraft_altitude: Some(Length::new::<meter>(1000.0 + noise)),
+            spacecraft_vel: Some(Vec3::from_value(Vec3 {x: -20.0 + noise, y: 0.0, z: 0.0})),
+            spacecraft_heading: None,
+            spacecraft_contact: false,
+        }
+    }
+
+    #[test]
+    fn test_fdir_1_not_finite() {
+        // without nav_fdir, the non-finite values are discarded, nothing fails
+        let mut fdir = Fdir::new(None);
+        let t = Time::new::<second>(1.0);
+        let dt_nominal = Time::new::<second>(0.1);
+
+        for i in 0..10 {
+            let mut vals = sensors_vals(i);
+            vals.dt_step = Time::new::<second>(f64::NAN);
+            vals.spacecraft_acc = Some(Vec3::from_value(Vec3 {x: 1.0, y: f64::NAN, z: 0.0}));
+            vals.spacecraft_altitude = Some(Length::new::<meter>(f64::INFINITY));
+
+            let ret = fdir.check(t, dt_nominal, &vals);
+            assert_eq!(ret.dt_step, dt_nominal);
+            assert!(ret.spacecraft_acc.is_none());
+            assert!(ret.spacecraft_altitude.is_none());
+            assert!(ret.spacecraft_vel.is_some());
+
+            assert!(!fdir.valid(Sensor::Accelerometer));
+            assert!(fdir.valid(Sensor::Gyro));
+        }
+
+        assert!(!fdir.failed(Sensor::Accelerometer));
+        assert!(fdir.events.is_empty());
+
+        fdir.check(t, dt_nominal, &sensors_vals(10));
+        assert!(fdir.valid(Sensor::Accelerometer));
+    }
+
+    #[test]
+    fn test_fdir_2_checks() {
+        let mut fdir = Fdir::new(Some(conf()));
+        let dt_nominal = Time::new::<second>(0.1);
+
+        for i in 0..20 {
+            let t = Time::new::<second>(0.1*i as f64);
+            let mut vals = sensors_vals(i);
+
+            // a spike, then out of range for good
+            if i == 5 {
+                vals.spacecraft_vel = Some(Vec3::from_value(Vec3 {x: 500.0, y: 0.0, z: 0.0}));
+            }
+            if i >= 8 {
+                vals.spacecraft_altitude = Some(Length::new::<meter>(-10.0));
+            }
+            // stuck
+            vals.spacecraft_ang_vel = Vec3::from_value(Vec3 {x: 0.0, y: 0.0, z: 0.01});
+
+            let ret = fdir.check(t, dt_nominal, &vals);
+            assert_eq!(ret.spacecraft_vel.is_some(), i != 5);
+            assert_eq!(ret.spacecraft_altitude.is_some(), i < 8);
+        }
+
+        assert!(!fdir.failed(Sensor::Accelerometer));
+        assert!(!fdir.failed(Sensor::DopplerVelocimeter));
+        assert!(fdir.failed(Sensor::RadarAltimeter));
+        assert!(fdir.failed(Sensor::Gyro));
+
+        assert_eq!(fdir.events.len(), 2);
+        assert_eq!(fdir.events[0].sensor, Sensor::RadarAltimeter);
+        assert_eq!(fdir.events[0].fault, Fault::OutOfRange);
+        assert_eq!(fdir.events[0].t, Time::new::<second>(0.1*10.0));
+        assert_eq!(fdir.events[1].sensor, Sensor::Gyro);
+        assert_eq!(fdir.events[1].fault, Fault::Stuck);
+
+        // failed for good
+        let ret = fdir.check(Time::new::<second>(2.0), dt_nominal, &sensors_vals(20));
+        assert!(ret.spacecraft_altitude.is_none());
+    }
+
+    #[test]
+    fn test_fdir_3_gate() {
+        let mut fdir = Fdir::new(Some(conf()));
+        let t = Time::new::<second>(1.0);
+
+        // a few rejections in a row are fine
+        for _ in 0..49 {
+            fdir.gate(t, Sensor::DopplerVelocimeter, false);
+        }
+        fdir.gate(t, Sensor::DopplerVelocimeter, true);
+        for _ in 0..49 {
+            fdir.gate(t, Sensor::DopplerVelocimeter, false);
+        }
+        assert!(!fdir.failed(Sensor::DopplerVelocimeter));
+
+        fdir.gate(t, Sensor::DopplerVelocimeter, false);
+        assert!(fdir.failed(Sensor::DopplerVelocimeter));
+        assert_eq!(fdir.events[0].fault, Fault::Innovation);
+    }
+
+    #[test]
+    fn test_fdir_4_dropout() {
+        let mut fdir = Fdir::new(Some(conf()));
+        let dt_nominal = Time::new::<second>(0.1);
+
+        // no velocity for 20 s, it changed by more than vel_jump_max meanwhile
+        for i in 0..250 {
+            let t = Time::new::<second>(0.1*i as f64);
+            let mut vals = sensors_vals(i);
+            vals.spacecraft_vel = if i < 50 {
+                Some(Vec3::from_value(Vec3 {x: -450.0 + 1e-4*(i as f64).sin(), y: 0.0, z: 0.0}))
+            } else {
+                None
+            };
+            fdir.check(t, dt_nominal, &vals);
+        }
+
+        for i in 250..260 {
+            let t = Time::new::<second>(0.1*i as f64);
+            let mut vals = sensors_vals(i);
+            vals.spacecraft_vel = Some(Vec3::from_value(Vec3 {x: -350.0 + 1e-4*(i as f64).sin(), y: 0.0, z: 0.0}));
+
+            let ret = fdir.check(t, dt_nominal, &vals);
+            assert!(ret.spacecraft_vel.is_some());
+        }
+        assert!(!fdir.failed(Sensor::DopplerVelocimeter));
+
+        // still a jump right after
+        let mut vals = sensors_vals(260);
+        vals.spacecraft_vel = Some(Vec3::from_value(Vec3 {x: -200.0, y: 0.0, z: 0.0}));
+        let ret = fdir.check(Time::new::<second>(26.0), dt_nominal, &vals);
+        assert!(ret.spacecraft_vel.is_none());
+    }
+
+    #[test]
+    fn test_fdir_5_no_sample() {
+        // no new accelerometer sample (cf. adapters::ksp) is not a fault
+        let mut fdir = Fdir::new(Some(conf()));
+        let dt_nominal = Time::new::<second>(0.1);
+
+        for i in 0..20 {
+            let t = Time::new::<second>(0.1*i as f64);
+            let mut vals = sensors_vals(i);
+            if i % 2 == 1 {
+                vals.spacecraft_acc = None;
+            }
+
+            let ret = fdir.check(t, dt_nominal, &vals);
+            assert_eq!(ret.spacecraft_acc.is_some(), i % 2 == 0);
+        }
+
+        assert!(fdir.valid(Sensor::Accelerometer));
+        assert!(fdir.events.is_empty());
+    }
+}
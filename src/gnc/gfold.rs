@@ -0,0 +1,550 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::angle::radian;
+use uom::si::length::meter;
+use uom::si::mass::kilogram;
+use uom::si::ratio::ratio;
+use uom::si::time::second;
+use uom::si::velocity::meter_per_second;
+
+use crate::utils::math::Vec3;
+use crate::utils::socp::{self, Cone, Row, Socp, SocpStatus};
+use crate::utils::space::G0;
+
+
+/// Final time search: grid, then golden section around the best point
+const GFOLD_TF_GRID: usize = 6;
+const GFOLD_TF_GOLDEN: usize = 5;
+
+
+/// Tuning of the powered descent optimization
+///
+/// Yaml serialized equivalent:
+///
+/// gui_gfold:
+///   steps: 30                     # the thrust is constant over each step
+///   throttle_min: 0.1             # of the nominal thrust
+///   throttle_max: 0.6             # Apollo DPS, throttleable between 10% and 60%
+///   glide_slope: 0.2793           # rad, 16 deg, min elevation of the spacecraft seen from the target
+///   tilt_max: 1.0472              # rad, 60 deg, of the thrust from the vertical
+///   jerk_max: 0.15                # m/s**3, of the thrust acceleration, the attitude turns slowly
+///   tf_min: 60.0                  # s, final time search
+///   tf_max: 240.0
+///   period: 10.0                  # s, re-solved from the current state
+///   tracking_tau: 5.0             # s, of the position and velocity errors
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GfoldConf {
+    pub steps: usize,
+    pub throttle_min: Ratio,
+    pub throttle_max: Ratio,
+    pub glide_slope: Angle,
+    pub tilt_max: Angle,
+    pub jerk_max: Jerk,
+    pub tf_min: Time,
+    pub tf_max: Time,
+    pub period: Time,
+    pub tracking_tau: Time,
+}
+
+
+/// Initial state and target of a powered descent
+///
+/// Flat ground and uniform gravity (body gravity minus centrifugal): fine for
+/// the last kilometers, not for the braking from orbit.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Descent {
+    pub pos: Vec3<Length>,
+    pub vel: Vec3<Velocity>,
+    pub acc: Vec3<Acceleration>,    // thrust, the first step starts from it
+    pub mass: Mass,
+    pub fuel: Mass,                 // available
+    pub thrust: Force,              // nominal, full throttle
+    pub isp: Time,
+    pub gravity: Vec3<Acceleration>,
+    pub pf: Vec3<Length>,
+    pub vf: Vec3<Velocity>,
+}
+
+
+/// Reference trajectory, the thrust acceleration is constant over each step
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Reference {
+    pub t0: Time,
+    pub dt: Time,
+    pub gravity: Vec3<Acceleration>,
+    pub pos: Vec<Vec3<Length>>,     // steps+1
+    pub vel: Vec<Vec3<Velocity>>,   // steps+1
+    pub acc: Vec<Vec3<Acceleration>>,   // steps, thrust
+    pub fuel: Mass,
+}
+
+
+impl Reference {
+    pub fn t_end(&self) -> Time {
+        self.t0 + self.dt*(self.acc.len() as f64)
+    }
+
+    /// Position, velocity and thrust acceleration at t (the last ones after t_end)
+    pub fn sample(&self, t: Time) -> (Vec3<Length>, Vec3<Velocity>, Vec3<Acceleration>) {
+        let steps = self.acc.len();
+        let k = ((t - self.t0)/self.dt).get::<ratio>().floor().max(0.0) as usize;
+        let k = k.min(steps-1);
+        let tau = (t - self.t0 - self.dt*(k as f64)).min(self.dt).max(Time::new::<second>(0.0));
+
+        let acc = self.acc[k] + self.gravity;
+        let vel = self.vel[k] + acc*tau;
+        let pos = self.pos[k] + self.vel[k]*tau + acc*(tau*tau/2.0);
+
+        (pos, vel, self.acc[k])
+    }
+}
+
+
+/// Non-dimensional units of a problem: lengths relative to the distance to go,
+/// times relative to tf
+struct Units {
+    length: Length,
+    time: Time,
+}
+
+
+impl Units {
+    fn acc(&self) -> Acceleration {
+        self.length/(self.time*self.time)
+    }
+
+    fn vel(&self) -> Velocity {
+        self.length/self.time
+    }
+}
+
+
+/// Variables: thrust acceleration u_k (3 per step), then its norm bound s_k
+fn u_idx(k: usize, axis: usize) -> usize {
+    3*k + axis
+}
+
+fn s_idx(steps: usize, k: usize) -> usize {
+    3*steps + k
+}
+
+
+/// Position at step k, affine in the thrust accelerations (piecewise constant):
+///     r_k = r0 + k*dt*v0 + g*(k*dt)**2/2 + dt**2*sum_{j<k} (k-j-1/2)*u_j
+/// Returns the constant part and the coefficients of u_j (same for each axis).
+fn position_map(r0: f64, v0: f64, g: f64, dt: f64, k: usize) -> (f64, Vec<(usize, f64)>) {
+    let t = dt*(k as f64);
+    let constant = r0 + t*v0 + g*t*t/2.0;
+    let coefs = (0..k).map(|j| (j, dt*dt*((k - j) as f64 - 0.5))).collect();
+    (constant, coefs)
+}
+
+
+/// Thrust bounds at step k, linear in s_j (j < k), non-dimensional
+///
+/// With z = ln(m), the bounds rho/m_k = rho*exp(-z_k) are linearized around
+/// the lightest mass at step k (full throttle since the start), z0_k:
+///     rho*exp(-z0_k)*(1 - (z_k - z0_k))
+/// which is conservative for the upper bound, and below the lower bound by a
+/// second-order term only. z_k is affine in the thrust accelerations:
+///     z_k = ln(m0) - dt/ve*sum_{j<k} s_j
+/// Returns (rho_min, rho_max)*exp(-z0_k), and z_0 - z0_k.
+fn thrust_bounds(conf: &GfoldConf, d: &Descent, units: &Units, t: Time) -> (f64, f64, f64) {
+    let ve: Velocity = d.isp*(*G0);
+    let flow = conf.throttle_max*d.thrust/ve;
+    let lightest = (d.mass - flow*t).max(d.mass - d.fuel);
+
+    let lo: Acceleration = conf.throttle_min*d.thrust/lightest;
+    let hi: Acceleration = conf.throttle_max*d.thrust/lightest;
+    (
+        (lo/units.acc()).get::<ratio>(),
+        (hi/units.acc()).get::<ratio>(),
+        (d.mass/lightest).get::<ratio>().ln(),
+    )
+}
+
+
+/// Minimum fuel powered descent, at a fixed final time
+///
+/// Second-order cone program, with the lossless convexification of the thrust
+/// bounds (the non-convex min thrust becomes a bound on the slack s):
+///     min sum s_k
+///     s.t. |u_k| <= s_k
+///          rho_min/m_k <= s_k <= rho_max/m_k (linearized in ln(m_k))
+///          u_k.y >= s_k*cos(tilt_max) (k > 0)
+///          |u_k - u_k-1| <= jerk_max*dt (u_-1: the current thrust, within the bounds)
+///          r_k.y - pf.y >= tan(glide_slope)*|r_k.xz - pf.xz|
+///          r_N = pf, v_N = vf, u_N-1 vertical
+///          m_N >= m0 - fuel
+/// The last step is vertical, to hand over upright to the vertical descent.
+/// The positions are eliminated (affine in u), hence the only variables are
+/// u and s.
+///
+/// Returns None if the problem is infeasible (not enough thrust, or fuel, or
+/// time), or did not converge.
+///
+/// Source: Acikmese, Carson, Blackmore, Lossless convexification of nonconvex
+/// control bound and pointing constraints of the soft landing optimal control
+/// problem, 2013
+pub fn solve(conf: &GfoldConf, d: &Descent, t0: Time, tf: Time) -> Option<Reference> {
+    let n = conf.steps;
+    let units = Units {
+        length: (d.pos - d.pf).norm().max(Length::new::<meter>(1.0)),
+        time: tf,
+    };
+    let dt = tf/(n as f64);
+    let dt_nd = 1.0/(n as f64);
+
+    let r0 = ((d.pos - d.pf)/units.length).map(|a| a.get::<ratio>());
+    let v0 = (d.vel/units.vel()).map(|a| a.get::<ratio>());
+    let vf = (d.vf/units.vel()).map(|a| a.get::<ratio>());
+    let g = (d.gravity/units.acc()).map(|a| a.get::<ratio>());
+    let axis = |v: &Vec3<f64>, i: usize| [v.x, v.y, v.z][i];
+
+    let tan_glide = conf.glide_slope.get::<radian>().tan();
+    let cos_tilt = conf.tilt_max.get::<radian>().cos();
+
+    // objective: fuel, i.e. the integral of the thrust acceleration
+
+    let mut c = vec![0.0; 4*n];
+    for k in 0..n {
+        c[s_idx(n, k)] = 1.0;
+    }
+
+    // final position and velocity
+
+    let mut e: Vec<Row> = vec![];
+    let mut f = vec![];
+    for i in 0..3 {
+        let (constant, coefs) = position_map(axis(&r0, i), axis(&v0, i), axis(&g, i), dt_nd, n);
+        e.push(coefs.iter().map(|&(j, a)| (u_idx(j, i), a)).collect());
+        f.push(-constant);
+    }
+    for i in 0..3 {
+        e.push((0..n).map(|j| (u_idx(j, i), dt_nd)).collect());
+        f.push(axis(&vf, i) - axis(&v0, i) - axis(&g, i));
+    }
+    for i in [0, 2].iter() {
+        e.push(vec![(u_idx(n-1, *i), 1.0)]);
+        f.push(0.0);
+    }
+
+    // thrust bounds, tilt and fuel, then the cones
+
+    let mut g_rows: Vec<Row> = vec![];
+    let mut h = vec![];
+    let mut cones = vec![];
+
+    let kappa = (dt*units.acc()/(d.isp*(*G0))).get::<ratio>();
+    for k in 0..n {
+        let (lo, hi, dz) = thrust_bounds(conf, d, &units, dt*(k as f64));
+        g_rows.push((0..k).map(|j| (s_idx(n, j), -lo*kappa)).chain(Some((s_idx(n, k), 1.0))).collect());
+        h.push(lo*(1.0 - dz));
+        g_rows.push((0..k).map(|j| (s_idx(n, j), hi*kappa)).chain(Some((s_idx(n, k), -1.0))).collect());
+        h.push(-hi*(1.0 - dz));
+        // the first step starts from the current thrust, which may be tilted more
+        let cos_tilt = if k == 0 { -1.0 } else { cos_tilt };
+        g_rows.push(vec![(u_idx(k, 1), 1.0), (s_idx(n, k), -cos_tilt)]);
+        h.push(0.0);
+    }
+    g_rows.push((0..n).map(|j| (s_idx(n, j), -kappa)).collect());
+    h.push(-(d.mass/(d.mass - d.fuel)).get::<ratio>().ln());
+    cones.push(Cone::NonNegative(3*n + 1));
+
+    for k in 0..n {
+        g_rows.push(vec![(s_idx(n, k), 1.0)]);
+        for i in 0..3 {
+            g_rows.push(vec![(u_idx(k, i), 1.0)]);
+        }
+        h.extend_from_slice(&[0.0; 4]);
+        cones.push(Cone::SecondOrder(4));
+    }
+
+    // within the thrust bounds, control may fly out of them before the handover
+    let a0 = (d.acc/units.acc()).map(|a| a.get::<ratio>());
+    let (lo, hi, _) = thrust_bounds(conf, d, &units, Time::new::<second>(0.0));
+    let a0 = a0.unit()*a0.norm().clamp(lo, hi);
+    let du = (conf.jerk_max*dt/units.acc()).get::<ratio>();
+    for k in 0..n {
+        g_rows.push(vec![]);
+        h.push(-du);
+        for i in 0..3 {
+            if k == 0 {
+                g_rows.push(vec![(u_idx(k, i), 1.0)]);
+                h.push(axis(&a0, i));
+            } else {
+                g_rows.push(vec![(u_idx(k, i), 1.0), (u_idx(k-1, i), -1.0)]);
+                h.push(0.0);
+            }
+        }
+        cones.push(Cone::SecondOrder(4));
+    }
+
+    for k in 1..n {
+        for (i, scale) in [(1, 1.0), (0, tan_glide), (2, tan_glide)].iter() {
+            let (constant, coefs) = position_map(axis(&r0, *i), axis(&v0, *i), axis(&g, *i), dt_nd, k);
+            g_rows.push(coefs.iter().map(|&(j, a)| (u_idx(j, *i), a*scale)).collect());
+            h.push(-constant*scale);
+        }
+        cones.push(Cone::SecondOrder(3));
+    }
+
+    let problem = Socp {
+        c: c,
+        e: e,
+        f: f,
+        g: g_rows,
+        h: h,
+        cones: cones,
+    };
+
+    let (status, x) = socp::solve(&problem);
+    if status != SocpStatus::Solved {
+        return None;
+    }
+
+    // reference trajectory, propagated with the solution
+
+    let acc: Vec<Vec3<Acceleration>> = (0..n).map(|k| Vec3 {
+        x: units.acc()*x[u_idx(k, 0)],
+        y: units.acc()*x[u_idx(k, 1)],
+        z: units.acc()*x[u_idx(k, 2)],
+    }).collect();
+
+    let mut pos = vec![d.pos];
+    let mut vel = vec![d.vel];
+    let mut dv = Velocity::new::<meter_per_second>(0.0);
+    for u in &acc {
+        let a = *u + d.gravity;
+        let (p, v) = (pos[pos.len()-1], vel[vel.len()-1]);
+        pos.push(p + v*dt + a*(dt*dt/2.0));
+        vel.push(v + a*dt);
+        dv += u.norm()*dt;
+    }
+    let fuel = d.mass*(1.0 - (-(dv/(d.isp*(*G0))).get::<ratio>()).exp());
+
+    Some(Reference {
+        t0: t0,
+        dt: dt,
+        gravity: d.gravity,
+        pos: pos,
+        vel: vel,
+        acc: acc,
+        fuel: fuel,
+    })
+}
+
+
+/// Minimum fuel powered descent, the final time is optimized too
+///
+/// The fuel is unimodal in tf (too short: not enough thrust, too long: gravity
+/// losses): a coarse grid, then a golden section search around its best point.
+pub fn solve_free_time(conf: &GfoldConf, d: &Descent, t0: Time) -> Option<Reference> {
+    let mut best: Option<Reference> = None;
+
+    // infeasible, or numerically broken: infinite fuel
+    let eval = |tf: Time, best: &mut Option<Reference>| -> Mass {
+        match solve(conf, d, t0, tf) {
+            None => Mass::new::<kilogram>(f64::INFINITY),
+            Some(reference) if reference.fuel.value.is_nan() => Mass::new::<kilogram>(f64::INFINITY),
+            Some(reference) => {
+                let fuel = reference.fuel;
+                if best.as_ref().is_none_or(|b| fuel < b.fuel) {
+                    *best = Some(reference);
+                }
+                fuel
+            },
+        }
+    };
+
+    let step = (conf.tf_max - conf.tf_min)/((GFOLD_TF_GRID - 1) as f64);
+    let grid: Vec<Time> = (0..GFOLD_TF_GRID).map(|i| conf.tf_min + step*(i as f64)).collect();
+    let fuels: Vec<Mass> = grid.iter().map(|tf| eval(*tf, &mut best)).collect();
+
+    let i_best = (0..GFOLD_TF_GRID).min_by(|&i, &j| fuels[i].value.total_cmp(&fuels[j].value))?;
+    if !fuels[i_best].value.is_finite() {
+        return None;
+    }
+
+    let phi = (5.0f64.sqrt() - 1.0)/2.0;
+    let mut a = grid[i_best.max(1) - 1];
+    let mut b = grid[(i_best + 1).min(GFOLD_TF_GRID - 1)];
+    for _ in 0..GFOLD_TF_GOLDEN {
+        let t1 = b - (b - a)*phi;
+        let t2 = a + (b - a)*phi;
+        if eval(t1, &mut best) < eval(t2, &mut best) {
+            b = t2;
+        } else {
+            a = t1;
+        }
+    }
+
+    best
+}
+
+
+/// Powered descent guidance state: the reference trajectory, and when it was
+/// solved
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Gfold {
+    pub reference: Option<Reference>,
+    t_solve: Option<Time>,
+}
+
+
+impl Gfold {
+    pub fn new() -> Self {
+        Gfold {
+            reference: None,
+            t_solve: None,
+        }
+    }
+
+    pub fn tgo(&self, t: Time) -> Option<Time> {
+        self.reference.as_ref().map(|reference| reference.t_end() - t)
+    }
+
+    /// Solve the reference trajectory every period, from the current state:
+    /// with a free final time while there is none, then to the same final time
+    ///
+    /// A failed update keeps the previous reference.
+    pub fn update(&mut self, conf: &GfoldConf, d: &Descent, t: Time) {
+        if self.t_solve.is_some_and(|t_solve| t - t_solve < conf.period) {
+            return;
+        }
+
+        let reference = match &self.reference {
+            None => solve_free_time(conf, d, t),
+            Some(reference) => {
+                let tgo = reference.t_end() - t;
+                if tgo < conf.period {
+                    return;
+                }
+                solve(conf, d, t, tgo)
+            },
+        };
+        self.t_solve = Some(t);
+
+        match reference {
+            None => {
+                println!("[LOGD:Gfold::update] t={:.3} infeasible", t.get::<second>());
+            },
+            Some(reference) => {
+                println!(
+                    "[LOGD:Gfold::update] t={:.3} tf={:.3} fuel={:.3}",
+                    t.get::<second>(), (reference.t_end() - t).get::<second>(), reference.fuel.get::<kilogram>(),
+                );
+                self.reference = Some(reference);
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::acceleration::meter_per_second_squared;
+    use uom::si::angle::degree;
+    use uom::si::force::newton;
+    use uom::si::jerk::meter_per_second_cubed;
+
+    use crate::assert_approx_eq;
+
+    fn conf() -> GfoldConf {
+        GfoldConf {
+            steps: 20,
+            throttle_min: Ratio::new::<ratio>(0.2),
+            throttle_max: Ratio::new::<ratio>(0.8),
+            glide_slope: Angle::new::<degree>(10.0),
+            tilt_max: Angle::new::<degree>(60.0),
+            jerk_max: Jerk::new::<meter_per_second_cubed>(0.5),
+            tf_min: Time::new::<second>(40.0),
+            tf_max: Time::new::<second>(120.0),
+            period: Time::new::<second>(10.0),
+            tracking_tau: Time::new::<second>(5.0),
+        }
+    }
+
+    /// LM like, 2 km downrange of the target at 1 km
+    fn descent() -> Descent {
+        Descent {
+            pos: Vec3::from_value(Vec3 {x: -2000.0, y: 1000.0, z: 100.0}),
+            vel: Vec3::from_value(Vec3 {x: 60.0, y: -20.0, z: 0.0}),
+            acc: Vec3::from_value(Vec3 {x: -2.0, y: 2.0, z: 0.0}),
+            mass: Mass::new::<kilogram>(8000.0),
+            fuel: Mass::new::<kilogram>(1500.0),
+            thrust: Force::new::<newton>(45000.0),
+            isp: Time::new::<second>(311.0),
+            gravity: Vec3::from_value(Vec3 {x: 0.0, y: -1.62, z: 0.0}),
+            pf: Vec3::from_value(Vec3 {x: 0.0, y: 30.0, z: 0.0}),
+            vf: Vec3::from_value(Vec3 {x: 0.0, y: -1.0, z: 0.0}),
+        }
+    }
+
+    #[test]
+    fn test_gfold_1_fixed_time() {
+        let conf = conf();
+        let d = descent();
+        let t0 = Time::new::<second>(100.0);
+        let reference = solve(&conf, &d, t0, Time::new::<second>(80.0)).unwrap();
+
+        // on target
+        assert_approx_eq!(reference.t_end().get::<second>(), 180.0, 1e-9);
+        let (pos, vel, _acc) = reference.sample(reference.t_end());
+        assert_approx_eq!(pos.x.get::<meter>(), 0.0, 5.0);
+        assert_approx_eq!(pos.y.get::<meter>(), 30.0, 5.0);
+        assert_approx_eq!(pos.z.get::<meter>(), 0.0, 5.0);
+        assert_approx_eq!(vel.y.get::<meter_per_second>(), -1.0, 0.2);
+
+        // within the constraints
+        let acc_min = (conf.throttle_min*d.thrust/d.mass).get::<meter_per_second_squared>();
+        let acc_max = (conf.throttle_max*d.thrust/(d.mass - reference.fuel)).get::<meter_per_second_squared>();
+        for u in &reference.acc {
+            let norm = u.norm().get::<meter_per_second_squared>();
+            assert!((acc_min*0.99 < norm) && (norm < acc_max*1.01), "{}", norm);
+            assert!(u.value().unit().y > Angle::new::<degree>(60.0).cos().value - 0.01);
+        }
+        for pos in &reference.pos {
+            let above = (pos.y - d.pf.y).get::<meter>();
+            let horizontal = Vec3 {x: (pos.x - d.pf.x).value, y: 0.0, z: (pos.z - d.pf.z).value}.norm();
+            assert!(above > Angle::new::<degree>(10.0).tan().value*horizontal - 5.0);
+        }
+
+        // sampling in between two steps: continuous
+        let (p1, v1, _acc) = reference.sample(t0 + reference.dt*2.0 - Time::new::<second>(1e-6));
+        let (p2, v2, _acc) = reference.sample(t0 + reference.dt*2.0);
+        assert!((p1 - p2).norm() < Length::new::<meter>(1e-3));
+        assert!((v1 - v2).norm() < Velocity::new::<meter_per_second>(1e-3));
+    }
+
+    #[test]
+    fn test_gfold_2_free_time() {
+        let conf = conf();
+        let d = descent();
+        let t0 = Time::new::<second>(0.0);
+        let best = solve_free_time(&conf, &d, t0).unwrap();
+
+        // better than (or as good as) the bounds of the search
+        for tf in [conf.tf_min, conf.tf_max].iter() {
+            if let Some(reference) = solve(&conf, &d, t0, *tf) {
+                assert!(best.fuel <= reference.fuel + Mass::new::<kilogram>(1.0));
+            }
+        }
+
+        // not enough time to brake: infeasible
+        assert!(solve(&conf, &d, t0, Time::new::<second>(10.0)).is_none());
+
+        // not enough thrust: infeasible
+        let mut heavy = d;
+        heavy.mass = Mass::new::<kilogram>(40_000.0);
+        assert!(solve_free_time(&conf, &heavy, t0).is_none());
+    }
+}
@@ -1,27 +1,73 @@
 use uom::si::f64::*;
 use uom::si::acceleration::meter_per_second_squared;
+use uom::si::angle::{degree, radian};
 use crate::conf::GuiSpacecraft;
 use crate::gnc::common::Spacecraft;
+use crate::gnc::gfold::Descent;
 use crate::squared;
-use crate::utils::math::Vec2;
+use crate::utils::math::{Vec3, heading_pitch, direction, saturate};
 
 
-pub fn gui(spacecraft: &mut Spacecraft, tgo: Time) {
-    let body_acc_y = (
+/// Vertical acceleration of the body: gravity and centrifugal force
+fn body_acc_y(spacecraft: &Spacecraft) -> Acceleration {
+    (
         -spacecraft.conf.s.body.gravity(spacecraft.cur.pos.y)
-        +spacecraft.conf.s.body.centrifugal(spacecraft.cur.vel.x, spacecraft.cur.pos.y)
-    );
+        +spacecraft.conf.s.body.centrifugal(spacecraft.cur.vel_inertial.horizontal_norm(), spacecraft.cur.pos.y)
+    )
+}
+
+
+pub fn gui(spacecraft: &mut Spacecraft, tgo: Time) {
+    let body_acc_y = body_acc_y(spacecraft);
+
+    let gui_spacecraft = spacecraft.conf.s.gui_spacecraft;
 
-    let acc = match spacecraft.conf.s.gui_spacecraft {
+    let acc = match gui_spacecraft {
         GuiSpacecraft::GuiDescent => gui_descent(spacecraft, tgo),
         GuiSpacecraft::GuiAscentToOrbit => gui_ascent_orbit(spacecraft, tgo),
         GuiSpacecraft::GuiAscentToHover => gui_ascent_hover(spacecraft, tgo),
+        GuiSpacecraft::GuiVerticalDescent => gui_vertical_descent(spacecraft),
+        GuiSpacecraft::GuiAscentPeg => gui_ascent_peg(spacecraft, tgo),
+        GuiSpacecraft::GuiGfold => gui_gfold(spacecraft, tgo),
     };
 
-    spacecraft.cur.gui = acc + Vec2 {
+    // the linear tangent steering already accounts for the gravity and the centrifugal force
+    let body_compensation = match gui_spacecraft {
+        GuiSpacecraft::GuiAscentPeg => Acceleration::new::<meter_per_second_squared>(0.0),
+        _ => -body_acc_y,
+    };
+
+    let acc = acc + Vec3 {
         x: Acceleration::new::<meter_per_second_squared>(0.0),
-        y: -body_acc_y,
+        y: body_compensation,
+        z: Acceleration::new::<meter_per_second_squared>(0.0),
+    };
+
+    spacecraft.cur.gui = match gui_spacecraft {
+        GuiSpacecraft::GuiAscentToOrbit | GuiSpacecraft::GuiAscentPeg => gui_pitchover(spacecraft, acc),
+        _ => acc,
+    };
+}
+
+
+/// Pitch over from the vertical after lift-off
+///
+/// To avoid a dangerously big angular command, the direction goes from the
+/// vertical to the one of the guidance law at a constant pitch rate, during
+/// gui_pitchover. The norm is kept, control flies full thrust anyway.
+fn gui_pitchover(spacecraft: &Spacecraft, acc: Vec3<Acceleration>) -> Vec3<Acceleration> {
+    let tf = spacecraft.conf.s.gui_pitchover;
+
+    if spacecraft.cur.t > tf {
+        return acc;
     }
+
+    let (heading, af) = heading_pitch(acc.value().unit());
+
+    let na: Angle = (spacecraft.cur.t/tf*(Angle::new::<degree>(90.0)-af)).into();
+
+    let pitch: Angle = Angle::new::<degree>(90.0) - na;
+    direction(heading, pitch)*acc.norm()
 }
 
 
@@ -33,9 +79,10 @@ pub fn gui(spacecraft: &mut Spacecraft, tgo: Time) {
 /// y: Quadratic law
 ///     acc = k1*t**2+k2*t+k3
 ///     acc = af -6/tgo*(v0+vf) + 12/tgo.powi(2)*(pf-p0)
+/// z: Quadratic law (same as x)
 /// For more info, cf. https://blog.nodraak.fr/2020/12/aerospace-sim-2-guidance-law/
-pub fn gui_descent(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration> {
-    let conf = spacecraft.conf.s;
+pub fn gui_descent(spacecraft: &Spacecraft, tgo: Time) -> Vec3<Acceleration> {
+    let conf = &spacecraft.conf.s;
 
     // x
 
@@ -59,11 +106,23 @@ pub fn gui_descent(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration> {
         + 12.0/squared!(tgo)*(conf.gui_pf_y-p0_y)
     );
 
+    // z
+
+    let v0_z = spacecraft.cur.vel.z;
+    let p0_z = spacecraft.cur.pos.z;
+
+    let acc_z = (
+        conf.gui_af_z
+        -6.0/tgo*(v0_z+conf.gui_vf_z)
+        + 12.0/squared!(tgo)*(conf.gui_pf_z-p0_z)
+    );
+
     // return
 
-    Vec2 {
+    Vec3 {
         x: acc_x,
         y: acc_y,
+        z: acc_z,
     }
 }
 
@@ -76,9 +135,11 @@ pub fn gui_descent(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration> {
 /// y: Quadratic law:
 ///     acc = k1*t**2+k2*t+k3
 ///     acc = af -6/tgo*(v0+vf) + 12/tgo.powi(2)*(pf-p0)
+/// z: Quadratic law (null the cross-range error, to stay in the orbital plane)
+///     acc = af -6/tgo*(v0+vf) + 12/tgo.powi(2)*(pf-p0)
 /// For more info, cf. https://blog.nodraak.fr/2020/12/aerospace-sim-2-guidance-law/
-pub fn gui_ascent_orbit(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration> {
-    let conf = spacecraft.conf.s;
+pub fn gui_ascent_orbit(spacecraft: &Spacecraft, tgo: Time) -> Vec3<Acceleration> {
+    let conf = &spacecraft.conf.s;
 
     // x
 
@@ -100,11 +161,23 @@ pub fn gui_ascent_orbit(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration
         + 12.0/squared!(tgo)*(conf.gui_pf_y-p0_y)
     );
 
+    // z
+
+    let v0_z = spacecraft.cur.vel.z;
+    let p0_z = spacecraft.cur.pos.z;
+
+    let acc_z = (
+        conf.gui_af_z
+        -6.0/tgo*(v0_z+conf.gui_vf_z)
+        + 12.0/squared!(tgo)*(conf.gui_pf_z-p0_z)
+    );
+
     // return
 
-    Vec2 {
+    Vec3 {
         x: acc_x,
         y: acc_y,
+        z: acc_z,
     }
 }
 
@@ -116,9 +189,10 @@ pub fn gui_ascent_orbit(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration
 /// y: Quadratic law:
 ///     acc = k1*t**2+k2*t+k3
 ///     acc = af -6/tgo*(v0+vf) + 12/tgo.powi(2)*(pf-p0)
+/// z: Linear law (same as x)
 /// For more info, cf. https://blog.nodraak.fr/2020/12/aerospace-sim-2-guidance-law/
-pub fn gui_ascent_hover(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration> {
-    let conf = spacecraft.conf.s;
+pub fn gui_ascent_hover(spacecraft: &Spacecraft, tgo: Time) -> Vec3<Acceleration> {
+    let conf = &spacecraft.conf.s;
 
     // x
 
@@ -141,11 +215,151 @@ pub fn gui_ascent_hover(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration
         + 12.0/squared!(tgo)*(conf.gui_pf_y-p0_y)
     );
 
+    // z
+
+    let v0_z = spacecraft.cur.vel.z;
+    let p0_z = spacecraft.cur.pos.z;
+
+    let acc_z = (
+        -2.0/tgo * (conf.gui_vf_z+2.0*v0_z)
+        +6.0/squared!(tgo)*(conf.gui_pf_z-p0_z)
+    );
+
     // return
 
-    Vec2 {
+    Vec3 {
         x: acc_x,
         y: acc_y,
+        z: acc_z,
+    }
+}
+
+/// Ascent guidance to orbit, Powered Explicit Guidance (cf. gnc::peg)
+///
+/// Full thrust along the linear tangent steering direction, in the plane of
+/// the trajectory. Targets the insertion altitude, velocity and flight-path
+/// angle of gui_peg, the gui_* targets are not used. The tgo given is only the
+/// initial guess, the PEG then iterates its own (cf. Spacecraft::peg).
+pub fn gui_ascent_peg(spacecraft: &mut Spacecraft, tgo: Time) -> Vec3<Acceleration> {
+    let conf = &spacecraft.conf.s;
+    let target = conf.gui_peg.as_ref().unwrap();
+    let cur = &spacecraft.cur;
+
+    let acc_thrust: Acceleration = cur.thrust/(conf.sc_dry_mass + cur.fuel_mass);
+
+    let dir = spacecraft.peg.cycle(
+        conf,
+        target,
+        cur.t,
+        cur.pos.y,
+        cur.vel_inertial.y,
+        cur.vel_inertial.x,
+        acc_thrust,
+        cur.isp,
+        tgo,
+    );
+
+    dir*acc_thrust
+}
+
+/// Powered descent from the current state to the gui_pf and gui_vf targets (cf. gnc::gfold)
+pub fn gfold_descent(spacecraft: &Spacecraft) -> Descent {
+    let conf = &spacecraft.conf.s;
+    let cur = &spacecraft.cur;
+
+    Descent {
+        pos: cur.pos,
+        vel: cur.vel,
+        acc: cur.att.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0})*cur.acc_thrust,
+        mass: conf.sc_dry_mass + cur.fuel_mass,
+        fuel: cur.fuel_mass,
+        thrust: cur.thrust,
+        isp: cur.isp,
+        gravity: Vec3 {
+            x: Acceleration::new::<meter_per_second_squared>(0.0),
+            y: body_acc_y(spacecraft),
+            z: Acceleration::new::<meter_per_second_squared>(0.0),
+        },
+        pf: Vec3 {x: conf.gui_pf_x, y: conf.gui_pf_y, z: conf.gui_pf_z},
+        vf: Vec3 {x: conf.gui_vf_x, y: conf.gui_vf_y, z: conf.gui_vf_z},
+    }
+}
+
+/// Powered descent guidance, tracking a fuel optimal trajectory (cf. gnc::gfold)
+///
+/// The reference is solved from the current state to the gui_pf and gui_vf
+/// targets, and re-solved every gui_gfold.period. The errors are nulled by a
+/// critically damped second order:
+///     acc = acc_ref + w**2*(p_ref-p0) + 2*w*(v_ref-v0), w = 1/tracking_tau
+/// and the thrust is kept within the throttle and tilt bounds of the
+/// reference, hence the control does not have to saturate the command. Falls
+/// back on the quadratic law (gui_descent) while there is no feasible
+/// reference.
+pub fn gui_gfold(spacecraft: &mut Spacecraft, tgo: Time) -> Vec3<Acceleration> {
+    let conf = &spacecraft.conf.s;
+    let gfold_conf = conf.gui_gfold.as_ref().unwrap();
+    let cur = &spacecraft.cur;
+    let sc_mass = conf.sc_dry_mass + cur.fuel_mass;
+
+    let descent = gfold_descent(spacecraft);
+    let body_acc = descent.gravity;
+    spacecraft.gfold.update(gfold_conf, &descent, cur.t);
+
+    let reference = match &spacecraft.gfold.reference {
+        None => return gui_descent(spacecraft, tgo),
+        Some(reference) => reference,
+    };
+
+    // thrust
+
+    let (p_ref, v_ref, acc_ref) = reference.sample(cur.t);
+    let w: Frequency = 1.0/gfold_conf.tracking_tau;
+
+    let acc = Vec3 {
+        x: acc_ref.x + w*w*(p_ref.x-cur.pos.x) + 2.0*w*(v_ref.x-cur.vel.x),
+        y: acc_ref.y + w*w*(p_ref.y-cur.pos.y) + 2.0*w*(v_ref.y-cur.vel.y),
+        z: acc_ref.z + w*w*(p_ref.z-cur.pos.z) + 2.0*w*(v_ref.z-cur.vel.z),
+    };
+
+    let dir = acc.value().unit();
+    let tilt = gfold_conf.tilt_max.get::<radian>();
+    let dir = if dir.y < tilt.cos() {
+        let horizontal = Vec3 {x: dir.x, y: 0.0, z: dir.z}.unit();
+        horizontal*tilt.sin() + Vec3 {x: 0.0, y: tilt.cos(), z: 0.0}
+    } else {
+        dir
+    };
+    let norm = saturate(
+        acc.norm(),
+        gfold_conf.throttle_min*cur.thrust/sc_mass,
+        gfold_conf.throttle_max*cur.thrust/sc_mass,
+    );
+    let acc = dir*norm;
+
+    // kinematic, gui() compensates the body acceleration
+
+    acc + body_acc
+}
+
+/// Terminal vertical descent (Apollo P66), at a constant rate
+///
+/// x: First order, null the horizontal velocity:
+///     acc = (vf-v0)/tau
+/// y: First order, hold the descent rate vf:
+///     acc = (vf-v0)/tau
+/// z: First order (same as x)
+/// It does not depend on tgo, and lasts until touchdown (cf. gnc::sequencer).
+pub fn gui_vertical_descent(spacecraft: &Spacecraft) -> Vec3<Acceleration> {
+    let conf = &spacecraft.conf.s;
+    let tau = conf.gui_vertical_tau;
+
+    let vf = Vec3 {x: conf.gui_vf_x, y: conf.gui_vf_y, z: conf.gui_vf_z};
+    let v0 = spacecraft.cur.vel;
+
+    Vec3 {
+        x: (vf.x-v0.x)/tau,
+        y: (vf.y-v0.y)/tau,
+        z: (vf.z-v0.z)/tau,
     }
 }
 
@@ -154,8 +368,165 @@ pub fn gui_ascent_hover(spacecraft: &Spacecraft, tgo: Time) -> Vec2<Acceleration
 mod tests {
     use super::*;
 
+    use uom::si::length::meter;
+    use uom::si::mass::kilogram;
+    use uom::si::time::second;
+    use uom::si::velocity::meter_per_second;
+
+    use crate::assert_approx_eq;
+    use crate::conf::{SubCommand, Scenario, Conf};
+    use crate::utils::math::Quaternion;
+    use crate::utils::space::G0;
+
     #[test]
     fn test_gui_1() {
-        // TODO
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+
+        let mut sc = Spacecraft::new(conf);
+        let tgo = Time::new::<second>(100.0);
+
+        // in the plane: no cross-range command
+
+        let acc = gui_descent(&sc, tgo);
+        assert_eq!(acc.z, Acceleration::new::<meter_per_second_squared>(0.0));
+
+        // cross-range error and velocity: command toward the target
+
+        sc.cur.pos.z = Length::new::<meter>(100.0);
+        let acc = gui_descent(&sc, tgo);
+        assert_eq!(acc.z, 12.0/squared!(tgo)*Length::new::<meter>(-100.0));
+
+        sc.cur.pos.z = Length::new::<meter>(0.0);
+        sc.cur.vel.z = Velocity::new::<meter_per_second>(10.0);
+        let acc = gui_descent(&sc, tgo);
+        assert_eq!(acc.z, -6.0/tgo*Velocity::new::<meter_per_second>(10.0));
+
+        // body compensation is only vertical
+        gui(&mut sc, tgo);
+        assert_eq!(sc.cur.gui.z, acc.z);
+    }
+
+    #[test]
+    fn test_gui_2_vertical_descent() {
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.gui_spacecraft = GuiSpacecraft::GuiVerticalDescent;
+        scenario.gui_vf_y = Velocity::new::<meter_per_second>(-1.0);
+        scenario.gui_vertical_tau = Time::new::<second>(5.0);
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+
+        let mut sc = Spacecraft::new(conf);
+        sc.cur.vel = Vec3::from_value(Vec3 {x: 2.0, y: -3.0, z: -0.5});
+        sc.cur.vel_inertial = sc.cur.vel;
+
+        // null the horizontal velocity, slow down to the descent rate
+        let acc = gui_vertical_descent(&sc);
+        assert_eq!(acc.x, Acceleration::new::<meter_per_second_squared>(-0.4));
+        assert_eq!(acc.y, Acceleration::new::<meter_per_second_squared>(0.4));
+        assert_eq!(acc.z, Acceleration::new::<meter_per_second_squared>(0.1));
+
+        // whatever tgo, plus the gravity compensation
+        gui(&mut sc, Time::new::<second>(0.0));
+        assert!(sc.cur.gui.y > acc.y);
+        assert_eq!(sc.cur.gui.x, acc.x);
+    }
+
+    #[test]
+    fn test_gui_3_ascent_peg() {
+        let f = std::fs::File::open("conf/Apollo-ascent.yaml").unwrap();
+        let scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        let tf = scenario.gui_pitchover;
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+
+        let mut sc = Spacecraft::new(conf);
+        let acc_thrust: Acceleration = sc.cur.thrust/(sc.conf.s.sc_dry_mass + sc.cur.fuel_mass);
+
+        // lift-off: vertical, full thrust, the PEG has converged
+        gui(&mut sc, Time::new::<second>(500.0));
+        assert!(sc.cur.gui.x.abs() < Acceleration::new::<meter_per_second_squared>(1e-9));
+        assert!((sc.cur.gui.y - acc_thrust).abs() < Acceleration::new::<meter_per_second_squared>(1e-9));
+        let tgo = sc.peg.tgo.unwrap();
+        assert!((Time::new::<second>(300.0) < tgo) && (tgo < Time::new::<second>(500.0)));
+
+        // pitching over, toward downrange
+        sc.cur.t = tf/2.0;
+        gui(&mut sc, tgo);
+        let (_heading, pitch) = heading_pitch(sc.cur.gui.value().unit());
+        assert!((Angle::new::<degree>(45.0) < pitch) && (pitch < Angle::new::<degree>(90.0)));
+        assert!(sc.cur.gui.x > Acceleration::new::<meter_per_second_squared>(0.0));
+
+        // then the linear tangent steering, still at full thrust
+        sc.cur.t = tf*2.0;
+        gui(&mut sc, tgo);
+        let (_heading, pitch_peg) = heading_pitch(sc.cur.gui.value().unit());
+        assert!(pitch_peg < pitch);
+        assert!((sc.cur.gui.norm() - acc_thrust).abs() < Acceleration::new::<meter_per_second_squared>(1e-9));
+    }
+
+    #[test]
+    fn test_gui_4_gfold() {
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.gui_spacecraft = GuiSpacecraft::GuiGfold;
+        scenario.gui_pf_x = Length::new::<meter>(0.0);
+        scenario.gui_pf_y = Length::new::<meter>(30.0);
+        scenario.gui_pf_z = Length::new::<meter>(0.0);
+        scenario.gui_vf_x = Velocity::new::<meter_per_second>(0.0);
+        scenario.gui_vf_y = Velocity::new::<meter_per_second>(-1.0);
+        scenario.gui_vf_z = Velocity::new::<meter_per_second>(0.0);
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+
+        // point mass, from the high gate, upright and hovering, with the fuel left after the braking
+
+        let mut sc = Spacecraft::new(conf);
+        let gfold_conf = sc.conf.s.gui_gfold.unwrap();
+        let dt = Time::new::<second>(0.2);
+        sc.cur.fuel_mass = Mass::new::<kilogram>(1500.0);
+        sc.cur.pos = Vec3::from_value(Vec3 {x: -1500.0, y: 800.0, z: 100.0});
+        sc.cur.vel = Vec3::from_value(Vec3 {x: 40.0, y: -15.0, z: 0.0});
+        sc.cur.vel_inertial = sc.cur.vel;
+        sc.cur.att = Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(90.0));
+        sc.cur.acc_thrust = -body_acc_y(&sc);
+
+        gui(&mut sc, Time::new::<second>(100.0));
+        let tf = sc.gfold.tgo(sc.cur.t).unwrap();
+        assert!((gfold_conf.tf_min <= tf) && (tf <= gfold_conf.tf_max));
+
+        while sc.cur.t < tf {
+            let tgo = tf - sc.cur.t;
+            gui(&mut sc, tgo);
+
+            // the commanded thrust is within the bounds, without saturation by control
+            let sc_mass = sc.conf.s.sc_dry_mass + sc.cur.fuel_mass;
+            let acc_thrust = sc.cur.gui.norm();
+            assert!(acc_thrust >= gfold_conf.throttle_min*sc.cur.thrust/sc_mass*0.999);
+            assert!(acc_thrust <= gfold_conf.throttle_max*sc.cur.thrust/sc_mass*1.001);
+            assert!(sc.cur.gui.y >= acc_thrust*gfold_conf.tilt_max.cos()*0.999);
+
+            let dir = sc.cur.gui.value().unit();
+            let axis = Vec3 {x: 1.0, y: 0.0, z: 0.0}.cross(&dir);
+            sc.cur.att = Quaternion::from_axis_angle(axis, Angle::new::<radian>(dir.x.acos()));
+            sc.cur.acc_thrust = acc_thrust;
+
+            let acc = sc.cur.gui + Vec3 {
+                x: Acceleration::new::<meter_per_second_squared>(0.0),
+                y: body_acc_y(&sc),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
+            };
+            sc.cur.pos += sc.cur.vel*dt;
+            sc.cur.vel += acc*dt;
+            sc.cur.vel_inertial = sc.cur.vel;
+            sc.cur.fuel_mass -= acc_thrust*sc_mass/(sc.cur.isp*(*G0))*dt;
+            sc.cur.t += dt;
+        }
+
+        // at the low gate, upright
+        assert_approx_eq!(sc.cur.pos.x.get::<meter>(), 0.0, 5.0);
+        assert_approx_eq!(sc.cur.pos.y.get::<meter>(), 30.0, 5.0);
+        assert_approx_eq!(sc.cur.pos.z.get::<meter>(), 0.0, 5.0);
+        assert!((sc.cur.vel - Vec3::from_value(Vec3 {x: 0.0, y: -1.0, z: 0.0})).norm() < Velocity::new::<meter_per_second>(1.0));
+        assert!(sc.cur.gui.value().unit().y > 0.99);
     }
 }
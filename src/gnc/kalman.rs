@@ -0,0 +1,390 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::angle::radian;
+use uom::si::length::meter;
+use uom::si::ratio::ratio;
+use uom::si::time::second;
+use uom::si::velocity::meter_per_second;
+
+use crate::utils::math::{Vec3, Quaternion};
+use crate::utils::sensors::{RadarAltimeterConf, boresight_tilt};
+
+
+/// Error state of the navigation filter: position (m), velocity (m/s) and
+/// attitude (rad, small rotation), all in the local frame
+pub const NAV_STATES: usize = 9;
+
+/// Covariance of the navigation error state
+pub type Covariance = [[f64; NAV_STATES]; NAV_STATES];
+
+const POS: usize = 0;
+const VEL: usize = 3;
+const ATT: usize = 6;
+
+
+/// Tuning of the navigation Kalman filter
+///
+/// Values are 1-sigma. The filter does not know the terrain: the altimeter is
+/// assumed to measure the altitude, the terrain relief is part of its noise.
+/// No sensor sees the horizontal position, it stays dead-reckoned.
+///
+/// Yaml serialized equivalent:
+///
+/// nav_kalman:
+///     init_pos: 100.0
+///     init_vel: 1.0
+///     init_att: 0.0017                # 0.1 deg
+///     acc_noise: 1.0e-3               # m/s**2/sqrt(Hz)
+///     gyro_noise: 1.0e-5              # rad/s/sqrt(Hz)
+///     altimeter_noise: 5.0
+///     altimeter_noise_ratio: 0.02
+///     velocimeter_noise: 0.5
+///     velocimeter_noise_ratio: 0.02
+///     gate: 5.0                       # reject the measurements beyond 5 sigma
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KalmanConf {
+    pub init_pos: Length,
+    pub init_vel: Velocity,
+    pub init_att: Angle,
+    pub acc_noise: f64,                 // process noise, m/s**2/sqrt(Hz)
+    pub gyro_noise: f64,                // process noise, rad/s/sqrt(Hz)
+    pub altimeter_noise: Length,
+    pub altimeter_noise_ratio: Ratio,   // proportional to the range
+    pub velocimeter_noise: Velocity,
+    pub velocimeter_noise_ratio: Ratio, // proportional to the speed
+    pub gate: Ratio,                    // innovation gate, in sigmas
+}
+
+
+/// Correction of the navigation state, from a measurement update
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Correction {
+    pub pos: Vec3<f64>,
+    pub vel: Vec3<f64>,
+    pub att: Vec3<f64>,                 // small rotation, local frame
+}
+
+
+impl Correction {
+    pub fn zero() -> Self {
+        Correction {
+            pos: Vec3::zero(),
+            vel: Vec3::zero(),
+            att: Vec3::zero(),
+        }
+    }
+
+    /// Apply the correction to a navigation state
+    pub fn apply(&self, pos: &mut Vec3<Length>, vel: &mut Vec3<Velocity>, att: &mut Quaternion) {
+        *pos = Vec3::from_value(pos.value() + self.pos);
+        *vel = Vec3::from_value(vel.value() + self.vel);
+        let dq = Quaternion::from_axis_angle(self.att, Angle::new::<radian>(self.att.norm()));
+        *att = (dq * *att).normalize();
+    }
+}
+
+
+/// Initial covariance
+pub fn init(conf: &KalmanConf) -> Covariance {
+    let mut p = [[0.0; NAV_STATES]; NAV_STATES];
+    for i in 0..3 {
+        p[POS+i][POS+i] = conf.init_pos.get::<meter>().powi(2);
+        p[VEL+i][VEL+i] = conf.init_vel.get::<meter_per_second>().powi(2);
+        p[ATT+i][ATT+i] = conf.init_att.get::<radian>().powi(2);
+    }
+    p
+}
+
+
+/// Propagate the covariance over dt
+///
+/// The accelerometers measure in the local frame, hence the attitude error
+/// does not leak into the velocity: pos' = vel, vel' = noise, att' = noise.
+pub fn predict(conf: &KalmanConf, p: &Covariance, dt: Time) -> Covariance {
+    let dt = dt.get::<second>();
+
+    // P = F*P*F' with F = I + [0 dt*I 0; 0 0 0; 0 0 0]
+    let mut fp = *p;
+    for i in 0..3 {
+        for j in 0..NAV_STATES {
+            fp[POS+i][j] += dt*p[VEL+i][j];
+        }
+    }
+    let mut fpf = fp;
+    for i in 0..NAV_STATES {
+        for j in 0..3 {
+            fpf[i][POS+j] += dt*fp[i][VEL+j];
+        }
+    }
+
+    for i in 0..3 {
+        fpf[VEL+i][VEL+i] += conf.acc_noise.powi(2)*dt;
+        fpf[ATT+i][ATT+i] += conf.gyro_noise.powi(2)*dt;
+    }
+
+    fpf
+}
+
+
+/// Scalar measurement update (Joseph form)
+///
+/// Returns false if the measurement is rejected by the innovation gate.
+fn update_scalar(
+    conf: &KalmanConf,
+    p: &mut Covariance,
+    correction: &mut Correction,
+    h: &[f64; NAV_STATES],
+    innovation: f64,
+    r: f64,
+) -> bool {
+    // the innovation is computed before the previous corrections are applied
+    let mut x = [
+        correction.pos.x, correction.pos.y, correction.pos.z,
+        correction.vel.x, correction.vel.y, correction.vel.z,
+        correction.att.x, correction.att.y, correction.att.z,
+    ];
+    let innovation = innovation - (0..NAV_STATES).map(|i| h[i]*x[i]).sum::<f64>();
+
+    let ph: Vec<f64> = (0..NAV_STATES).map(|i| (0..NAV_STATES).map(|j| p[i][j]*h[j]).sum()).collect();
+    let s = (0..NAV_STATES).map(|i| h[i]*ph[i]).sum::<f64>() + r;
+
+    if innovation.powi(2) > conf.gate.get::<ratio>().powi(2)*s {
+        return false;
+    }
+
+    // the horizontal position is only considered (Schmidt-Kalman): it is not
+    // observable, the velocity corrections would make it jump by meters
+    let mut k: Vec<f64> = ph.iter().map(|v| v/s).collect();
+    k[POS] = 0.0;
+    k[POS+2] = 0.0;
+
+    for i in 0..NAV_STATES {
+        x[i] += k[i]*innovation;
+    }
+
+    // P = (I-KH)*P*(I-KH)' + K*R*K'
+    let mut ikh = [[0.0; NAV_STATES]; NAV_STATES];
+    for i in 0..NAV_STATES {
+        for j in 0..NAV_STATES {
+            let identity = if i == j { 1.0 } else { 0.0 };
+            ikh[i][j] = identity - k[i]*h[j];
+        }
+    }
+    let a = mul(&ikh, p);
+    let mut next = mul_transposed(&a, &ikh);
+    for i in 0..NAV_STATES {
+        for j in 0..NAV_STATES {
+            next[i][j] += k[i]*r*k[j];
+        }
+    }
+    *p = next;
+
+    correction.pos = Vec3 {x: x[POS], y: x[POS+1], z: x[POS+2]};
+    correction.vel = Vec3 {x: x[VEL], y: x[VEL+1], z: x[VEL+2]};
+    correction.att = Vec3 {x: x[ATT], y: x[ATT+1], z: x[ATT+2]};
+
+    true
+}
+
+
+/// Radar altimeter update
+///
+/// Same beam geometry as the simulated radar altimeter (cf. utils::sensors),
+/// but the altitude is taken as the height above the terrain.
+pub fn update_altimeter(
+    conf: &KalmanConf,
+    radar: Option<&RadarAltimeterConf>,
+    p: &mut Covariance,
+    correction: &mut Correction,
+    pos: Vec3<Length>,
+    att: Quaternion,
+    range: Length,
+) -> bool {
+    // slant factor, beyond the beam width
+    let slant = match radar {
+        None => 1.0,
+        Some(radar) => {
+            let tilt = boresight_tilt(radar.boresight, att);
+            1.0/(tilt - radar.beam_width.get::<radian>()).max(0.0).cos()
+        },
+    };
+
+    let mut h = [0.0; NAV_STATES];
+    h[POS+1] = slant;
+
+    let range = range.get::<meter>();
+    let sigma = conf.altimeter_noise.get::<meter>() + conf.altimeter_noise_ratio.get::<ratio>()*range;
+    let innovation = range - pos.y.get::<meter>()*slant;
+
+    update_scalar(conf, p, correction, &h, innovation, sigma.powi(2))
+}
+
+
+/// Doppler velocimeter update, one scalar update per body axis
+///
+/// v_body = R'*(I - [datt x])*v, hence H = [0, b_i, b_i x v] for the body axis b_i
+/// (local frame).
+///
+/// Returns the number of accepted axes.
+pub fn update_velocimeter(
+    conf: &KalmanConf,
+    p: &mut Covariance,
+    correction: &mut Correction,
+    vel: Vec3<Velocity>,
+    att: Quaternion,
+    vel_body: Vec3<Velocity>,
+) -> usize {
+    let v = vel.value();
+    let speed = vel_body.norm().get::<meter_per_second>();
+    let sigma = conf.velocimeter_noise.get::<meter_per_second>() + conf.velocimeter_noise_ratio.get::<ratio>()*speed;
+
+    let meas = vel_body.value();
+    let axes = [
+        (Vec3 {x: 1.0, y: 0.0, z: 0.0}, meas.x),
+        (Vec3 {x: 0.0, y: 1.0, z: 0.0}, meas.y),
+        (Vec3 {x: 0.0, y: 0.0, z: 1.0}, meas.z),
+    ];
+
+    let mut accepted = 0;
+    for (axis, m) in axes.iter() {
+        let b = att.rotate(*axis);
+        let bv = b.cross(&v);
+
+        let mut h = [0.0; NAV_STATES];
+        h[VEL] = b.x;
+        h[VEL+1] = b.y;
+        h[VEL+2] = b.z;
+        h[ATT] = bv.x;
+        h[ATT+1] = bv.y;
+        h[ATT+2] = bv.z;
+
+        let innovation = m - b.dot(&v);
+        if update_scalar(conf, p, correction, &h, innovation, sigma.powi(2)) {
+            accepted += 1;
+        }
+    }
+
+    accepted
+}
+
+
+fn mul(a: &Covariance, b: &Covariance) -> Covariance {
+    let mut c = [[0.0; NAV_STATES]; NAV_STATES];
+    for i in 0..NAV_STATES {
+        for j in 0..NAV_STATES {
+            c[i][j] = (0..NAV_STATES).map(|k| a[i][k]*b[k][j]).sum();
+        }
+    }
+    c
+}
+
+/// a*b'
+fn mul_transposed(a: &Covariance, b: &Covariance) -> Covariance {
+    let mut c = [[0.0; NAV_STATES]; NAV_STATES];
+    for i in 0..NAV_STATES {
+        for j in 0..NAV_STATES {
+            c[i][j] = (0..NAV_STATES).map(|k| a[i][k]*b[j][k]).sum();
+        }
+    }
+    c
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::f64::consts::FRAC_PI_2 as PI_2;
+
+    use crate::assert_approx_eq;
+
+    fn conf() -> KalmanConf {
+        KalmanConf {
+            init_pos: Length::new::<meter>(100.0),
+            init_vel: Velocity::new::<meter_per_second>(1.0),
+            init_att: Angle::new::<radian>(0.0017),
+            acc_noise: 1.0e-3,
+            gyro_noise: 1.0e-5,
+            altimeter_noise: Length::new::<meter>(1.0),
+            altimeter_noise_ratio: Ratio::new::<ratio>(0.0),
+            velocimeter_noise: Velocity::new::<meter_per_second>(0.1),
+            velocimeter_noise_ratio: Ratio::new::<ratio>(0.0),
+            gate: Ratio::new::<ratio>(5.0),
+        }
+    }
+
+    fn vec(x: f64, y: f64, z: f64) -> Vec3<f64> {
+        Vec3 {x: x, y: y, z: z}
+    }
+
+    #[test]
+    fn test_kalman_1_predict() {
+        let conf = conf();
+        let p0 = init(&conf);
+        let p = predict(&conf, &p0, Time::new::<second>(10.0));
+
+        // pos = pos + vel*dt: var(pos) += dt**2*var(vel), cov(pos, vel) = dt*var(vel)
+        assert_approx_eq!(p[POS+1][POS+1], 100.0*100.0 + 100.0*1.0, 1e-3);
+        assert_approx_eq!(p[POS+1][VEL+1], 10.0, 1e-3);
+        assert_approx_eq!(p[VEL+1][POS+1], 10.0, 1e-3);
+        assert_approx_eq!(p[VEL+1][VEL+1], 1.0 + 1.0e-6*10.0, 1e-9);
+        assert_approx_eq!(p[ATT][ATT], 0.0017f64.powi(2) + 1.0e-10*10.0, 1e-12);
+        assert_eq!(p[POS][VEL+1], 0.0);
+    }
+
+    #[test]
+    fn test_kalman_2_altimeter() {
+        let conf = conf();
+        let mut p = init(&conf);
+        let mut correction = Correction::zero();
+        let pos = Vec3::from_value(vec(0.0, 1000.0, 0.0));
+        let att = Quaternion::from_euler(Angle::new::<radian>(0.0), Angle::new::<radian>(PI_2), Angle::new::<radian>(0.0));
+
+        // the altitude is corrected almost entirely (1 m sigma against 100 m), not the horizontal position
+        assert!(update_altimeter(&conf, None, &mut p, &mut correction, pos, att, Length::new::<meter>(1050.0)));
+        assert_approx_eq!(correction.pos.y, 50.0*1.0e4/(1.0e4 + 1.0), 1e-6);
+        assert_eq!(correction.pos.x, 0.0);
+        assert_eq!(correction.pos.z, 0.0);
+        assert!(p[POS+1][POS+1] < 1.0);
+        assert_eq!(p[POS][POS], 100.0*100.0);
+
+        // an outlier is rejected by the gate, the state is unchanged
+        let p_prev = p;
+        assert!(!update_altimeter(&conf, None, &mut p, &mut correction, pos, att, Length::new::<meter>(1100.0)));
+        assert_eq!(p, p_prev);
+
+        // the correction brings the state on the measurement
+        let mut vel = Vec3::from_value(Vec3::zero());
+        let mut pos = pos;
+        let mut att = att;
+        correction.apply(&mut pos, &mut vel, &mut att);
+        assert_approx_eq!(pos.y.get::<meter>(), 1050.0, 0.01);
+    }
+
+    #[test]
+    fn test_kalman_3_velocimeter() {
+        let conf = conf();
+        let mut p = init(&conf);
+        let mut correction = Correction::zero();
+
+        // upright, the nav velocity is 0.5 m/s too fast downward
+        let att = Quaternion::from_euler(Angle::new::<radian>(0.0), Angle::new::<radian>(PI_2), Angle::new::<radian>(0.0));
+        let vel = Vec3::from_value(vec(10.0, -20.5, 0.0));
+        let vel_body = Vec3::from_value(att.rotate_inv(vec(10.0, -20.0, 0.0)));
+
+        assert_eq!(update_velocimeter(&conf, &mut p, &mut correction, vel, att, vel_body), 3);
+        assert_approx_eq!(correction.vel.y, 0.5, 0.01);
+        assert_approx_eq!(correction.vel.x, 0.0, 0.01);
+        // down to the sensor noise, and a bit of the attitude uncertainty
+        assert!(p[VEL+1][VEL+1] < 0.15*0.15);
+        assert!(p[VEL][VEL] < 0.15*0.15);
+
+        // the velocity does not move the horizontal position
+        assert_eq!(correction.pos.x, 0.0);
+        assert_eq!(correction.pos.z, 0.0);
+    }
+}
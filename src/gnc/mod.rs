@@ -1,4 +1,11 @@
 pub mod common;
 pub mod control;
+pub mod divert;
+pub mod engine;
+pub mod fdir;
+pub mod gfold;
 pub mod guidance;
+pub mod kalman;
 pub mod navigation;
+pub mod peg;
+pub mod sequencer;
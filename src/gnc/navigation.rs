@@ -1,50 +1,205 @@
-use std::f64::consts::PI;
+use serde::{Serialize, Deserialize};
 use uom::si::f64::*;
-use uom::si::angle::degree;
-use uom::si::velocity::meter_per_second;
+use uom::si::acceleration::meter_per_second_squared;
+use uom::si::angle::{degree, radian};
+use uom::si::time::second;
 
-use crate::{mul, norm, sqrt, squared, modulo};
-use crate::adapters::common::SensorsValues;
+use crate::modulo;
+use crate::adapters::common::{StateVector, SensorsValues};
+use crate::conf::NavSpacecraft;
 use crate::gnc::common::Spacecraft;
-use crate::utils::math::Vec2;
+use crate::gnc::engine;
+use crate::gnc::fdir::Sensor;
+use crate::gnc::kalman::{Correction, predict, update_altimeter, update_velocimeter};
+use crate::utils::aero::aero;
+use crate::utils::frames::{Lvlh, lvlh_pos_rate, transport_rate, inertial_velocity};
+use crate::utils::math::{Vec3, Quaternion};
+use crate::utils::space::mass_flow;
 
 
+/// Initial navigation errors, added to the state vector given by the adapter
+///
+/// Yaml serialized equivalent:
+///
+/// nav_init:
+///     pos_error: {x: 50.0, y: -20.0, z: 10.0}
+///     vel_error: {x: 0.5, y: -0.2, z: 0.0}
+///     att_error: {x: 0.0, y: 0.0, z: 0.0017}    # small rotation, local frame
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NavInitConf {
+    pub pos_error: Vec3<Length>,
+    pub vel_error: Vec3<Velocity>,
+    pub att_error: Vec3<Angle>,
+}
+
+
+/// Navigation initialisation
+///
+/// From the state vector given by the adapter (true state for the simulator,
+/// vessel state for KSP), plus the configured initial errors.
+pub fn nav_init(spacecraft: &mut Spacecraft, state: &StateVector) {
+    let conf = &spacecraft.conf.s;
+
+    let mut pos = state.pos;
+    let mut vel = state.vel;
+    let mut att = state.att;
+
+    if let Some(errors) = conf.nav_init {
+        pos += errors.pos_error;
+        vel += errors.vel_error;
+        let datt = errors.att_error.value();
+        if datt.norm() > 0.0 {
+            att = (Quaternion::from_axis_angle(datt, Angle::new::<radian>(datt.norm())) * att).normalize();
+        }
+    }
+
+    spacecraft.cur.pos = pos;
+    spacecraft.cur.vel = vel;
+    spacecraft.cur.vel_inertial = inertial_velocity(&conf.body, &Lvlh {pos: pos, vel: vel});
+    spacecraft.cur.att = att;
+
+    let (heading, pitch, roll) = att.to_euler();
+    spacecraft.cur.heading = heading;
+    spacecraft.cur.ang_pos = modulo!(pitch, Angle::new::<degree>(360.0));
+    spacecraft.cur.roll = roll;
+}
+
+
+/// Navigation function
+///
+/// Dead reckoning: integrate the accelerometers and the gyroscopes. With
+/// NavKalman, an error-state Kalman filter also corrects the position,
+/// velocity and attitude with the radar altimeter and the Doppler velocimeter.
+/// With nav_engine, the mass, thrust and Isp are estimated from the measured
+/// thrust acceleration (cf. gnc::engine).
+///
+/// The sensors values are checked first, the invalid measurements are not
+/// used (cf. gnc::fdir).
 pub fn nav(spacecraft: &mut Spacecraft, sensors_vals: &SensorsValues) {
-    let conf = spacecraft.conf.s;
+    let conf = &spacecraft.conf.s;
+    let t = spacecraft.cur.t + sensors_vals.dt_step;
+    let sensors_vals = &spacecraft.fdir.check(t, spacecraft.conf.dt_step, sensors_vals);
+    let acc_meas = sensors_vals.spacecraft_acc.filter(|_| spacecraft.fdir.valid(Sensor::Accelerometer));
     let dt = sensors_vals.dt_step;
     let sc_mass = conf.sc_dry_mass + spacecraft.cur.fuel_mass;
 
     spacecraft.cur.t += dt;
     spacecraft.cur.dt = dt;
 
-    spacecraft.cur.fuel_mass -= conf.sc_nominal_mass_flow*spacecraft.cur.eng_throttle*dt;
+    spacecraft.cur.fuel_mass -= mass_flow(conf, spacecraft.cur.thrust, spacecraft.cur.isp)*spacecraft.cur.eng_throttle*dt;
 
-    spacecraft.cur.acc_thrust = (spacecraft.cur.eng_throttle*conf.sc_nominal_thrust)/sc_mass;
-
-    // dynamic pressure q: Pa = Kg/(m*s**2)
-    // dynamic pressure n: N = Kg/(m*s**2) * m**2 = Kg*m/(s**2)
-    let vel: Velocity = Velocity::new::<meter_per_second>(norm!(spacecraft.cur.vel));
-    let dp_q: Pressure = 0.5 * conf.body.atmosphere_density(spacecraft.cur.pos.y) * squared!(vel);
-    let dp_n: Force = dp_q * (PI*squared!(conf.sc_width/2.0)) * conf.sc_cd;
-    spacecraft.cur.acc_atm = -dp_n/sc_mass;
+    // the atmosphere rotates with the body: the airspeed is the surface-relative velocity
+    let atm = conf.body.atmosphere(spacecraft.cur.pos.y);
+    let vel_air = spacecraft.cur.att.rotate_inv(spacecraft.cur.vel.value());
+    let aero = aero(conf, &atm, Vec3::from_value(vel_air));
+    spacecraft.cur.acc_atm = -(aero.drag + aero.lift).norm()/sc_mass;
 
     spacecraft.cur.acc_gravity = -conf.body.gravity(spacecraft.cur.pos.y);
-    spacecraft.cur.acc_centrifugal = conf.body.centrifugal(spacecraft.cur.vel.x, spacecraft.cur.pos.y);
+    spacecraft.cur.acc_centrifugal = conf.body.centrifugal(spacecraft.cur.vel_inertial.horizontal_norm(), spacecraft.cur.pos.y);
+
+    let acc_gravity = Vec3 {x: 0.0, y: (spacecraft.cur.acc_gravity + spacecraft.cur.acc_centrifugal).value, z: 0.0};
+    let acc_aero = spacecraft.cur.att.rotate((aero.drag + aero.lift).value()) * (1.0/sc_mass.value);
+
+    // engine estimation: the thrust acceleration is what remains of the measured
+    // acceleration without gravity (and centrifugal) and aero
+
+    if let Some(estimator) = conf.nav_engine.as_ref() {
+        spacecraft.cur.eng_cov = engine::predict(
+            conf, &spacecraft.cur.eng_cov, spacecraft.cur.thrust, spacecraft.cur.isp, spacecraft.cur.eng_throttle, dt,
+        );
+
+        if let Some(acc_meas) = acc_meas {
+            let acc_thrust = (acc_meas.value() - acc_gravity - acc_aero).norm();
+
+            engine::update(
+                conf, estimator, &mut spacecraft.cur.eng_cov,
+                &mut spacecraft.cur.fuel_mass, &mut spacecraft.cur.thrust, &mut spacecraft.cur.isp,
+                spacecraft.cur.eng_throttle, Acceleration::new::<meter_per_second_squared>(acc_thrust),
+            );
+        }
+    }
+
+    spacecraft.cur.acc_thrust = (spacecraft.cur.eng_throttle*spacecraft.cur.thrust)/sc_mass;
+
+    // pos is a ground distance (and altitude), vel is relative to the surface,
+    // cf. utils::frames. Without a valid accelerometer sample, the thrust
+    // (along the body x axis) is the estimated one
+
+    spacecraft.cur.acc = match acc_meas {
+        Some(acc_meas) => acc_meas,
+        None => {
+            let thrust_axis = spacecraft.cur.att.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0});
+            Vec3::from_value(thrust_axis*spacecraft.cur.acc_thrust.value + acc_gravity + acc_aero)
+        },
+    };
+    spacecraft.cur.vel += spacecraft.cur.acc*dt;
 
-    spacecraft.cur.acc = sensors_vals.spacecraft_acc;
-    spacecraft.cur.vel += mul!(spacecraft.cur.acc, dt);
-    spacecraft.cur.pos += mul!(spacecraft.cur.vel, dt);
+    let lvlh = Lvlh {pos: spacecraft.cur.pos, vel: spacecraft.cur.vel};
+    spacecraft.cur.pos += lvlh_pos_rate(&conf.body, &lvlh)*dt;
 
     spacecraft.cur.dv += spacecraft.cur.acc_thrust*dt;
 
-    spacecraft.cur.ang_acc = sensors_vals.spacecraft_ang_acc;
-    let dav: AngularVelocity = (spacecraft.cur.ang_acc*dt).into();
-    spacecraft.cur.ang_vel += dav;
-    let dap: Angle = (spacecraft.cur.ang_vel*dt).into();
-    spacecraft.cur.ang_pos += dap;
-    spacecraft.cur.ang_pos = modulo!(spacecraft.cur.ang_pos, Angle::new::<degree>(360.0));
+    // attitude: integrate the body angular rates (inertial, from the gyroscopes),
+    // then rotate by the increment relative to the local frame (which rotates
+    // with the transport rate). The last valid rates are kept for an invalid
+    // sample, the attitude is only propagated with the local frame once the
+    // gyroscopes are failed
 
-    // TODO cross check altitude - kalman filter?
+    let dt_s = dt.get::<second>();
+
+    let ang_vel_prev = spacecraft.cur.ang_vel;
+    if spacecraft.fdir.valid(Sensor::Gyro) {
+        spacecraft.cur.ang_vel = sensors_vals.spacecraft_ang_vel;
+    } else if spacecraft.fdir.failed(Sensor::Gyro) {
+        spacecraft.cur.ang_vel = Vec3::from_value(Vec3::zero());
+    }
+    spacecraft.cur.ang_acc = Vec3::from_value(
+        (spacecraft.cur.ang_vel.value() - ang_vel_prev.value())*(1.0/dt_s)
+    );
+
+    let lvlh_rate = spacecraft.cur.att.rotate_inv(transport_rate(&conf.body, &lvlh).value());
+    let dap: Vec3<f64> = (spacecraft.cur.ang_vel.value() - lvlh_rate)*dt_s;
+    let dq = Quaternion::from_axis_angle(dap, Angle::new::<radian>(dap.norm()));
+    spacecraft.cur.att = (spacecraft.cur.att * dq).normalize();
+
+    // sensor fusion
+
+    if conf.nav_spacecraft == NavSpacecraft::NavKalman {
+        let kalman = conf.nav_kalman.as_ref().unwrap();
+        let mut cov = predict(kalman, &spacecraft.cur.nav_cov, dt);
+        let mut correction = Correction::zero();
+
+        if let Some(range) = sensors_vals.spacecraft_altitude {
+            let radar = conf.sensors.radar_altimeter.as_ref();
+            let accepted = update_altimeter(kalman, radar, &mut cov, &mut correction, spacecraft.cur.pos, spacecraft.cur.att, range);
+            spacecraft.fdir.gate(t, Sensor::RadarAltimeter, accepted);
+        }
+        if let Some(vel_body) = sensors_vals.spacecraft_vel {
+            let accepted = update_velocimeter(kalman, &mut cov, &mut correction, spacecraft.cur.vel, spacecraft.cur.att, vel_body);
+            spacecraft.fdir.gate(t, Sensor::DopplerVelocimeter, accepted > 0);
+        }
+
+        correction.apply(&mut spacecraft.cur.pos, &mut spacecraft.cur.vel, &mut spacecraft.cur.att);
+        spacecraft.cur.nav_cov = cov;
+    }
+
+    spacecraft.cur.vel_inertial = inertial_velocity(&conf.body, &Lvlh {pos: spacecraft.cur.pos, vel: spacecraft.cur.vel});
+
+    let (mut heading, pitch, roll) = spacecraft.cur.att.to_euler();
+
+    if let Some(sensor_heading) = sensors_vals.spacecraft_heading {
+        heading = sensor_heading;
+        spacecraft.cur.att = Quaternion::from_euler(heading, pitch, roll);
+    }
+
+    spacecraft.cur.heading = heading;
+    spacecraft.cur.ang_pos = modulo!(pitch, Angle::new::<degree>(360.0));
+    spacecraft.cur.roll = roll;
+
+    spacecraft.cur.contact = sensors_vals.spacecraft_contact;
 
     // save everything
     spacecraft.all.push(spacecraft.cur);
@@ -60,8 +215,14 @@ mod tests {
     use uom::si::angular_acceleration::degree_per_second_squared;
     use uom::si::length::meter;
     use uom::si::time::second;
+    use uom::si::velocity::meter_per_second;
+
+    use uom::si::angular_velocity::{degree_per_second, radian_per_second};
+    use uom::si::ratio::ratio;
 
-    use crate::utils::math::Vec2;
+    use std::f64::consts::PI;
+
+    use crate::assert_approx_eq;
     use crate::conf::{SubCommand, Scenario, Conf};
 
     #[test]
@@ -73,36 +234,211 @@ mod tests {
         let mut sc = Spacecraft::new(conf);
         sc.cur.pos.x = Length::new::<meter>(0.0);  // TODO conf file for tests
 
+        // ground distance, flying at altitude (cf. utils::frames)
+        let r = sc.conf.s.body.radius;
+        let k = |altitude: f64| (r/(r+Length::new::<meter>(altitude))).value;
+
         nav(&mut sc, &SensorsValues {
             dt_step: Time::new::<second>(1.0),
-            spacecraft_acc: Vec2 {
+            spacecraft_acc: Some(Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(1.0),
                 y: Acceleration::new::<meter_per_second_squared>(2.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
+            }),
+            spacecraft_ang_vel: Vec3 {
+                x: AngularVelocity::new::<degree_per_second>(0.0),
+                y: AngularVelocity::new::<degree_per_second>(0.0),
+                z: AngularVelocity::new::<degree_per_second>(0.0),
             },
-            spacecraft_ang_acc: AngularAcceleration::new::<degree_per_second_squared>(0.0),
             spacecraft_altitude: None,
+            spacecraft_vel: None,
+            spacecraft_heading: None,
+            spacecraft_contact: false,
         });
         assert_eq!(sc.cur.acc.x, Acceleration::new::<meter_per_second_squared>(1.0));
         assert_eq!(sc.cur.vel.x, Velocity::new::<meter_per_second>(1_673.0+1.0));
-        assert_eq!(sc.cur.pos.x, Length::new::<meter>(1_673.0+1.0));
+        assert_approx_eq!(sc.cur.pos.x, Length::new::<meter>((1_673.0+1.0)*k(15_000.0)), Length::new::<meter>(1e-9));
         assert_eq!(sc.cur.acc.y, Acceleration::new::<meter_per_second_squared>(2.0));
         assert_eq!(sc.cur.vel.y, Velocity::new::<meter_per_second>(2.0));
         assert_eq!(sc.cur.pos.y, Length::new::<meter>(15_000.0+2.0));
 
         nav(&mut sc, &SensorsValues {
             dt_step: Time::new::<second>(1.0),
-            spacecraft_acc: Vec2 {
+            spacecraft_acc: Some(Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(3.0),
                 y: Acceleration::new::<meter_per_second_squared>(4.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
+            }),
+            spacecraft_ang_vel: Vec3 {
+                x: AngularVelocity::new::<degree_per_second>(0.0),
+                y: AngularVelocity::new::<degree_per_second>(0.0),
+                z: AngularVelocity::new::<degree_per_second>(0.0),
             },
-            spacecraft_ang_acc: AngularAcceleration::new::<degree_per_second_squared>(0.0),
             spacecraft_altitude: None,
+            spacecraft_vel: None,
+            spacecraft_heading: None,
+            spacecraft_contact: false,
         });
         assert_eq!(sc.cur.acc.x, Acceleration::new::<meter_per_second_squared>(3.0));
         assert_eq!(sc.cur.vel.x, Velocity::new::<meter_per_second>(1_673.0 + 1.0 + 3.0));
-        assert_eq!(sc.cur.pos.x, Length::new::<meter>(1_673.0+1.0 + 1_673.0+1.0+3.0));
+        assert_approx_eq!(
+            sc.cur.pos.x,
+            Length::new::<meter>((1_673.0+1.0)*k(15_000.0) + (1_673.0+1.0+3.0)*k(15_002.0)),
+            Length::new::<meter>(1e-9)
+        );
         assert_eq!(sc.cur.acc.y, Acceleration::new::<meter_per_second_squared>(4.0));
         assert_eq!(sc.cur.vel.y, Velocity::new::<meter_per_second>(2.0 + 4.0));
         assert_eq!(sc.cur.pos.y, Length::new::<meter>(15_000.0 + 2.0 + 2.0+4.0));
+        assert_eq!(sc.cur.pos.z, Length::new::<meter>(0.0));
+    }
+
+    #[test]
+    fn test_nav_2_attitude() {
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.initial_sc_ang_pos = Angle::new::<degree>(90.0);
+        scenario.initial_sc_vel = Vec3::from_value(Vec3::zero());
+        scenario.body.rotation_period = Time::new::<second>(f64::INFINITY);
+        scenario.nav_fdir = None;   // the body rates jump
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+
+        let mut sc = Spacecraft::new(conf);
+
+        let mut sensors_vals = SensorsValues {
+            dt_step: Time::new::<second>(1.0),
+            spacecraft_acc: Some(Vec3 {
+                x: Acceleration::new::<meter_per_second_squared>(0.0),
+                y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
+            }),
+            spacecraft_ang_vel: Vec3 {
+                x: AngularVelocity::new::<degree_per_second>(0.0),
+                y: AngularVelocity::new::<degree_per_second>(0.0),
+                z: AngularVelocity::new::<degree_per_second>(-10.0),
+            },
+            spacecraft_altitude: None,
+            spacecraft_vel: None,
+            spacecraft_heading: None,
+            spacecraft_contact: false,
+        };
+
+        // pitch down, from vertical
+        nav(&mut sc, &sensors_vals);
+        assert_approx_eq!(sc.cur.ang_pos.get::<degree>(), 80.0, 1e-9);
+        assert_approx_eq!(sc.cur.heading.get::<degree>(), 0.0, 1e-9);
+
+        // then yaw (around body y) toward +z
+        sensors_vals.spacecraft_ang_vel.z = AngularVelocity::new::<degree_per_second>(0.0);
+        sensors_vals.spacecraft_ang_vel.y = AngularVelocity::new::<degree_per_second>(-20.0);
+        nav(&mut sc, &sensors_vals);
+        assert_approx_eq!(sc.cur.ang_acc.z.get::<degree_per_second_squared>(), 10.0, 1e-9);
+        assert!(sc.cur.heading > Angle::new::<degree>(1.0));
+
+        // heading sensor overrides the integrated heading
+        sensors_vals.spacecraft_ang_vel.y = AngularVelocity::new::<degree_per_second>(0.0);
+        sensors_vals.spacecraft_heading = Some(Angle::new::<degree>(5.0));
+        nav(&mut sc, &sensors_vals);
+        assert_approx_eq!(sc.cur.heading.get::<degree>(), 5.0, 1e-9);
+        let (heading, _pitch, _roll) = sc.cur.att.to_euler();
+        assert_approx_eq!(heading.get::<degree>(), 5.0, 1e-9);
+    }
+
+    #[test]
+    fn test_nav_3_transport_rate() {
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.initial_sc_ang_pos = Angle::new::<degree>(0.0);
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+
+        let mut sc = Spacecraft::new(conf);
+
+        // no rotation (inertial), but the local vertical rotates while flying over the body
+        nav(&mut sc, &SensorsValues {
+            dt_step: Time::new::<second>(1.0),
+            spacecraft_acc: Some(Vec3::from_value(Vec3::zero())),
+            spacecraft_ang_vel: Vec3::from_value(Vec3::zero()),
+            spacecraft_altitude: None,
+            spacecraft_vel: None,
+            spacecraft_heading: None,
+            spacecraft_contact: false,
+        });
+
+        // the body rotation adds to the transport rate
+        let r = sc.conf.s.body.radius + Length::new::<meter>(15_000.0);
+        let expected = (Velocity::new::<meter_per_second>(1_673.0)/r).value + sc.conf.s.body.rotation_rate().value;
+        assert_approx_eq!(sc.cur.ang_pos.get::<radian>(), expected, 1e-12);
+        assert_approx_eq!(sc.cur.heading.get::<degree>(), 0.0, 1e-9);
+    }
+
+    #[test]
+    fn test_nav_4_init() {
+        let f = std::fs::File::open("conf/tests/default.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.nav_init = Some(NavInitConf {
+            pos_error: Vec3::from_value(Vec3 {x: 50.0, y: -30.0, z: 0.0}),
+            vel_error: Vec3::from_value(Vec3 {x: 0.0, y: 0.0, z: 0.5}),
+            att_error: Vec3::from_value(Vec3 {x: 0.0, y: 0.0, z: 0.1}),
+        });
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+        let mut sc = Spacecraft::new(conf);
+
+        // not the scenario initial state: upright and hovering
+        let state = StateVector {
+            pos: Vec3::from_value(Vec3 {x: -100.0, y: 500.0, z: 10.0}),
+            vel: Vec3::from_value(Vec3 {x: 2.0, y: -1.0, z: 0.0}),
+            att: Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(90.0)),
+        };
+        nav_init(&mut sc, &state);
+
+        assert_approx_eq!(sc.cur.pos.x.get::<meter>(), -50.0, 1e-9);
+        assert_approx_eq!(sc.cur.pos.y.get::<meter>(), 470.0, 1e-9);
+        assert_approx_eq!(sc.cur.pos.z.get::<meter>(), 10.0, 1e-9);
+        assert_approx_eq!(sc.cur.vel.z.get::<meter_per_second>(), 0.5, 1e-9);
+        assert_approx_eq!(sc.cur.vel_inertial.y.get::<meter_per_second>(), -1.0, 1e-9);
+
+        // pitched by the attitude error
+        assert_approx_eq!(sc.cur.ang_pos.get::<radian>(), PI/2.0 + 0.1, 1e-9);
+        assert_approx_eq!(sc.cur.att.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0}).x, -(0.1f64).sin(), 1e-9);
+    }
+    #[test]
+    fn test_nav_5_invalid_sensors() {
+        let f = std::fs::File::open("conf/tests/default.yaml").unwrap();
+        let scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(0.1), Time::new::<second>(0.1), scenario);
+        let mut sc = Spacecraft::new(conf);
+
+        let state = StateVector {
+            pos: Vec3::from_value(Vec3 {x: 0.0, y: 500.0, z: 0.0}),
+            vel: Vec3::from_value(Vec3 {x: 0.0, y: -1.0, z: 0.0}),
+            att: Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(90.0)),
+        };
+        nav_init(&mut sc, &state);
+        sc.cur.eng_throttle = Ratio::new::<ratio>(0.5);
+
+        let mut sensors_vals = SensorsValues {
+            dt_step: Time::new::<second>(0.1),
+            spacecraft_acc: Some(Vec3::from_value(Vec3 {x: 0.0, y: 0.5, z: 0.0})),
+            spacecraft_ang_vel: Vec3::from_value(Vec3 {x: 0.0, y: 0.0, z: 0.01}),
+            spacecraft_altitude: None,
+            spacecraft_vel: None,
+            spacecraft_heading: None,
+            spacecraft_contact: false,
+        };
+        nav(&mut sc, &sensors_vals);
+        assert_approx_eq!(sc.cur.acc.y.get::<meter_per_second_squared>(), 0.5, 1e-9);
+
+        // invalid samples: the thrust is modeled, the body rates are kept
+        sensors_vals.spacecraft_acc = Some(Vec3::from_value(Vec3 {x: 0.0, y: f64::NAN, z: 0.0}));
+        sensors_vals.spacecraft_ang_vel.z = AngularVelocity::new::<radian_per_second>(f64::NAN);
+        nav(&mut sc, &sensors_vals);
+
+        let sc_mass = sc.conf.s.sc_dry_mass + sc.cur.fuel_mass;
+        let acc_thrust: Acceleration = 0.5*sc.conf.s.sc_nominal_thrust/sc_mass;
+        let acc_y = acc_thrust + sc.cur.acc_gravity + sc.cur.acc_centrifugal;
+        assert_approx_eq!(sc.cur.acc.y.get::<meter_per_second_squared>(), acc_y.get::<meter_per_second_squared>(), 1e-3);
+        assert_approx_eq!(sc.cur.ang_vel.z.get::<radian_per_second>(), 0.01, 1e-12);
+        assert!(sc.cur.pos.y.value.is_finite());
+        assert!(sc.cur.att.w.is_finite());
+        assert!(sc.fdir.events.is_empty());
     }
 }
@@ -0,0 +1,264 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::acceleration::meter_per_second_squared;
+use uom::si::length::meter;
+use uom::si::time::second;
+use uom::si::velocity::meter_per_second;
+
+use crate::conf::Scenario;
+use crate::utils::math::Vec3;
+use crate::utils::space::G0;
+
+
+/// Iterations of the first guidance cycle, to converge from the initial tgo
+const PEG_INIT_ITERATIONS: usize = 20;
+
+/// Intervals of the steering losses integration (even)
+const PEG_SIMPSON_INTERVALS: usize = 20;
+
+
+/// Target of the ascent to orbit (insertion conditions)
+///
+/// Yaml serialized equivalent:
+///
+/// gui_peg:
+///   altitude: 15000.0             # m
+///   velocity: 1672.7              # m/s, inertial, circular at 15 km
+///   flight_path_angle: 0.0        # rad, above the local horizontal
+///   freeze: 10.0                  # s, steering constants held below this tgo
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PegConf {
+    pub altitude: Length,
+    pub velocity: Velocity,
+    pub flight_path_angle: Angle,
+    pub freeze: Time,
+}
+
+
+/// Powered Explicit Guidance (linear tangent steering), state between cycles
+///
+/// The radial component of the thrust direction is
+///     f_r = A + B*t + (g - w**2*r)/a
+/// i.e. linear in time once the gravity and the centrifugal acceleration are
+/// compensated. A and B reach the target radius and radial velocity at tgo,
+/// tgo reaches the target angular momentum (minor loop). Both are updated each
+/// cycle from the current state, and held close to the insertion where the
+/// equations are ill-conditioned.
+///
+/// Source: Jaggers, An explicit solution to the exoatmospheric powered flight
+/// guidance and trajectory optimization problem for rocket propelled vehicles,
+/// AIAA 1977
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Peg {
+    pub a: f64,
+    pub b: f64,                         // 1/s
+    pub tgo: Option<Time>,              // not converged yet if None
+    t: Time,                            // of A and B
+}
+
+
+/// Thrust integrals over [0, tgo], for a constant thrust and mass flow
+///
+///     b0 = int a(t) dt,   b1 = int a(t)*t dt
+///     c0 = int int a(t),  c1 = int int a(t)*t
+fn thrust_integrals(ve: f64, tau: f64, tgo: f64) -> (f64, f64, f64, f64) {
+    let b0 = -ve*(1.0 - tgo/tau).ln();
+    let b1 = b0*tau - ve*tgo;
+    let c0 = b0*tgo - b1;
+    let c1 = c0*tau - ve*tgo.powi(2)/2.0;
+    (b0, b1, c0, c1)
+}
+
+
+/// Horizontal part of the thrust, averaged over [0, tgo] and weighted by the thrust acceleration
+///
+/// With f_r linear from fr to fr_t:
+///     int a(t)*sqrt(1-f_r(t)**2) dt / int a(t) dt
+/// Simpson's rule, the closed form of the Taylor expansion of the cosine is
+/// singular for long burns (tgo close to tau).
+fn steering_efficiency(tau: f64, tgo: f64, fr: f64, fr_t: f64) -> f64 {
+    let n = PEG_SIMPSON_INTERVALS;
+    let (mut num, mut den) = (0.0, 0.0);
+    for i in 0..=n {
+        let k = i as f64/n as f64;
+        let w = if (i == 0) || (i == n) { 1.0 } else if i % 2 == 1 { 4.0 } else { 2.0 };
+        let acc = 1.0/(1.0 - k*tgo/tau);    // relative to a0
+        let f = (fr + k*(fr_t - fr)).clamp(-1.0, 1.0);
+        num += w*acc*(1.0 - f.powi(2)).sqrt();
+        den += w*acc;
+    }
+    num/den
+}
+
+
+impl Peg {
+    pub fn new() -> Self {
+        Peg {
+            a: 0.0,
+            b: 0.0,
+            tgo: None,
+            t: Time::new::<second>(0.0),
+        }
+    }
+
+    /// Guidance cycle: update A, B and tgo, returns the thrust direction
+    ///
+    /// Only in the plane (x downrange, y up) of the trajectory. The initial tgo
+    /// is only a first guess for the iterations.
+    #[allow(clippy::too_many_arguments)]
+    pub fn cycle(
+        &mut self,
+        conf: &Scenario,
+        target: &PegConf,
+        t: Time,
+        altitude: Length,
+        vel_radial: Velocity,
+        vel_horizontal: Velocity,       // inertial
+        acc_thrust: Acceleration,       // full throttle
+        isp: Time,
+        tgo_init: Time,
+    ) -> Vec3<f64> {
+        let radius = conf.body.radius;
+
+        let r = (radius + altitude).get::<meter>();
+        let rdot = vel_radial.get::<meter_per_second>();
+        let vtheta = vel_horizontal.get::<meter_per_second>();
+        let a0 = acc_thrust.get::<meter_per_second_squared>();
+        let ve = (isp*(*G0)).get::<meter_per_second>();
+        let tau = ve/a0;
+
+        let r_t = (radius + target.altitude).get::<meter>();
+        let rdot_t = (target.velocity*target.flight_path_angle.sin()).get::<meter_per_second>();
+        let vtheta_t = (target.velocity*target.flight_path_angle.cos()).get::<meter_per_second>();
+
+        // gravity minus centrifugal, relative to the thrust acceleration
+        let c = (conf.body.gravity(altitude).get::<meter_per_second_squared>() - vtheta.powi(2)/r)/a0;
+        let g_t = conf.body.gravity(target.altitude).get::<meter_per_second_squared>() - vtheta_t.powi(2)/r_t;
+
+        // move A and B to the current time
+
+        let (mut tgo, iterations) = match self.tgo {
+            None => (tgo_init.get::<second>(), PEG_INIT_ITERATIONS),
+            Some(tgo) => {
+                let dt = (t - self.t).get::<second>();
+                self.a += self.b*dt;
+                (tgo.get::<second>() - dt, 1)
+            },
+        };
+        self.t = t;
+
+        if (self.tgo.is_none()) || (tgo > target.freeze.get::<second>()) {
+            for _ in 0..iterations {
+                // major loop: A and B from the radius and radial velocity to go
+
+                let (b0, b1, c0, c1) = thrust_integrals(ve, tau, tgo);
+                let det = b0*c1 - b1*c0;
+                let drdot = rdot_t - rdot;
+                let dr = r_t - r - rdot*tgo;
+
+                let a = (c1*drdot - b1*dr)/det;
+                let b = (b0*dr - c0*drdot)/det;
+
+                // minor loop: tgo from the angular momentum to go
+
+                let a_t = a0/(1.0 - tgo/tau);
+                let fr = a + c;
+                let fr_t = a + b*tgo + g_t/a_t;
+
+                let dh = r_t*vtheta_t - r*vtheta;
+                let rbar = (r + r_t)/2.0;
+                let dv = dh/rbar/steering_efficiency(tau, tgo, fr, fr_t);
+                let tgo_next = tau*(1.0 - (-dv/ve).exp());
+
+                // keep the previous solution rather than diverge
+                if !(a.is_finite() && b.is_finite() && tgo_next.is_finite() && (tgo_next > 0.0)) {
+                    break;
+                }
+
+                self.a = a;
+                self.b = b;
+                tgo = tgo_next;
+            }
+        }
+        self.tgo = Some(Time::new::<second>(tgo));
+
+        // steering
+
+        let fr = (self.a + c).clamp(-1.0, 1.0);
+
+        Vec3 {
+            x: (1.0 - fr.powi(2)).sqrt(),
+            y: fr,
+            z: 0.0,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::angle::degree;
+
+    use crate::assert_approx_eq;
+
+    #[test]
+    fn test_peg_1_insertion() {
+        let f = std::fs::File::open("conf/Apollo-ascent.yaml").unwrap();
+        let conf: Scenario = serde_yaml::from_reader(f).unwrap();
+        let target = PegConf {
+            altitude: Length::new::<meter>(15000.0),
+            velocity: Velocity::new::<meter_per_second>(1672.7),
+            flight_path_angle: Angle::new::<degree>(0.0),
+            freeze: Time::new::<second>(5.0),
+        };
+
+        // point mass in the plane, after the vertical rise
+
+        let dt = Time::new::<second>(0.1);
+        let thrust = conf.sc_nominal_thrust;
+        let mut mass = conf.sc_dry_mass + conf.initial_sc_fuel_mass;
+        let mut t = Time::new::<second>(0.0);
+        let mut x = Length::new::<meter>(0.0);
+        let mut r = conf.body.radius + Length::new::<meter>(500.0);
+        let mut vx = Velocity::new::<meter_per_second>(0.0);
+        let mut vr = Velocity::new::<meter_per_second>(40.0);
+
+        let mut peg = Peg::new();
+        let mut tgo = Time::new::<second>(500.0);
+        let mut dv = Velocity::new::<meter_per_second>(0.0);
+
+        while tgo > dt {
+            let dir = peg.cycle(&conf, &target, t, r - conf.body.radius, vr, vx, thrust/mass, conf.sc_nominal_isp, tgo);
+            tgo = peg.tgo.unwrap();
+
+            let acc: Acceleration = thrust/mass;
+            let g = conf.body.gravity(r - conf.body.radius);
+
+            // polar coordinates: r'' = a_r - g + vx**2/r, vx' = a_x - vx*vr/r
+            let ar: Acceleration = acc*dir.y - g + vx*vx/r;
+            let ax: Acceleration = acc*dir.x - vx*vr/r;
+            r += vr*dt;
+            x += vx*dt;
+            vr += ar*dt;
+            vx += ax*dt;
+            mass -= thrust/(conf.sc_nominal_isp*(*G0))*dt;
+            dv += acc*dt;
+            t += dt;
+
+            assert!(t < Time::new::<second>(600.0));
+        }
+
+        // on target, and close to the ideal dv (rocket equation from 0 m/s: 1673 m/s)
+        assert_approx_eq!((r - conf.body.radius).get::<meter>(), 15000.0, 50.0);
+        assert_approx_eq!(vr.get::<meter_per_second>(), 0.0, 1.0);
+        assert_approx_eq!(vx.get::<meter_per_second>(), 1672.7, 2.0);
+        assert!(dv < Velocity::new::<meter_per_second>(1780.0));
+        assert!(x > Length::new::<meter>(0.0));
+    }
+}
@@ -0,0 +1,440 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::length::meter;
+use uom::si::time::second;
+
+use crate::conf::{Scenario, GuiSpacecraft, CtrSpacecraft};
+use crate::gnc::common::Spacecraft;
+use crate::gnc::divert::{self, DivertConf, DivertEvent, DivertSource};
+use crate::gnc::gfold::Gfold;
+use crate::gnc::peg::Peg;
+use crate::utils::math::Vec3;
+
+
+/// Condition ending a phase, besides tgo_stop
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
+pub enum PhaseExit {
+    ExitAltitude(Length),           // below
+    ExitSpeed(Velocity),            // below, surface-relative
+    ExitTgo(Time),                  // below
+}
+
+
+/// Mission phase: target, guidance law and control mode
+///
+/// Yaml serialized equivalent:
+///
+/// seq_phases:
+///   - name: "P63 braking"
+///     gui_spacecraft: "GuiDescent"
+///     ctr_spacecraft: "CtrSpacecraftDescent"
+///     gui_af: [-2.2, 0.16, 0.0]
+///     gui_vf: [139.0, -25.0, 0.0]
+///     gui_pf: [-5850.0, 2420.0, 0.0]      # high gate
+///     tgo: 455.0                          # cf. tgo_method if null
+///     exit: {ExitAltitude: 2500.0}        # until tgo_stop if null
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PhaseConf {
+    pub name: String,
+    pub gui_spacecraft: GuiSpacecraft,
+    pub ctr_spacecraft: CtrSpacecraft,
+    pub gui_af: Vec3<Acceleration>,
+    pub gui_vf: Vec3<Velocity>,
+    pub gui_pf: Vec3<Length>,
+    pub tgo: Option<Time>,              // initial tgo, cf. tgo_method if null
+    pub exit: Option<PhaseExit>,
+}
+
+
+impl PhaseConf {
+    /// The single phase of a scenario without seq_phases
+    fn from_scenario(conf: &Scenario) -> Self {
+        PhaseConf {
+            name: "single".to_string(),
+            gui_spacecraft: conf.gui_spacecraft,
+            ctr_spacecraft: conf.ctr_spacecraft,
+            gui_af: Vec3 {x: conf.gui_af_x, y: conf.gui_af_y, z: conf.gui_af_z},
+            gui_vf: Vec3 {x: conf.gui_vf_x, y: conf.gui_vf_y, z: conf.gui_vf_z},
+            gui_pf: Vec3 {x: conf.gui_pf_x, y: conf.gui_pf_y, z: conf.gui_pf_z},
+            tgo: None,
+            exit: None,
+        }
+    }
+}
+
+
+/// Mission phases sequencer
+///
+/// Hands over from one phase to the next when its exit condition is met, or
+/// when its tgo is below tgo_stop (the guidance would diverge), or at touchdown
+/// for the vertical descent. The phase
+/// target, guidance law and control mode replace the ones of the scenario
+/// (gui_*, gui_spacecraft, ctr_spacecraft), hence guidance and control do not
+/// know about phases.
+///
+/// It also moves the landing site (divert), cf. Sequencer::divert().
+pub struct Sequencer {
+    phases: Vec<PhaseConf>,
+    cur: usize,
+    divert: Option<DivertConf>,
+    scheduled: usize,                   // next scheduled divert
+    hazards_checked: bool,              // in the current phase
+    pub diverts: Vec<DivertEvent>,
+}
+
+
+impl Sequencer {
+    pub fn new(conf: &Scenario) -> Self {
+        let phases = match &conf.seq_phases {
+            Some(phases) if !phases.is_empty() => phases.clone(),
+            _ => vec![PhaseConf::from_scenario(conf)],
+        };
+
+        Sequencer {
+            phases: phases,
+            cur: 0,
+            divert: conf.seq_divert.clone(),
+            scheduled: 0,
+            hazards_checked: false,
+            diverts: vec![],
+        }
+    }
+
+    pub fn phase(&self) -> &PhaseConf {
+        &self.phases[self.cur]
+    }
+
+    /// Landing site, the target of the last phase
+    pub fn site(&self) -> Vec3<Length> {
+        self.phases[self.phases.len()-1].gui_pf
+    }
+
+    /// Enter the first phase
+    pub fn start(&self, spacecraft: &mut Spacecraft) {
+        self.enter(spacecraft);
+    }
+
+    /// Whether the current phase is over
+    ///
+    /// The vertical descent does not depend on tgo: it is over at touchdown.
+    pub fn exit(&self, spacecraft: &Spacecraft, tgo: Time) -> bool {
+        if self.phase().gui_spacecraft == GuiSpacecraft::GuiVerticalDescent {
+            if spacecraft.cur.contact {
+                return true;
+            }
+        } else if tgo < spacecraft.conf.s.tgo_stop {
+            return true;
+        }
+
+        match self.phase().exit {
+            None => false,
+            Some(PhaseExit::ExitAltitude(altitude)) => spacecraft.cur.pos.y < altitude,
+            Some(PhaseExit::ExitSpeed(speed)) => spacecraft.cur.vel.norm() < speed,
+            Some(PhaseExit::ExitTgo(t)) => tgo < t,
+        }
+    }
+
+    /// Hand over to the next phase, returns false after the last one
+    pub fn next(&mut self, spacecraft: &mut Spacecraft) -> bool {
+        if self.cur+1 >= self.phases.len() {
+            return false;
+        }
+
+        self.cur += 1;
+        self.hazards_checked = false;
+        self.enter(spacecraft);
+        true
+    }
+
+    /// Scheduled diverts, and the hazard map once per phase, every guidance cycle
+    pub fn update(&mut self, spacecraft: &mut Spacecraft, tgo: Time) {
+        let conf = match &self.divert {
+            None => return,
+            Some(conf) => conf,
+        };
+
+        let event = conf.scheduled.get(self.scheduled).copied();
+        if let Some(event) = event {
+            if event.t <= spacecraft.cur.t {
+                self.scheduled += 1;
+                self.divert(spacecraft, event.site, tgo, DivertSource::Scheduled);
+                return;
+            }
+        }
+
+        if !self.hazards_checked {
+            self.hazards_checked = true;
+            let site = self.divert.as_ref().and_then(|conf| divert::safe_site(&conf.hazards, self.site()));
+            if let Some(site) = site {
+                self.divert(spacecraft, site, tgo, DivertSource::HazardMap);
+            }
+        }
+    }
+
+    /// Move the landing site, returns false if the divert is rejected
+    ///
+    /// The targets of the current and next phases move with the site (the
+    /// gates are relative to it). Only the current phase is checked: it must
+    /// reach its new target within tgo, with the gui_gfold bounds and the fuel
+    /// above fuel_reserve (cf. divert::reachable). The next phases start from
+    /// there. A divert is always rejected without seq_divert, and during the
+    /// vertical descent or an ascent. It is in practice during the braking from
+    /// orbit too: the check assumes a flat ground (cf. gfold::Descent).
+    pub fn divert(&mut self, spacecraft: &mut Spacecraft, site: Vec3<Length>, tgo: Time, source: DivertSource) -> bool {
+        let offset = site - self.site();
+        let phase = self.phase();
+        let pf = phase.gui_pf + offset;
+
+        let accepted = match &self.divert {
+            None => false,
+            Some(conf) => (
+                [GuiSpacecraft::GuiDescent, GuiSpacecraft::GuiGfold].contains(&phase.gui_spacecraft)
+                && divert::reachable(spacecraft, conf, pf, phase.gui_vf, tgo)
+            ),
+        };
+
+        println!(
+            "[LOGD:Sequencer::divert] source={:?} site=[{:.1}, {:.1}, {:.1}] accepted={} t={:.3}",
+            source, site.x.get::<meter>(), site.y.get::<meter>(), site.z.get::<meter>(), accepted,
+            spacecraft.cur.t.get::<second>(),
+        );
+        self.diverts.push(DivertEvent {
+            t: spacecraft.cur.t,
+            source: source,
+            site: site,
+            accepted: accepted,
+        });
+
+        if !accepted {
+            return false;
+        }
+
+        for phase in self.phases[self.cur..].iter_mut() {
+            phase.gui_pf += offset;
+        }
+
+        let conf = &mut spacecraft.conf.s;
+        conf.gui_pf_x = pf.x;
+        conf.gui_pf_y = pf.y;
+        conf.gui_pf_z = pf.z;
+
+        // the reference trajectory is for the previous target
+        spacecraft.gfold = Gfold::new();
+
+        true
+    }
+
+    fn enter(&self, spacecraft: &mut Spacecraft) {
+        let phase = self.phase();
+        let conf = &mut spacecraft.conf.s;
+
+        conf.gui_spacecraft = phase.gui_spacecraft;
+        conf.ctr_spacecraft = phase.ctr_spacecraft;
+
+        conf.gui_af_x = phase.gui_af.x;
+        conf.gui_af_y = phase.gui_af.y;
+        conf.gui_af_z = phase.gui_af.z;
+        conf.gui_vf_x = phase.gui_vf.x;
+        conf.gui_vf_y = phase.gui_vf.y;
+        conf.gui_vf_z = phase.gui_vf.z;
+        conf.gui_pf_x = phase.gui_pf.x;
+        conf.gui_pf_y = phase.gui_pf.y;
+        conf.gui_pf_z = phase.gui_pf.z;
+
+        // the guidance states are for the previous target
+        spacecraft.peg = Peg::new();
+        spacecraft.gfold = Gfold::new();
+
+        println!(
+            "[LOGD:Sequencer::enter] phase={} name={:?} t={:.3}",
+            self.cur, phase.name, spacecraft.cur.t.get::<second>(),
+        );
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::acceleration::meter_per_second_squared;
+    use uom::si::velocity::meter_per_second;
+
+    use uom::si::angle::degree;
+    use uom::si::mass::kilogram;
+
+    use crate::conf::{SubCommand, Conf};
+    use crate::gnc::divert::{Hazard, ScheduledDivert};
+    use crate::utils::math::Quaternion;
+
+    fn spacecraft(phases: Option<Vec<PhaseConf>>) -> Spacecraft {
+        let f = std::fs::File::open("conf/tests/default.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.seq_phases = phases;
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(1.0), Time::new::<second>(1.0), scenario);
+        Spacecraft::new(conf)
+    }
+
+    fn phase(name: &str, pf_y: f64, exit: Option<PhaseExit>) -> PhaseConf {
+        PhaseConf {
+            name: name.to_string(),
+            gui_spacecraft: GuiSpacecraft::GuiDescent,
+            ctr_spacecraft: CtrSpacecraft::CtrSpacecraftDescent,
+            gui_af: Vec3::from_value(Vec3 {x: -1.0, y: 0.0, z: 0.0}),
+            gui_vf: Vec3::from_value(Vec3 {x: 10.0, y: -5.0, z: 0.0}),
+            gui_pf: Vec3::from_value(Vec3 {x: -500.0, y: pf_y, z: 0.0}),
+            tgo: None,
+            exit: exit,
+        }
+    }
+
+    #[test]
+    fn test_sequencer_1_single() {
+        let mut sc = spacecraft(None);
+        let vf_y = sc.conf.s.gui_vf_y;
+        let mut seq = Sequencer::new(&sc.conf.s);
+
+        seq.start(&mut sc);
+        assert_eq!(seq.phase().name, "single");
+        assert_eq!(sc.conf.s.gui_vf_y, vf_y);
+
+        // until tgo_stop, then done
+        let tgo_stop = sc.conf.s.tgo_stop;
+        assert!(!seq.exit(&sc, tgo_stop + Time::new::<second>(0.1)));
+        assert!(seq.exit(&sc, tgo_stop - Time::new::<second>(0.1)));
+        assert!(!seq.next(&mut sc));
+    }
+
+    #[test]
+    fn test_sequencer_2_phases() {
+        let phases = vec![
+            phase("braking", 2000.0, Some(PhaseExit::ExitAltitude(Length::new::<meter>(2500.0)))),
+            phase("approach", 1.0, Some(PhaseExit::ExitSpeed(Velocity::new::<meter_per_second>(2.0)))),
+        ];
+        let mut sc = spacecraft(Some(phases));
+        let mut seq = Sequencer::new(&sc.conf.s);
+        let tgo = Time::new::<second>(100.0);
+
+        // the phase targets replace the scenario ones
+        seq.start(&mut sc);
+        assert_eq!(seq.phase().name, "braking");
+        assert_eq!(sc.conf.s.gui_pf_y, Length::new::<meter>(2000.0));
+        assert_eq!(sc.conf.s.gui_vf_x, Velocity::new::<meter_per_second>(10.0));
+        assert_eq!(sc.conf.s.gui_af_x, Acceleration::new::<meter_per_second_squared>(-1.0));
+
+        sc.cur.pos.y = Length::new::<meter>(3000.0);
+        sc.cur.vel = Vec3::from_value(Vec3 {x: 100.0, y: -20.0, z: 0.0});
+        assert!(!seq.exit(&sc, tgo));
+
+        sc.cur.pos.y = Length::new::<meter>(2400.0);
+        assert!(seq.exit(&sc, tgo));
+        assert!(seq.next(&mut sc));
+        assert_eq!(seq.phase().name, "approach");
+        assert_eq!(sc.conf.s.gui_pf_y, Length::new::<meter>(1.0));
+
+        assert!(!seq.exit(&sc, tgo));
+        sc.cur.vel = Vec3::from_value(Vec3 {x: 0.5, y: -1.0, z: 0.0});
+        assert!(seq.exit(&sc, tgo));
+        assert!(!seq.next(&mut sc));
+    }
+
+    #[test]
+    fn test_sequencer_3_vertical_descent() {
+        let mut vertical = phase("vertical descent", 0.0, None);
+        vertical.gui_spacecraft = GuiSpacecraft::GuiVerticalDescent;
+        let phases = vec![phase("approach", 30.0, None), vertical];
+        let mut sc = spacecraft(Some(phases));
+        let mut seq = Sequencer::new(&sc.conf.s);
+
+        seq.start(&mut sc);
+        assert!(seq.exit(&sc, Time::new::<second>(0.0)));
+        assert!(seq.next(&mut sc));
+        assert_eq!(sc.conf.s.gui_spacecraft, GuiSpacecraft::GuiVerticalDescent);
+
+        // until touchdown, whatever tgo
+        assert!(!seq.exit(&sc, Time::new::<second>(-10.0)));
+        sc.cur.contact = true;
+        assert!(seq.exit(&sc, Time::new::<second>(100.0)));
+        assert!(!seq.next(&mut sc));
+    }
+
+    #[test]
+    fn test_sequencer_4_divert() {
+        let mut vertical = phase("vertical descent", 0.0, None);
+        vertical.gui_spacecraft = GuiSpacecraft::GuiVerticalDescent;
+        let phases = vec![phase("approach", 30.0, None), vertical];
+        let mut sc = spacecraft(Some(phases));
+
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let apollo: Scenario = serde_yaml::from_reader(f).unwrap();
+        sc.conf.s.gui_gfold = apollo.gui_gfold;
+        sc.conf.s.seq_divert = Some(DivertConf {
+            fuel_reserve: Mass::new::<kilogram>(100.0),
+            scheduled: vec![ScheduledDivert {
+                t: Time::new::<second>(5.0),
+                site: Vec3::from_value(Vec3 {x: -400.0, y: 0.0, z: 50.0}),
+            }],
+            hazards: vec![Hazard {
+                x: Length::new::<meter>(-520.0),
+                z: Length::new::<meter>(0.0),
+                radius: Length::new::<meter>(80.0),
+            }],
+        });
+        let mut seq = Sequencer::new(&sc.conf.s);
+        let tgo = Time::new::<second>(60.0);
+
+        // at the high gate, upright and hovering, with the fuel left after the braking
+        sc.cur.pos = Vec3::from_value(Vec3 {x: -1500.0, y: 800.0, z: 100.0});
+        sc.cur.vel = Vec3::from_value(Vec3 {x: 40.0, y: -15.0, z: 0.0});
+        sc.cur.vel_inertial = sc.cur.vel;
+        sc.cur.fuel_mass = Mass::new::<kilogram>(1500.0);
+        sc.cur.att = Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(90.0));
+        sc.cur.acc_thrust = sc.conf.s.body.gravity(sc.cur.pos.y);
+
+        // the site is in a hazard: moved out of it, the gates with it
+        seq.start(&mut sc);
+        seq.update(&mut sc, tgo);
+        assert_eq!(seq.diverts.len(), 1);
+        assert_eq!(seq.diverts[0].source, DivertSource::HazardMap);
+        assert!(seq.diverts[0].accepted);
+        assert_eq!(sc.conf.s.gui_pf_x, Length::new::<meter>(-439.0));
+        assert_eq!(sc.conf.s.gui_pf_y, Length::new::<meter>(30.0));
+        assert_eq!(seq.site().x, Length::new::<meter>(-439.0));
+        assert_eq!(seq.site().y, Length::new::<meter>(0.0));
+
+        // once per phase
+        seq.update(&mut sc, tgo);
+        assert_eq!(seq.diverts.len(), 1);
+
+        // too far, or not enough fuel: rejected, the target is kept
+        let far = Vec3::from_value(Vec3 {x: 5000.0, y: 0.0, z: 3000.0});
+        assert!(!seq.divert(&mut sc, far, tgo, DivertSource::Command));
+        sc.cur.fuel_mass = Mass::new::<kilogram>(110.0);
+        let near = Vec3::from_value(Vec3 {x: -400.0, y: 0.0, z: 20.0});
+        assert!(!seq.divert(&mut sc, near, tgo, DivertSource::Command));
+        assert_eq!(sc.conf.s.gui_pf_x, Length::new::<meter>(-439.0));
+        sc.cur.fuel_mass = Mass::new::<kilogram>(1500.0);
+        assert!(seq.divert(&mut sc, near, tgo, DivertSource::Command));
+        assert_eq!(sc.conf.s.gui_pf_z, Length::new::<meter>(20.0));
+
+        // scheduled
+        sc.cur.t = Time::new::<second>(5.0);
+        seq.update(&mut sc, tgo);
+        assert_eq!(seq.diverts.len(), 5);
+        assert_eq!(seq.diverts[4].source, DivertSource::Scheduled);
+        assert!(seq.diverts[4].accepted);
+        assert_eq!(sc.conf.s.gui_pf_z, Length::new::<meter>(50.0));
+
+        // not during the vertical descent
+        assert!(seq.next(&mut sc));
+        assert_eq!(sc.conf.s.gui_pf_x, Length::new::<meter>(-400.0));
+        assert!(!seq.divert(&mut sc, near, tgo, DivertSource::Command));
+    }
+}
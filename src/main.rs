@@ -1,5 +1,7 @@
 // When they are multi-lines, I like to enclose my if conditions to differentiate with the body
 #![allow(unused_parens)]
+// I also like my struct inits explicit, `field: field` even when the names match
+#![allow(clippy::redundant_field_names)]
 
 mod adapters;
 mod conf;
@@ -9,36 +11,60 @@ mod utils;
 
 use std::process::exit;
 use std::{thread, time};
-use clap;
 use pyo3::prelude::*;
-use serde_json;
 use uom::si::f64::*;
+use uom::si::angle::degree;
+use uom::si::force::newton;
+use uom::si::length::meter;
+use uom::si::mass::kilogram;
 use uom::si::time::second;
 use uom::si::velocity::meter_per_second;
 
-use crate::conf::{Scenario, Conf, TgoEstimate, SubCommand};
+use crate::conf::{Scenario, Conf, TgoEstimate, SubCommand, GuiSpacecraft};
 use crate::gnc::common::Spacecraft;
-use crate::utils::space::{tgo_estimate, has_softly_landed};
+use crate::gnc::sequencer::Sequencer;
+use crate::utils::space::{tgo_estimate, has_softly_landed, has_safely_touched_down};
 
 
-fn land(adapter: &mut dyn adapters::common::Adapter, conf: Conf) {
-    let mut sc = Spacecraft::new(conf);
+/// Initial tgo, of the scenario or of a phase
+fn tgo_init(sc: &Spacecraft, seq: &Sequencer) -> Time {
+    if let Some(tgo) = seq.phase().tgo {
+        return tgo;
+    }
 
-    let mut tgo: Time = match conf.s.tgo_method {
+    match sc.conf.s.tgo_method {
         TgoEstimate::TgoGivenFixed => {
-            conf.s.tgo_init
+            sc.conf.s.tgo_init
         },
         TgoEstimate::TgoEstimateFixed | TgoEstimate::TgoEstimateUpdating => {
-            tgo_estimate(&sc, sc.conf.s.gui_vf_x, sc.conf.s.gui_vf_y, sc.conf.s.tgo_thrust_mul)
+            tgo_estimate(sc, sc.conf.s.gui_vf_x, sc.conf.s.gui_vf_y, sc.conf.s.gui_vf_z, sc.conf.s.tgo_thrust_mul)
         }
-    };
+    }
+}
+
+
+fn land(adapter: &mut dyn adapters::common::Adapter, conf: Conf) {
+    let mut sc = Spacecraft::new(conf.clone());
+    let mut seq = Sequencer::new(&conf.s);
+
+    // start from where the spacecraft actually is
+
+    let state = adapter.read_state();
+    gnc::navigation::nav_init(&mut sc, &state);
+
+    seq.start(&mut sc);
+    let mut tgo: Time = tgo_init(&sc, &seq);
 
     sc.export_to_csv_conf();
     adapter.export_to_csv_conf();
 
     loop {
-        if conf.s.tgo_method == TgoEstimate::TgoEstimateUpdating {
-            tgo = tgo_estimate(&sc, sc.conf.s.gui_vf_x, sc.conf.s.gui_vf_y, sc.conf.s.tgo_thrust_mul);
+        if sc.conf.s.gui_spacecraft == GuiSpacecraft::GuiAscentPeg {
+            tgo = sc.peg.tgo.unwrap_or(tgo);    // iterated by the guidance itself
+        } else if sc.conf.s.gui_spacecraft == GuiSpacecraft::GuiGfold {
+            tgo = sc.gfold.tgo(sc.cur.t).unwrap_or(tgo);    // final time of the reference trajectory
+        } else if conf.s.tgo_method == TgoEstimate::TgoEstimateUpdating {
+            tgo = tgo_estimate(&sc, sc.conf.s.gui_vf_x, sc.conf.s.gui_vf_y, sc.conf.s.gui_vf_z, sc.conf.s.tgo_thrust_mul);
         }
 
         println!("[LOGD:land] tgo={:.3}", tgo.get::<second>());
@@ -49,6 +75,7 @@ fn land(adapter: &mut dyn adapters::common::Adapter, conf: Conf) {
         println!("[LOGD:land] SensorsValues={}", serde_json::to_string(&sensors_vals).unwrap());
 
         gnc::navigation::nav(&mut sc, &sensors_vals);
+        seq.update(&mut sc, tgo);
         gnc::guidance::gui(&mut sc, tgo);
         let actuators_vals = gnc::control::ctr(&mut sc);
 
@@ -63,23 +90,86 @@ fn land(adapter: &mut dyn adapters::common::Adapter, conf: Conf) {
 
         sc.export_to_csv_cur();
         adapter.export_to_csv_cur();
+        adapter.check_nav(&sc.cur);
+
+        // time, next phase, or stop the loop: at touchdown for the vertical descent, else a few
+        // seconds before touchdown, to prevent guidance from diverging to +/- inf
+
+        if seq.exit(&sc, tgo) {
+            if !seq.next(&mut sc) {
+                break;
+            }
+            tgo = tgo_init(&sc, &seq);
+        } else {
+            tgo -= sensors_vals.dt_step;
+        }
+        thread::sleep(time::Duration::from_secs_f64(conf.dt_sleep.get::<second>()));
+    }
+
+    // engine off, run the last seconds to rest (simulator only, descent only)
 
-        // Stop the loop a few seconds before touchdown, to prevent guidance from diverging to +/- inf
+    let descent = [GuiSpacecraft::GuiDescent, GuiSpacecraft::GuiVerticalDescent, GuiSpacecraft::GuiGfold].contains(&sc.conf.s.gui_spacecraft);
+    let touchdown = if descent { adapter.settle() } else { None };
+    if let Some(touchdown) = &touchdown {
+        println!("[LOGD:land] Touchdown={}", serde_json::to_string(&touchdown).unwrap());
 
-        if tgo < conf.s.tgo_stop {
-            break;
+        if touchdown.tipped_over {
+            println!("Touchdown is a TIP-OVER");
+        } else if touchdown.t_rest.is_none() {
+            println!("Touchdown is NOT AT REST");
+        } else {
+            println!("Touchdown is UPRIGHT");
         }
+        println!("Touchdown slope is {}", if touchdown.safe { "SAFE" } else { "UNSAFE" });
+
+        let max_leg_load = touchdown.leg_loads.iter().fold(0.0, |acc: f64, f| acc.max(f.get::<newton>()));
+        println!(
+            "touchdown: vel={:.2} m/s, slope={:.1} deg, max leg load={:.0} N, bounce={:.2} m, tilt={:.1} deg",
+            touchdown.vel.norm().get::<meter_per_second>(), touchdown.slope.get::<degree>(),
+            max_leg_load, touchdown.bounce.get::<meter>(), touchdown.tilt.get::<degree>(),
+        );
+    }
 
-        // time
+    // safe, and on target: the true touchdown (simulator only), else the navigation state
 
-        tgo -= sensors_vals.dt_step;
-        thread::sleep(time::Duration::from_secs_f64(conf.dt_sleep.get::<second>()));
+    if descent {
+        let pos = touchdown.as_ref().map_or(sc.cur.pos, |touchdown| touchdown.pos);
+        let miss = (pos - seq.site()).horizontal_norm();
+        println!("landing site: miss distance={:.1} m", miss.get::<meter>());
+
+        let landed = match &touchdown {
+            Some(touchdown) => has_safely_touched_down(touchdown),
+            None => has_softly_landed(&sc),
+        };
+
+        if landed && (miss <= sc.conf.s.landing_radius) {
+            println!("Landing is SUCCESSFUL");
+        } else {
+            println!("Landing is FAILED");
+        }
+    }
+
+    if let Some(report) = adapter.nav_report() {
+        println!("[LOGD:land] NavReport={}", serde_json::to_string(&report).unwrap());
+        println!(
+            "nav error (rms/max/final): pos={:.2}/{:.2}/{:.2} m, vel={:.3}/{:.3}/{:.3} m/s, att={:.3}/{:.3}/{:.3} deg, fuel={:.1}/{:.1}/{:.1} kg",
+            report.pos.rms.get::<meter>(), report.pos.max.get::<meter>(), report.pos.last.get::<meter>(),
+            report.vel.rms.get::<meter_per_second>(), report.vel.max.get::<meter_per_second>(), report.vel.last.get::<meter_per_second>(),
+            report.att.rms.get::<degree>(), report.att.max.get::<degree>(), report.att.last.get::<degree>(),
+            report.fuel_mass.rms.get::<kilogram>(), report.fuel_mass.max.get::<kilogram>(), report.fuel_mass.last.get::<kilogram>(),
+        );
+    }
+
+    for event in sc.fdir.events.iter() {
+        println!("sensor failed: {:?} ({:?}) at t={:.1} s", event.sensor, event.fault, event.t.get::<second>());
     }
 
-    if has_softly_landed(&sc) {
-        println!("Landing is SUCCESSFUL");
-    } else {
-        println!("Landing is FAILED");
+    for event in seq.diverts.iter() {
+        println!(
+            "divert {}: {:?} to [{:.1}, {:.1}, {:.1}] m at t={:.1} s",
+            if event.accepted { "accepted" } else { "REJECTED" }, event.source,
+            event.site.x.get::<meter>(), event.site.y.get::<meter>(), event.site.z.get::<meter>(), event.t.get::<second>(),
+        );
     }
 
     println!("spacecraft.dv: {:.1} m/s", sc.cur.dv.get::<meter_per_second>());
@@ -120,7 +210,11 @@ fn main() {
 
             let conf = Conf::new(SubCommand::Sim, dt_step, Time::new::<second>(0.0), scenario);
 
-            let mut adapter = adapters::sim::init(conf).unwrap();  // TODO handle error
+            // the error is already printed
+            let mut adapter = match adapters::sim::init(conf.clone()) {
+                Ok(adapter) => adapter,
+                Err(_) => exit(1),
+            };
 
             land(&mut adapter, conf);
         },
@@ -134,7 +228,11 @@ fn main() {
 
             let conf = Conf::new(SubCommand::Ksp, Time::new::<second>(0.0), dt_sleep, scenario);
 
-            let mut adapter = adapters::ksp::init(&py).unwrap();  // TODO handle error
+            // the error is already printed
+            let mut adapter = match adapters::ksp::init(&py, &conf) {
+                Ok(adapter) => adapter,
+                Err(_) => exit(1),
+            };
 
             land(&mut adapter, conf);
         },
@@ -1,130 +1,659 @@
 use std::f64::consts::PI;
+use std::ops::{Add, Mul};
 
+use serde::{Serialize, Deserialize};
 use uom::si::f64::*;
+use uom::si::angle::radian;
 use uom::si::acceleration::meter_per_second_squared;
+use uom::si::angular_velocity::{degree_per_second, radian_per_second};
+use uom::si::force::newton;
+use uom::si::length::meter;
 use uom::si::ratio::ratio;
+use uom::si::time::second;
 use uom::si::velocity::meter_per_second;
 
-use crate::{mul, norm, sqrt, squared};
-use crate::adapters::common::{SensorsValues, ActuatorsValues};
+use crate::squared;
+use crate::adapters::common::{StateVector, SensorsValues, ActuatorsValues, Touchdown, NavError, NavReport};
 use crate::conf::{Conf, Scenario};
-use crate::utils::math::Vec2;
+use crate::utils::aero::aero;
+use crate::utils::atmosphere::Atmosphere;
+use crate::utils::frames::{Lvlh, lvlh_to_bci, bci_to_lvlh, lvlh_to_bci_rotation, inertial_velocity};
+use crate::utils::integrator::{IntegrableState, integrate};
+use crate::utils::math::{Vec3, Quaternion, Mat3};
+use crate::utils::space::mass_flow;
 use crate::utils::spacecraft::SpacecraftDynamic;
-
-
+use crate::utils::terrain::Terrain;
+
+
+/// 3D simulator
+///
+/// The spacecraft is propagated in the body-centred inertial frame (cf.
+/// utils::frames), with a quaternion attitude, a two-axis engine gimbal and a
+/// roll RCS. Sensors and exported values are converted to the local frame
+/// (LVLH: x downrange, y up, z cross-range).
+///
+/// The radar altimeter measures the height above the terrain. The landing
+/// legs (and the hull) touch the terrain with a spring-damper and friction
+/// model, the touchdown is tracked from the first contact to rest.
 pub struct Sim {
     dt: Time,
     conf: Scenario,                 // spacecraft configuration / static properties
-    cur: SpacecraftDynamic,         // latest changing properties
+    terrain: Terrain,
+    state: SimState,                // 3D state, integrated, BCI frame
+    cur: SpacecraftDynamic,         // latest changing properties, LVLH frame
     all: Vec<SpacecraftDynamic>,    // all changing properties
+    airborne: bool,                 // the legs have left the ground (or never touched it)
+    touchdown: Option<Touchdown>,
+    nav_errors: Vec<NavError>,      // navigation minus truth, every step
+}
+
+
+// Contacts are stiff, they are integrated with smaller steps
+const CONTACT_SUBSTEPS: usize = 20;
+// Below this tangential speed, friction is proportional to the speed (instead
+// of a discontinuous Coulomb friction)
+const CONTACT_FRICTION_VEL: f64 = 0.1;  // m/s
+// At rest: surface-relative speed and body rates below these, after this time
+const SETTLE_VEL: f64 = 0.05;  // m/s
+const SETTLE_ANG_VEL: f64 = 0.1;  // deg/s
+const SETTLE_MIN_TIME: f64 = 1.0;  // s, from the first contact
+const SETTLE_TIMEOUT: f64 = 60.0;  // s
+
+
+/// Actual performance of the simulated engine, relative to the nominal one
+///
+/// The GNC only knows the nominal thrust and Isp (cf. gnc::engine).
+///
+/// Yaml serialized equivalent:
+///
+/// sim_engine:
+///     thrust_error: -0.01     # 1% below the nominal thrust
+///     isp_error: 0.005
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimEngineConf {
+    pub thrust_error: Ratio,
+    pub isp_error: Ratio,
+}
+
+
+/// Integrated state of the simulator
+#[derive(Clone, Copy)]
+#[derive(Serialize)]
+pub struct SimState {
+    pub pos: Vec3<Length>,                  // BCI frame
+    pub vel: Vec3<Velocity>,                // BCI frame
+    pub att: Quaternion,                    // body to BCI frame, body x is the thrust axis
+    pub ang_vel: Vec3<AngularVelocity>,     // body frame
+    pub fuel_mass: Mass,
+}
+
+/// Time derivative of SimState
+#[derive(Clone, Copy)]
+pub struct SimStateDerivative {
+    pub vel: Vec3<Velocity>,
+    pub acc: Vec3<Acceleration>,
+    pub att_rate: Quaternion,               // per second
+    pub ang_acc: Vec3<AngularAcceleration>,
+    pub mass_rate: MassRate,
+}
+
+/// Accelerations acting on the spacecraft, for a given state and control
+struct SimAccelerations {
+    thrust: Vec3<Acceleration>,
+    aero: Vec3<Acceleration>,
+    gravity: Vec3<Acceleration>,
+    contact: Vec3<Acceleration>,
+    ang_acc: Vec3<AngularAcceleration>,
+}
+
+/// Contact of a point of the spacecraft with the terrain
+struct SimContact {
+    leg: bool,                      // landing leg footpad, or hull
+    clearance: Length,              // height above the terrain, negative when in contact
+    force: Vec3<f64>,               // N, BCI frame
+    torque: Vec3<f64>,              // N*m, body frame
+}
+
+/// Integrated state (BCI), and a few derived values (LVLH) for the plotter
+#[derive(Serialize)]
+struct SimStateReport {
+    t: Time,
+    state: SimState,
+    cross_range: Length,            // out-of-plane drift
+    track: Angle,                   // heading of the horizontal velocity
+    atm: Atmosphere,
+    mach: Ratio,
+    aoa: Angle,                     // angle of attack
+    gravity_err: Vec3<Acceleration>,    // truth minus the point mass gravity of the GNC, LVLH frame
+}
+
+
+impl Add for SimStateDerivative {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            vel: self.vel + other.vel,
+            acc: self.acc + other.acc,
+            att_rate: self.att_rate + other.att_rate,
+            ang_acc: self.ang_acc + other.ang_acc,
+            mass_rate: self.mass_rate + other.mass_rate,
+        }
+    }
+}
+
+
+impl Mul<f64> for SimStateDerivative {
+    type Output = Self;
+
+    fn mul(self, k: f64) -> Self {
+        Self {
+            vel: self.vel*k,
+            acc: self.acc*k,
+            att_rate: self.att_rate*k,
+            ang_acc: self.ang_acc*k,
+            mass_rate: self.mass_rate*k,
+        }
+    }
+}
+
+
+impl IntegrableState for SimState {
+    type Derivative = SimStateDerivative;
+
+    fn step(&self, d: SimStateDerivative, dt: Time) -> Self {
+        let dt_s = dt.get::<second>();
+
+        Self {
+            pos: self.pos + d.vel*dt,
+            vel: self.vel + d.acc*dt,
+            att: (self.att + d.att_rate*dt_s).normalize(),
+            ang_vel: Vec3::from_value(self.ang_vel.value() + d.ang_acc.value()*dt_s),
+            fuel_mass: self.fuel_mass + d.mass_rate*dt,
+        }
+    }
+
+    fn to_raw(&self) -> Vec<f64> {
+        let (pos, vel, ang_vel) = (self.pos.value(), self.vel.value(), self.ang_vel.value());
+        vec![
+            pos.x, pos.y, pos.z,
+            vel.x, vel.y, vel.z,
+            self.att.w, self.att.x, self.att.y, self.att.z,
+            ang_vel.x, ang_vel.y, ang_vel.z,
+            self.fuel_mass.value,
+        ]
+    }
 }
 
 
 impl Sim {
-    pub fn new(conf: Conf) -> Sim {
-        Sim {
+    /// Fails if the terrain can not be loaded (cf. Terrain::new())
+    pub fn new(conf: Conf) -> Result<Sim, String> {
+        let s = conf.s;
+
+        let t0 = Time::new::<second>(0.0);
+        let (pos, vel) = lvlh_to_bci(&s.body, t0, &Lvlh {pos: s.initial_sc_pos, vel: s.initial_sc_vel});
+        let att_lvlh = Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, s.initial_sc_ang_pos);
+
+        let state = SimState {
+            pos: pos,
+            vel: vel,
+            att: lvlh_to_bci_rotation(&s.body, t0, s.initial_sc_pos) * att_lvlh,
+            ang_vel: Vec3::from_value(Vec3::zero()),
+            fuel_mass: s.initial_sc_fuel_mass,
+        };
+
+        let mut sim = Sim {
             dt: conf.dt_step,
-            conf: conf.s,
-            cur: SpacecraftDynamic::new(&conf.s),
+            terrain: Terrain::new(&s.sim_terrain)?,
+            state: state,
+            cur: SpacecraftDynamic::new(&s),
+            conf: s,
             all: vec![],
+            airborne: false,
+            touchdown: None,
+            nav_errors: vec![],
+        };
+
+        // export the actual engine performance
+        sim.cur.thrust = sim.engine_thrust();
+        sim.cur.isp = sim.engine_isp();
+
+        Ok(sim)
+    }
+
+    /// True state, local frame
+    pub fn read_state(&self) -> StateVector {
+        StateVector {
+            pos: self.cur.pos,
+            vel: self.cur.vel,
+            att: self.cur.att,
         }
     }
 
     pub fn read_sensors(&self) -> SensorsValues {
         SensorsValues {
             dt_step: self.dt,
-            spacecraft_acc: self.cur.acc,
-            spacecraft_ang_acc: self.cur.ang_acc,
-            spacecraft_altitude: Some(self.cur.pos.y),
+            spacecraft_acc: Some(self.cur.acc),
+            spacecraft_ang_vel: self.cur.ang_vel,
+            spacecraft_altitude: Some(self.altitude_above_terrain()),
+            spacecraft_vel: Some(Vec3::from_value(self.cur.att.rotate_inv(self.cur.vel.value()))),
+            spacecraft_heading: None,
+            spacecraft_contact: self.touchdown.is_some(),
         }
     }
 
-    pub fn write_actuators(&mut self, control: ActuatorsValues) {
-        let dt = self.dt;
-        let sc_mass = self.conf.sc_dry_mass + self.cur.fuel_mass;
+    /// Navigation error (navigation minus truth), at the current time
+    ///
+    /// Both are compared after the step, at the same time. The navigation
+    /// integrates the sensors of the previous step: this lag is part of the
+    /// error.
+    pub fn check_nav(&mut self, nav: &SpacecraftDynamic) -> NavError {
+        let dq = nav.att * self.cur.att.conj();
+        let att_err = 2.0*Vec3 {x: dq.x, y: dq.y, z: dq.z}.norm().atan2(dq.w.abs());
+
+        let err = NavError {
+            t: self.cur.t,
+            pos: nav.pos - self.cur.pos,
+            vel: nav.vel - self.cur.vel,
+            att: Angle::new::<radian>(att_err),
+            fuel_mass: nav.fuel_mass - self.cur.fuel_mass,
+        };
+        self.nav_errors.push(err);
+
+        err
+    }
+
+    /// Navigation error over the flight, None before the first step
+    pub fn nav_report(&self) -> Option<NavReport> {
+        NavReport::new(&self.nav_errors)
+    }
+
+    /// Attitude (body to LVLH), to model the sensors in the body frame
+    pub fn attitude(&self) -> Quaternion {
+        self.cur.att
+    }
+
+    /// Acceleration not sensed by the accelerometers (LVLH frame): gravity,
+    /// less the centrifugal acc of the local frame (same model as the navigation)
+    pub fn gravity(&self) -> Vec3<Acceleration> {
+        Vec3 {
+            x: Acceleration::new::<meter_per_second_squared>(0.0),
+            y: self.cur.acc_gravity + self.cur.acc_centrifugal,
+            z: Acceleration::new::<meter_per_second_squared>(0.0),
+        }
+    }
 
-        // compute t, mass
+    /// Height above the terrain, along the local vertical
+    fn altitude_above_terrain(&self) -> Length {
+        self.cur.pos.y - self.terrain.height(self.cur.pos.x, self.cur.pos.z)
+    }
 
-        let t = self.cur.t + dt;
+    /// Actual full throttle thrust
+    fn engine_thrust(&self) -> Force {
+        match self.conf.sim_engine {
+            None => self.conf.sc_nominal_thrust,
+            Some(engine) => self.conf.sc_nominal_thrust*(1.0 + engine.thrust_error.get::<ratio>()),
+        }
+    }
 
-        let sc_fuel_mass = self.cur.fuel_mass - self.conf.sc_nominal_mass_flow*control.engine_throttle*dt;
+    /// Actual Isp
+    fn engine_isp(&self) -> Time {
+        match self.conf.sim_engine {
+            None => self.conf.sc_nominal_isp,
+            Some(engine) => self.conf.sc_nominal_isp*(1.0 + engine.isp_error.get::<ratio>()),
+        }
+    }
+
+    /// Actual full throttle mass flow
+    fn engine_mass_flow(&self) -> MassRate {
+        mass_flow(&self.conf, self.engine_thrust(), self.engine_isp())
+    }
+
+    /// Inertia tensor (body frame) for a given mass
+    ///
+    /// Cylinder, pitch and yaw use the same approximation as the previous 2D sim: 1/2*m*(h/2)**2
+    fn inertia_tensor(&self, sc_mass: Mass) -> Mat3 {
+        let i_roll = 0.5 * sc_mass * squared!(self.conf.sc_width/2.0);
+        let i_pitch = 0.5 * sc_mass * squared!(self.conf.sc_height/2.0);
+
+        Mat3::diag(i_roll.value, i_pitch.value, i_pitch.value)
+    }
 
-        // self.dv_flight += acc*dt
+    /// Compute all accelerations (translation and rotation) for a given state, at time t
+    fn accelerations(&self, t: Time, state: &SimState, control: &ActuatorsValues) -> SimAccelerations {
+        let sc_mass = self.conf.sc_dry_mass + state.fuel_mass;
+        let sc_thrust = control.engine_throttle*self.engine_thrust();
 
-        // compute torque and angular vel/pos
+        // compute torque and angular acc
+        //
+        // The engine is at -h/2 on the body x axis, and is gimbaled around
+        // body z (pitch) and body y (yaw).
+        // Torque = r*F = (0, h/2*F_z, -h/2*F_y)
+        // The RCS only produces a torque around the body x axis (roll).
 
-        let sc_moment_of_inertia = 0.5 * sc_mass * squared!(self.conf.sc_height/2.0);  // 1/2*m*r**2 = kg.m**2
         let alpha: Angle = (control.engine_gimbal*self.conf.ctr_eng_gimbal_pos_max).into();
-        let torque = (
-            self.conf.sc_height/2.0
-            * control.engine_throttle*self.conf.sc_nominal_thrust
-            * alpha.sin()
+        let beta: Angle = (control.engine_gimbal_yaw*self.conf.ctr_eng_gimbal_pos_max).into();
+
+        let thrust_vunit_body = Vec3 {
+            x: alpha.cos().value*beta.cos().value,
+            y: -alpha.sin().value,
+            z: alpha.cos().value*beta.sin().value,
+        };
+
+        // aerodynamics, the atmosphere rotates with the body (around -Z)
+
+        let body_rate = Vec3 {x: 0.0, y: 0.0, z: -self.conf.body.rotation_rate().get::<radian_per_second>()};
+        let vel_air: Vec3<Velocity> = state.vel - Vec3::from_value(body_rate.cross(&state.pos.value()));
+        let altitude: Length = state.pos.norm() - self.conf.body.radius;
+        let atm = self.conf.body.atmosphere(altitude);
+        let aero = aero(&self.conf, &atm, Vec3::from_value(state.att.rotate_inv(vel_air.value())));
+
+        let lever: Torque = (self.conf.sc_height/2.0*sc_thrust).into();
+        let rcs: Torque = (control.rcs_roll*self.conf.sc_rcs_roll_torque).into();
+        let torque = Vec3 {
+            x: rcs.value,
+            y: lever.value*thrust_vunit_body.z,
+            z: -lever.value*thrust_vunit_body.y,
+        } + aero.torque.value();
+
+        // contacts with the terrain
+
+        let contacts = self.contacts(t, state);
+        let contact_force = contacts.iter().fold(Vec3::zero(), |acc, c| acc + c.force);
+        let torque = contacts.iter().fold(torque, |acc, c| acc + c.torque);
+
+        // Euler's equation: I*dw/dt = torque - w*(I*w)
+        let inertia = self.inertia_tensor(sc_mass);
+        let w = state.ang_vel.value();
+        let sc_ang_acc = inertia.inverse().mul_vec(torque - w.cross(&inertia.mul_vec(w)));
+
+        // compute thrust and aerodynamic forces
+
+        let engine_acc_norm: Acceleration = sc_thrust/sc_mass;
+        let engine_acc = state.att.rotate(thrust_vunit_body).map(|k| k*engine_acc_norm);
+
+        let aero_acc: Vec3<Acceleration> = Vec3::from_value(
+            state.att.rotate((aero.drag + aero.lift).value())*(1.0/sc_mass.value)
         );
-        let sc_ang_acc: AngularAcceleration = (torque/sc_moment_of_inertia).into();
 
-        let dav: AngularVelocity = (sc_ang_acc*dt).into();
-        let sc_ang_vel = self.cur.ang_vel + dav;
-        let dap: Angle = (sc_ang_vel*dt).into();
-        let sc_ang_pos = self.cur.ang_pos + dap;
+        // compute gravity (the centrifugal acc is a consequence of the inertial frame)
 
-        // compute thrust and aerodynamic drags
+        SimAccelerations {
+            thrust: engine_acc,
+            aero: aero_acc,
+            gravity: self.conf.body.gravity_bci(t, state.pos),
+            contact: Vec3::from_value(contact_force*(1.0/sc_mass.value)),
+            ang_acc: Vec3::from_value(sc_ang_acc),
+        }
+    }
 
-        let ang_vunit = Vec2 {
-            x: Ratio::new::<ratio>(sc_ang_pos.cos().value),
-            y: Ratio::new::<ratio>(sc_ang_pos.sin().value),
-        };
+    /// Contact points (body frame, m): the landing legs footpads, then the
+    /// hull (top and bottom rims of the cylinder)
+    fn contact_points(&self) -> Vec<(Vec3<f64>, bool)> {
+        let leg_count = self.conf.sc_leg_count;
+        let leg_radius = self.conf.sc_leg_radius.get::<meter>();
+        let leg_length = self.conf.sc_leg_length.get::<meter>();
+        let half_height = (self.conf.sc_height/2.0).get::<meter>();
+        let half_width = (self.conf.sc_width/2.0).get::<meter>();
 
-        let engine_acc_norm = control.engine_throttle * self.conf.sc_nominal_thrust/sc_mass;
-        let engine_acc = mul!(ang_vunit, engine_acc_norm);
+        let mut points = vec![];
 
-        // dynamic pressure q: Pa = Kg/(m*s**2)
-        // dynamic pressure n: N = Kg/(m*s**2) * m**2 = Kg*m/(s**2)
-        let vel: Velocity = Velocity::new::<meter_per_second>(norm!(self.cur.vel));
-        let dp_q: Pressure = 0.5 * self.conf.body.atmosphere_density(self.cur.pos.y) * squared!(vel);
-        let dp_n: Force = dp_q * (PI*squared!(self.conf.sc_width/2.0)) * self.conf.sc_cd;
-        let dp_drag = mul!(ang_vunit, -dp_n/sc_mass);
+        for i in 0..leg_count {
+            let a = 2.0*PI*(i as f64)/(leg_count as f64);
+            points.push((Vec3 {x: -leg_length, y: leg_radius*a.cos(), z: leg_radius*a.sin()}, true));
+        }
 
-        // compute gravity/centrifugal
+        for x in [-half_height, half_height].iter() {
+            for i in 0..4 {
+                let a = PI/4.0 + PI/2.0*(i as f64);
+                points.push((Vec3 {x: *x, y: half_width*a.cos(), z: half_width*a.sin()}, false));
+            }
+        }
 
-        let vertical_vunit = Vec2 {
-            x: Ratio::new::<ratio>(0.0),
-            y: Ratio::new::<ratio>(1.0),
-        };
+        points
+    }
 
-        let gravity_acc = mul!(vertical_vunit, -self.conf.body.gravity(self.cur.pos.y));
-        let centrifugal_acc = mul!(vertical_vunit, self.conf.body.centrifugal(self.cur.vel.x, self.cur.pos.y));
+    /// Distance from the center of mass to the farthest contact point
+    fn contact_reach(&self) -> Length {
+        let reach = self.contact_points().iter().map(|(p, _leg)| p.norm()).fold(0.0, f64::max);
+        Length::new::<meter>(reach)
+    }
 
-        // compute acc/vel/pos
+    /// Contact forces with the terrain, for a given state, at time t
+    ///
+    /// Spring-damper along the terrain normal, and Coulomb friction (cf.
+    /// CONTACT_FRICTION_VEL) opposed to the sliding velocity.
+    /// Empty when the spacecraft is too high to touch the terrain.
+    fn contacts(&self, t: Time, state: &SimState) -> Vec<SimContact> {
+        let body = &self.conf.body;
+        let w = state.ang_vel.value();
+
+        let com = bci_to_lvlh(body, t, state.pos, state.vel);
+        if com.pos.y - self.terrain.height(com.pos.x, com.pos.z) > self.contact_reach() {
+            return vec![];
+        }
 
-        let sc_acc: Vec2<Acceleration> = engine_acc + dp_drag + gravity_acc + centrifugal_acc;
-        // self.g = self.acc_y/G0
+        self.contact_points().iter().map(|(point, leg)| {
+            let pos = state.pos + Vec3::from_value(state.att.rotate(*point));
+            let vel = state.vel + Vec3::from_value(state.att.rotate(w.cross(point)));
+            let lvlh = bci_to_lvlh(body, t, pos, vel);
+
+            let clearance = lvlh.pos.y - self.terrain.height(lvlh.pos.x, lvlh.pos.z);
+
+            let force_lvlh = if clearance.value < 0.0 {
+                let normal = self.terrain.normal(lvlh.pos.x, lvlh.pos.z);
+                let depth = -clearance.get::<meter>()*normal.y;
+                let v = lvlh.vel.value();
+                let v_normal = v.dot(&normal);
+                let v_tangent = v - normal*v_normal;
+
+                let f_normal = (self.conf.sc_leg_stiffness*depth - self.conf.sc_leg_damping*v_normal).max(0.0);
+                let f_friction = self.conf.sc_leg_friction.get::<ratio>()*f_normal;
+
+                normal*f_normal - v_tangent*(f_friction/v_tangent.norm().max(CONTACT_FRICTION_VEL))
+            } else {
+                Vec3::zero()
+            };
+
+            let force = lvlh_to_bci_rotation(body, t, lvlh.pos).rotate(force_lvlh);
+
+            SimContact {
+                leg: *leg,
+                clearance: clearance,
+                force: force,
+                torque: point.cross(&state.att.rotate_inv(force)),
+            }
+        }).collect()
+    }
 
-        let sc_vel = self.cur.vel + mul!(sc_acc, dt);
-        let sc_pos = self.cur.pos + mul!(sc_vel, dt);
+    /// State derivative, for the integrator
+    fn derivative(&self, t: Time, state: &SimState, control: &ActuatorsValues) -> SimStateDerivative {
+        let a = self.accelerations(t, state, control);
+
+        SimStateDerivative {
+            vel: state.vel,
+            acc: a.thrust + a.aero + a.gravity + a.contact,
+            att_rate: state.att.derivative(state.ang_vel.value()),
+            ang_acc: a.ang_acc,
+            mass_rate: -self.engine_mass_flow()*control.engine_throttle,
+        }
+    }
+
+    /// Propagate the state by dt (control is constant during dt)
+    pub fn write_actuators(&mut self, control: ActuatorsValues) {
+        let dt = self.dt;
+        let prev = self.state;
+
+        let clearance = self.altitude_above_terrain() - self.contact_reach();
+        let near_ground = clearance < self.cur.vel.norm()*dt + Length::new::<meter>(1.0);
+        let substeps = if near_ground { CONTACT_SUBSTEPS } else { 1 };
+
+        let mut next = prev;
+        for i in 0..substeps {
+            next = integrate(
+                self.conf.sim_integrator,
+                self.conf.sim_integrator_tol,
+                |t, s: &SimState| self.derivative(t, s, &control),
+                self.cur.t + dt*(i as f64)/(substeps as f64),
+                next,
+                dt/(substeps as f64),
+            );
+        }
+
+        // convert to the local frame
+        // accelerations are averaged over dt, like the delta-v of a real IMU
+
+        let body = &self.conf.body;
+        let prev_lvlh = bci_to_lvlh(body, self.cur.t, prev.pos, prev.vel);
+        let next_lvlh = bci_to_lvlh(body, self.cur.t+dt, next.pos, next.vel);
+        let att_lvlh = lvlh_to_bci_rotation(body, self.cur.t+dt, next_lvlh.pos).conj() * next.att;
+        let vel_inertial = inertial_velocity(body, &next_lvlh);
+
+        let sc_acc: Vec3<Acceleration> = (next_lvlh.vel - prev_lvlh.vel)/dt;
+        let sc_ang_acc: Vec3<AngularAcceleration> = (next.ang_vel - prev.ang_vel).map(|w| (w/dt).into());
+
+        let a = self.accelerations(self.cur.t+dt, &next, &control);
+
+        let (heading, pitch, roll) = att_lvlh.to_euler();
 
         // save everything
 
-        self.cur.t = t;
+        self.state = next;
+
+        self.cur.t += dt;
         self.cur.dt = dt;
 
         self.cur.eng_throttle = control.engine_throttle;
-        self.cur.fuel_mass = sc_fuel_mass;
+        self.cur.fuel_mass = next.fuel_mass;
         self.cur.eng_gimbal = control.engine_gimbal;
+        self.cur.eng_gimbal_yaw = control.engine_gimbal_yaw;
+        self.cur.rcs_roll = control.rcs_roll;
 
-        self.cur.acc_thrust = Acceleration::new::<meter_per_second_squared>(norm!(engine_acc));
-        self.cur.acc_atm = -Acceleration::new::<meter_per_second_squared>(norm!(dp_drag));
-        self.cur.acc_gravity = -Acceleration::new::<meter_per_second_squared>(norm!(gravity_acc));
-        self.cur.acc_centrifugal = Acceleration::new::<meter_per_second_squared>(norm!(centrifugal_acc));
+        self.cur.acc_thrust = a.thrust.norm();
+        self.cur.acc_atm = -a.aero.norm();
+        self.cur.acc_gravity = -a.gravity.norm();
+        self.cur.acc_centrifugal = self.conf.body.centrifugal(vel_inertial.horizontal_norm(), next_lvlh.pos.y);
 
-        self.cur.pos = sc_pos;
-        self.cur.vel = sc_vel;
+        self.cur.pos = next_lvlh.pos;
+        self.cur.vel = next_lvlh.vel;
+        self.cur.vel_inertial = vel_inertial;
         self.cur.acc = sc_acc;
 
-        self.cur.ang_pos = sc_ang_pos;
-        self.cur.ang_vel = sc_ang_vel;
+        self.cur.att = att_lvlh;
+        self.cur.ang_pos = pitch;
+        self.cur.heading = heading;
+        self.cur.roll = roll;
+        self.cur.ang_vel = next.ang_vel;
         self.cur.ang_acc = sc_ang_acc;
 
         self.all.push(self.cur);
+
+        self.update_touchdown();
+    }
+
+    /// Track the touchdown: first contact of the legs, peak loads, bounce and attitude
+    fn update_touchdown(&mut self) {
+        let contacts = self.contacts(self.cur.t, &self.state);
+        let legs: Vec<&SimContact> = contacts.iter().filter(|c| c.leg).collect();
+        let clearance = if legs.is_empty() {
+            self.altitude_above_terrain() - self.conf.sc_leg_length
+        } else {
+            legs.iter().map(|c| c.clearance).fold(Length::new::<meter>(f64::INFINITY), |a, b| a.min(b))
+        };
+
+        if self.touchdown.is_none() {
+            // starting on the ground (takeoff): only a contact after lift-off is a touchdown
+            if clearance > Length::new::<meter>(1.0) {
+                self.airborne = true;
+            }
+            if !self.airborne || clearance.value > 0.0 {
+                return;
+            }
+
+            let slope = self.terrain.slope(self.cur.pos.x, self.cur.pos.z);
+            self.touchdown = Some(Touchdown {
+                t: self.cur.t,
+                pos: self.cur.pos,
+                vel: self.cur.vel,
+                slope: slope,
+                safe: slope <= self.terrain.max_slope(),
+                leg_loads: vec![Force::new::<newton>(0.0); legs.len()],
+                bounce: Length::new::<meter>(0.0),
+                t_rest: None,
+                heading: self.cur.heading,
+                pitch: self.cur.ang_pos,
+                roll: self.cur.roll,
+                tilt: Angle::new::<radian>(0.0),
+                tipped_over: false,
+            });
+            println!("[LOGD:Sim::update_touchdown] first contact, t={:.3}", self.cur.t.get::<second>());
+        }
+
+        // the spacecraft tips over once its center of mass is beyond the
+        // footprint of the legs (edge between two legs)
+        let leg_count = self.conf.sc_leg_count as f64;
+        let tilt_max = (self.conf.sc_leg_radius*(PI/leg_count).cos()/self.conf.sc_leg_length).value.atan();
+
+        let normal = self.terrain.normal(self.cur.pos.x, self.cur.pos.z);
+        let axis = self.cur.att.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0});
+        let tilt = axis.dot(&normal).clamp(-1.0, 1.0).acos();
+
+        let touchdown = self.touchdown.as_mut().unwrap();
+        for (load, leg) in touchdown.leg_loads.iter_mut().zip(legs.iter()) {
+            *load = load.max(Force::new::<newton>(leg.force.norm()));
+        }
+        touchdown.bounce = touchdown.bounce.max(clearance);
+        touchdown.heading = self.cur.heading;
+        touchdown.pitch = self.cur.ang_pos;
+        touchdown.roll = self.cur.roll;
+        touchdown.tilt = Angle::new::<radian>(tilt);
+        touchdown.tipped_over = tilt > tilt_max;
+    }
+
+    /// Engine off, run until the spacecraft is at rest on the ground (or timeout)
+    pub fn settle(&mut self) -> Option<Touchdown> {
+        let timeout = self.cur.t + Time::new::<second>(SETTLE_TIMEOUT);
+
+        while self.cur.t < timeout {
+            self.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.0),
+                engine_gimbal: Ratio::new::<ratio>(0.0),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+            self.export_to_csv_cur();
+
+            let at_rest = (
+                (self.cur.vel.norm() < Velocity::new::<meter_per_second>(SETTLE_VEL))
+                && (self.cur.ang_vel.norm() < AngularVelocity::new::<degree_per_second>(SETTLE_ANG_VEL))
+            );
+
+            if let Some(touchdown) = self.touchdown.as_mut() {
+                if at_rest && (self.cur.t - touchdown.t > Time::new::<second>(SETTLE_MIN_TIME)) {
+                    touchdown.t_rest = Some(self.cur.t);
+                    break;
+                }
+            }
+        }
+
+        self.touchdown.clone()
+    }
+
+    /// Difference between the simulated gravity (J2, third body) and the
+    /// point mass gravity assumed by the GNC, in the LVLH frame
+    fn gravity_err(&self, t: Time, state: &SimState) -> Vec3<Acceleration> {
+        let body = &self.conf.body;
+        let lvlh = bci_to_lvlh(body, t, state.pos, state.vel);
+        let rot = lvlh_to_bci_rotation(body, t, lvlh.pos);
+
+        let truth = rot.rotate_inv(body.gravity_bci(t, state.pos).value());
+        let simplified = Vec3 {x: 0.0, y: -body.gravity(lvlh.pos.y).value, z: 0.0};
+
+        Vec3::from_value(truth - simplified)
     }
 
     pub fn export_to_csv_conf(&self) {
@@ -133,5 +662,355 @@ impl Sim {
 
     pub fn export_to_csv_cur(&self) {
         println!("[LOGD:Sim::export_to_csv_cur] CSV={}", serde_json::to_string(&self.cur).unwrap());
+
+        let atm = self.conf.body.atmosphere(self.cur.pos.y);
+        let aero = aero(&self.conf, &atm, Vec3::from_value(self.cur.att.rotate_inv(self.cur.vel.value())));
+        let gravity_err = self.gravity_err(self.cur.t, &self.state);
+
+        let report = SimStateReport {
+            t: self.cur.t,
+            state: self.state,
+            cross_range: self.cur.pos.z,
+            track: Angle::new::<radian>(self.cur.vel.z.value.atan2(self.cur.vel.x.value)),
+            atm: atm,
+            mach: aero.mach,
+            aoa: aero.aoa,
+            gravity_err: gravity_err,
+        };
+        println!("[LOGD:Sim::export_to_csv_state] CSV={}", serde_json::to_string(&report).unwrap());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::acceleration::meter_per_second_squared;
+    use uom::si::angle::degree;
+    use uom::si::ratio::ratio;
+    use uom::si::velocity::meter_per_second;
+
+    use crate::assert_approx_eq;
+    use crate::conf::{SimIntegrator, SubCommand};
+    use crate::utils::frames::surface_velocity;
+    use crate::utils::terrain::Crater;
+
+    fn sim_new(integrator: SimIntegrator, dt: f64) -> Sim {
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let mut scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.sim_integrator = integrator;
+
+        let conf = Conf::new(SubCommand::Sim, Time::new::<second>(dt), Time::new::<second>(0.0), scenario);
+        Sim::new(conf).unwrap()
+    }
+
+    /// Fly the Apollo descent for 100 sec with a fixed control, return the final state
+    fn propagate(integrator: SimIntegrator, dt: f64) -> SpacecraftDynamic {
+        let mut sim = sim_new(integrator, dt);
+
+        let steps = (100.0/dt).round() as usize;
+        for _ in 0..steps {
+            sim.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.8),
+                engine_gimbal: Ratio::new::<ratio>(0.1),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+        }
+
+        sim.cur
+    }
+
+    #[test]
+    fn test_sim_1_rk4_convergence() {
+        let coarse = propagate(SimIntegrator::SimRk4, 0.2);
+        let fine = propagate(SimIntegrator::SimRk4, 0.1);
+        let finer = propagate(SimIntegrator::SimRk4, 0.05);
+
+        // trajectories agree across dt
+        assert!((coarse.pos.x-finer.pos.x).abs() < Length::new::<meter>(0.1));
+        assert!((coarse.pos.y-finer.pos.y).abs() < Length::new::<meter>(0.1));
+        assert!((fine.pos.x-finer.pos.x).abs() < Length::new::<meter>(0.01));
+        assert!((fine.pos.y-finer.pos.y).abs() < Length::new::<meter>(0.01));
+
+        // and converge
+        assert!((fine.pos.x-finer.pos.x).abs() < (coarse.pos.x-finer.pos.x).abs());
+        assert!((fine.pos.y-finer.pos.y).abs() < (coarse.pos.y-finer.pos.y).abs());
+    }
+
+    #[test]
+    fn test_sim_2_integrators_agree() {
+        let rk4 = propagate(SimIntegrator::SimRk4, 0.1);
+        let dp = propagate(SimIntegrator::SimDormandPrince, 0.1);
+        let euler = propagate(SimIntegrator::SimEuler, 0.1);
+
+        assert!((rk4.pos.x-dp.pos.x).abs() < Length::new::<meter>(0.01));
+        assert!((rk4.pos.y-dp.pos.y).abs() < Length::new::<meter>(0.01));
+
+        // Euler is first order, hence depends on dt
+        assert!((rk4.pos.y-euler.pos.y).abs() > Length::new::<meter>(0.01));
+    }
+
+    #[test]
+    fn test_sim_3_in_plane() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+
+        for _ in 0..100 {
+            sim.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.8),
+                engine_gimbal: Ratio::new::<ratio>(0.5),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+        }
+
+        // no out-of-plane motion
+        let (heading, pitch, roll) = sim.cur.att.to_euler();
+        assert_eq!(sim.state.pos.z, Length::new::<meter>(0.0));
+        assert_eq!(sim.state.vel.z, Velocity::new::<meter_per_second>(0.0));
+        assert!(heading.abs() < Angle::new::<degree>(1e-9));
+        assert!(roll.abs() < Angle::new::<degree>(1e-9));
+
+        // pitch gimbal only produces pitch
+        assert_eq!(pitch, sim.cur.ang_pos);
+        assert!(sim.state.ang_vel.z.value > 0.0);
+        assert_eq!(sim.state.ang_vel.y.value, 0.0);
+
+        // thrust is in the plane too
+        assert!(sim.cur.acc_thrust > Acceleration::new::<meter_per_second_squared>(0.0));
+    }
+
+    #[test]
+    fn test_sim_4_yaw_gimbal() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+
+        for _ in 0..100 {
+            sim.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.8),
+                engine_gimbal: Ratio::new::<ratio>(0.0),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.5),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+        }
+
+        // yaw gimbal produces a rotation around body y, hence a heading change
+        let (heading, _pitch, _roll) = sim.cur.att.to_euler();
+        assert!(sim.state.ang_vel.y.value > 0.0);
+        assert!(sim.state.ang_vel.z.value.abs() < 1e-12);
+        assert!(heading.abs() > Angle::new::<degree>(1.0));
+
+        // and the thrust pushes the spacecraft out of the plane
+        assert!(sim.state.pos.z.abs() > Length::new::<meter>(1.0));
+        assert!(sim.state.vel.z.abs() > Velocity::new::<meter_per_second>(0.1));
+    }
+
+    #[test]
+    fn test_sim_5_rcs_roll() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+
+        for _ in 0..10 {
+            sim.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.8),
+                engine_gimbal: Ratio::new::<ratio>(0.0),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(1.0),
+            });
+        }
+
+        // RCS only rolls the spacecraft around its thrust axis
+        assert!(sim.cur.ang_vel.x.value > 0.0);
+        assert!(sim.cur.ang_vel.y.value.abs() < 1e-12);
+        assert!(sim.cur.ang_vel.z.value.abs() < 1e-12);
+        assert!(sim.cur.roll > Angle::new::<degree>(0.0));
+        assert!(sim.state.pos.z.abs() < Length::new::<meter>(1e-6));
+    }
+
+    #[test]
+    fn test_sim_6_circular_orbit() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+
+        // point mass
+        sim.conf.body.j2 = 0.0;
+        sim.conf.body.third_body = None;
+
+        // circular velocity at the initial altitude, engine off
+        let r = sim.conf.body.radius + sim.conf.initial_sc_pos.y;
+        let vel = Velocity::new::<meter_per_second>((sim.conf.body.mu/r.value).sqrt());
+        let vel_surface = vel - surface_velocity(&sim.conf.body, sim.conf.initial_sc_pos).x;
+        let lvlh = Lvlh {
+            pos: sim.conf.initial_sc_pos,
+            vel: Vec3 {x: vel_surface, y: Velocity::new::<meter_per_second>(0.0), z: Velocity::new::<meter_per_second>(0.0)},
+        };
+        let (pos, vel) = lvlh_to_bci(&sim.conf.body, Time::new::<second>(0.0), &lvlh);
+        sim.state.pos = pos;
+        sim.state.vel = vel;
+
+        for _ in 0..1000 {
+            sim.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.0),
+                engine_gimbal: Ratio::new::<ratio>(0.0),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+        }
+
+        // gravity and centrifugal acc cancel out, without any explicit centrifugal term
+        assert!((sim.cur.pos.y - sim.conf.initial_sc_pos.y).abs() < Length::new::<meter>(0.01));
+        assert!(sim.cur.vel.y.abs() < Velocity::new::<meter_per_second>(1e-3));
+        assert!(sim.cur.acc.y.abs() < Acceleration::new::<meter_per_second_squared>(1e-3));
+        assert!(sim.cur.pos.x > sim.conf.initial_sc_pos.x + Length::new::<meter>(100_000.0));
+    }
+
+    #[test]
+    fn test_sim_7_gravity_err() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+
+        // point mass: the sim and the GNC agree
+        sim.conf.body.j2 = 0.0;
+        sim.conf.body.third_body = None;
+        let err = sim.gravity_err(Time::new::<second>(0.0), &sim.state);
+        assert!(err.norm() < Acceleration::new::<meter_per_second_squared>(1e-12));
+
+        // J2, at the equator: stronger gravity (down), no horizontal component
+        sim.conf.body.j2 = 2.033e-4;
+        let err = sim.gravity_err(Time::new::<second>(0.0), &sim.state);
+        let g = sim.conf.body.gravity(sim.cur.pos.y);
+        let k = (sim.conf.body.radius/(sim.conf.body.radius + sim.conf.initial_sc_pos.y)).value;
+        assert_approx_eq!(err.y, -g*1.5*2.033e-4*k*k, Acceleration::new::<meter_per_second_squared>(1e-9));
+        assert!(err.x.abs() < Acceleration::new::<meter_per_second_squared>(1e-9));
+        assert!(err.z.abs() < Acceleration::new::<meter_per_second_squared>(1e-9));
+    }
+
+    #[test]
+    fn test_sim_8_terrain() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+
+        // 10 m above the slope of a crater, falling
+        let mut terrain = sim.conf.sim_terrain.clone().unwrap();
+        terrain.slope_z = Ratio::new::<ratio>(0.0);
+        terrain.craters = vec![Crater {
+            x: Length::new::<meter>(100.0),
+            z: Length::new::<meter>(0.0),
+            radius: Length::new::<meter>(100.0),
+            depth: Length::new::<meter>(50.0),
+            rim: Length::new::<meter>(5.0),
+        }];
+        sim.terrain = Terrain::new(&Some(terrain)).unwrap();
+
+        let ground = sim.terrain.height(Length::new::<meter>(20.0), Length::new::<meter>(0.0));
+        let leg_length = sim.conf.sc_leg_length;
+        place_upright(&mut sim, 20.0, 10.0 + leg_length.value, 0.0, -5.0);
+
+        // the radar altimeter measures the height above the terrain
+        assert_approx_eq!(sim.read_sensors().spacecraft_altitude.unwrap(), Length::new::<meter>(10.0) + leg_length, Length::new::<meter>(1e-9));
+
+        for _ in 0..30 {
+            sim.write_actuators(ActuatorsValues {
+                engine_throttle: Ratio::new::<ratio>(0.0),
+                engine_gimbal: Ratio::new::<ratio>(0.0),
+                engine_gimbal_yaw: Ratio::new::<ratio>(0.0),
+                rcs_roll: Ratio::new::<ratio>(0.0),
+            });
+        }
+
+        // about 1.6 s to fall 10 m (less for the uphill leg); the crater wall is too steep
+        let touchdown = sim.touchdown.unwrap();
+        assert!(Time::new::<second>(1.2) < touchdown.t && touchdown.t < Time::new::<second>(1.7));
+        assert!(touchdown.pos.y > ground);
+        assert!(touchdown.slope > Angle::new::<degree>(12.0));
+        assert!(!touchdown.safe);
+    }
+
+    #[test]
+    fn test_sim_9_soft_landing() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+        sim.terrain = Terrain::new(&None).unwrap();
+
+        // 2 m above the ground (feet), falling at 2 m/s
+        let leg_length = sim.conf.sc_leg_length;
+        place_upright(&mut sim, 0.0, 2.0 + leg_length.value, 0.0, -2.0);
+        assert!(!sim.read_sensors().spacecraft_contact);
+
+        let touchdown = sim.settle().unwrap();
+        assert!(sim.read_sensors().spacecraft_contact);
+        assert!(touchdown.t_rest.is_some());
+        assert!(!touchdown.tipped_over);
+        assert!(touchdown.tilt < Angle::new::<degree>(1.0));
+        assert!(touchdown.safe);
+
+        // vertical: the legs share the load
+        assert_eq!(touchdown.leg_loads.len(), sim.conf.sc_leg_count);
+        let max = touchdown.leg_loads.iter().fold(0.0, |acc: f64, f| acc.max(f.value));
+        let min = touchdown.leg_loads.iter().fold(f64::INFINITY, |acc: f64, f| acc.min(f.value));
+        assert!(min > 0.0);
+        assert!(max/min < 1.1);
+
+        // resting on the legs
+        assert_approx_eq!(sim.cur.pos.y, leg_length, Length::new::<meter>(0.5));
+    }
+
+    #[test]
+    fn test_sim_10_tip_over() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+        let mut terrain = sim.conf.sim_terrain.clone().unwrap();
+        terrain.slope_x = Ratio::new::<ratio>(-0.5);
+        terrain.slope_z = Ratio::new::<ratio>(0.0);
+        terrain.craters = vec![];
+        sim.terrain = Terrain::new(&Some(terrain)).unwrap();
+
+        // steep slope, moving fast downhill
+        let leg_length = sim.conf.sc_leg_length;
+        place_upright(&mut sim, 0.0, 1.0 + leg_length.value, 4.0, -2.0);
+
+        let touchdown = sim.settle().unwrap();
+        assert!(!touchdown.safe);
+        assert!(touchdown.tipped_over);
+    }
+
+    /// Place the spacecraft upright, at a height above the terrain (LVLH x, z=0)
+    fn place_upright(sim: &mut Sim, x: f64, height: f64, vel_x: f64, vel_y: f64) {
+        let t0 = Time::new::<second>(0.0);
+        let x = Length::new::<meter>(x);
+        let ground = sim.terrain.height(x, Length::new::<meter>(0.0));
+        let lvlh = Lvlh {
+            pos: Vec3 {x: x, y: ground + Length::new::<meter>(height), z: Length::new::<meter>(0.0)},
+            vel: Vec3 {x: Velocity::new::<meter_per_second>(vel_x), y: Velocity::new::<meter_per_second>(vel_y), z: Velocity::new::<meter_per_second>(0.0)},
+        };
+        let (pos, vel) = lvlh_to_bci(&sim.conf.body, t0, &lvlh);
+        let att_lvlh = Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(90.0));
+
+        sim.state.pos = pos;
+        sim.state.vel = vel;
+        sim.state.att = lvlh_to_bci_rotation(&sim.conf.body, t0, lvlh.pos) * att_lvlh;
+        sim.cur.pos = lvlh.pos;
+        sim.cur.att = att_lvlh;
+    }
+
+    #[test]
+    fn test_sim_11_nav_error() {
+        let mut sim = sim_new(SimIntegrator::SimRk4, 0.1);
+        assert!(sim.nav_report().is_none());
+
+        // navigation 3 m and 0.4 m/s off, then 4 m
+        let mut nav = sim.cur;
+        nav.pos.y += Length::new::<meter>(3.0);
+        nav.vel.x += Velocity::new::<meter_per_second>(0.4);
+        let err = sim.check_nav(&nav);
+        assert_approx_eq!(err.pos.y, Length::new::<meter>(3.0), Length::new::<meter>(1e-9));
+        assert_eq!(err.att, Angle::new::<radian>(0.0));
+
+        nav.pos.y += Length::new::<meter>(1.0);
+        nav.att = Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(1.0)) * nav.att;
+        sim.check_nav(&nav);
+
+        let report = sim.nav_report().unwrap();
+        assert_eq!(report.steps, 2);
+        assert_approx_eq!(report.pos.rms.get::<meter>(), 12.5f64.sqrt(), 1e-9);
+        assert_approx_eq!(report.pos.max.get::<meter>(), 4.0, 1e-9);
+        assert_approx_eq!(report.pos.last.get::<meter>(), 4.0, 1e-9);
+        assert_approx_eq!(report.vel.last.get::<meter_per_second>(), 0.4, 1e-9);
+        assert_approx_eq!(report.att.max.get::<degree>(), 1.0, 1e-9);
+        assert_eq!(report.fuel_mass.max.value, 0.0);
     }
 }
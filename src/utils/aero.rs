@@ -0,0 +1,192 @@
+use std::f64::consts::PI;
+
+use uom::si::f64::*;
+use uom::si::angle::{degree, radian};
+use uom::si::force::newton;
+use uom::si::ratio::ratio;
+use uom::si::torque::newton_meter;
+
+use crate::squared;
+use crate::conf::Scenario;
+use crate::utils::atmosphere::Atmosphere;
+use crate::utils::math::Vec3;
+
+
+/// Aerodynamic forces and torque, in the body frame (x is the thrust axis)
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Aero {
+    pub drag: Vec3<Force>,          // opposite to the airspeed
+    pub lift: Vec3<Force>,          // perpendicular to the airspeed
+    pub torque: Vec3<Torque>,       // around the center of mass
+    pub mach: Ratio,
+    pub aoa: Angle,                 // angle of attack, between the body x axis and the airspeed
+}
+
+
+// Cd multiplier (applied to sc_cd) of a blunt cylinder
+// Transonic drag rise, and a slightly lower drag side-on.
+const CD_MACH: [f64; 6] = [0.0, 0.8, 1.0, 1.2, 2.0, 5.0];
+const CD_AOA: [f64; 3] = [0.0, 90.0, 180.0];  // deg
+const CD_TABLE: [[f64; 3]; 6] = [
+    [1.00, 1.00, 1.00],
+    [1.10, 1.05, 1.10],
+    [1.50, 1.30, 1.50],
+    [1.60, 1.35, 1.60],
+    [1.30, 1.20, 1.30],
+    [1.10, 1.10, 1.10],
+];
+
+
+/// Drag coefficient, bilinear interpolation of the table (clamped)
+pub fn cd(conf: &Scenario, mach: Ratio, aoa: Angle) -> Ratio {
+    let (i, ki) = table_index(&CD_MACH, mach.get::<ratio>());
+    let (j, kj) = table_index(&CD_AOA, aoa.get::<degree>());
+
+    let c0 = CD_TABLE[i][j]*(1.0-kj) + CD_TABLE[i][j+1]*kj;
+    let c1 = CD_TABLE[i+1][j]*(1.0-kj) + CD_TABLE[i+1][j+1]*kj;
+
+    conf.sc_cd * (c0*(1.0-ki) + c1*ki)
+}
+
+
+/// Returns the index of the lower breakpoint, and the interpolation factor
+fn table_index(breakpoints: &[f64], val: f64) -> (usize, f64) {
+    let last = breakpoints.len()-2;
+    let i = breakpoints[..=last].iter().rposition(|b| *b <= val).unwrap_or(0);
+    let k = (val-breakpoints[i])/(breakpoints[i+1]-breakpoints[i]);
+
+    (i, k.clamp(0.0, 1.0))
+}
+
+
+/// Aerodynamic model of the spacecraft (cylinder)
+///
+/// Inputs:
+///     atm: atmosphere at the spacecraft altitude
+///     vel_air: airspeed (velocity relative to the atmosphere), in the body frame
+///
+/// drag = q * projected area * Cd(Mach, AoA)
+/// lift: cross-flow normal force (q * side area * sc_cd * sin(aoa)**2), minus
+///     its component along the airspeed (already in the drag)
+/// torque: the aero forces apply at the center of pressure, at sc_cp_offset on
+///     the body x axis
+pub fn aero(conf: &Scenario, atm: &Atmosphere, vel_air: Vec3<Velocity>) -> Aero {
+    let zero = Vec3::from_value(Vec3::zero());
+
+    let speed: Velocity = vel_air.norm();
+    if speed.value == 0.0 || atm.density.value == 0.0 {
+        return Aero {
+            drag: zero,
+            lift: zero,
+            torque: Vec3::zero().map(Torque::new::<newton_meter>),
+            mach: Ratio::new::<ratio>(0.0),
+            aoa: Angle::new::<radian>(0.0),
+        };
+    }
+
+    let v = vel_air.value().unit();
+    let aoa = Angle::new::<radian>(v.x.clamp(-1.0, 1.0).acos());
+    let mach = if atm.speed_of_sound.value > 0.0 {
+        speed/atm.speed_of_sound
+    } else {
+        Ratio::new::<ratio>(0.0)
+    };
+
+    // dynamic pressure q: Pa = Kg/(m*s**2)
+    // dynamic pressure n: N = Kg/(m*s**2) * m**2 = Kg*m/(s**2)
+    let dp_q: Pressure = 0.5 * atm.density * squared!(speed);
+
+    let area_base: Area = PI*squared!(conf.sc_width/2.0);
+    let area_side: Area = conf.sc_width*conf.sc_height;
+    let area: Area = area_base*aoa.cos().abs() + area_side*aoa.sin().abs();
+
+    let drag: Force = dp_q * area * cd(conf, mach, aoa);
+    let drag_body = v*(-drag.get::<newton>());
+
+    // cross-flow: normal force opposes the airspeed component perpendicular to the body axis
+    let cross = Vec3 {x: 0.0, y: v.y, z: v.z};
+    let lift_body = if cross.norm() > 0.0 {
+        let normal: Force = dp_q * area_side * conf.sc_cd * squared!(aoa.sin());
+        let n = cross.unit()*(-normal.get::<newton>());
+        n - v*n.dot(&v)
+    } else {
+        Vec3::zero()
+    };
+
+    // Torque = r*F, r = (cp, 0, 0)
+    let r_cp = Vec3 {x: conf.sc_cp_offset.value, y: 0.0, z: 0.0};
+    let torque = r_cp.cross(&(drag_body + lift_body));
+
+    Aero {
+        drag: Vec3::from_value(drag_body),
+        lift: Vec3::from_value(lift_body),
+        torque: torque.map(Torque::new::<newton_meter>),
+        mach: mach,
+        aoa: aoa,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::length::meter;
+
+    use crate::assert_approx_eq;
+    use crate::utils::bodies::BodyPreset;
+
+    fn scenario() -> Scenario {
+        let f = std::fs::File::open("conf/TM1/TM1a-takeoff.yaml").unwrap();
+        serde_yaml::from_reader(f).unwrap()
+    }
+
+    fn vel(x: f64, y: f64, z: f64) -> Vec3<Velocity> {
+        Vec3::from_value(Vec3 {x: x, y: y, z: z})
+    }
+
+    #[test]
+    fn test_aero_1_drag() {
+        let mut conf = scenario();
+        conf.sc_cp_offset = Length::new::<meter>(0.0);
+        let atm = BodyPreset::Earth.body().atmosphere(Length::new::<meter>(0.0));
+
+        // axial flow, low speed: drag = q*S*Cd, opposite to the airspeed, no lift
+        let a = aero(&conf, &atm, vel(-10.0, 0.0, 0.0));
+        let q = 0.5*atm.density.value*100.0;
+        let s = PI*(conf.sc_width.value/2.0).powi(2);
+        assert_approx_eq!(a.drag.x.value, q*s*cd(&conf, a.mach, a.aoa).value, 1e-9);
+        assert!(a.drag.x.value > 0.0);
+        assert_eq!(a.lift.norm().value, 0.0);
+        assert_approx_eq!(a.aoa.get::<degree>(), 180.0, 1e-9);
+
+        // transonic drag rise
+        let subsonic = cd(&conf, Ratio::new::<ratio>(0.5), Angle::new::<degree>(0.0));
+        let transonic = cd(&conf, Ratio::new::<ratio>(1.1), Angle::new::<degree>(0.0));
+        assert!(transonic > subsonic);
+        assert_eq!(cd(&conf, Ratio::new::<ratio>(50.0), Angle::new::<degree>(0.0)).value, conf.sc_cd.value*1.1);
+
+        // vacuum
+        let vacuum = BodyPreset::Moon.body().atmosphere(Length::new::<meter>(0.0));
+        assert_eq!(aero(&conf, &vacuum, vel(-10.0, 0.0, 0.0)).drag.norm().value, 0.0);
+    }
+
+    #[test]
+    fn test_aero_2_lift_torque() {
+        let mut conf = scenario();
+        conf.sc_cp_offset = Length::new::<meter>(-1.0);
+        let atm = BodyPreset::Earth.body().atmosphere(Length::new::<meter>(0.0));
+
+        // nose up (airspeed along +x, and a bit of -y): lift is perpendicular to the airspeed
+        let v = vel(100.0, -20.0, 0.0);
+        let a = aero(&conf, &atm, v);
+        assert_approx_eq!(a.lift.value().dot(&v.value()), 0.0, 1e-6);
+        assert!(a.lift.y.value > 0.0);
+
+        // center of pressure behind the center of mass: weathervane stability, the
+        // torque brings the nose back into the airspeed (around -z here)
+        assert!(a.torque.z.value < 0.0);
+        assert_approx_eq!(a.torque.x.value, 0.0, 1e-9);
+    }
+}
@@ -0,0 +1,253 @@
+use std::f64::consts::E;
+
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::length::meter;
+use uom::si::mass_density::kilogram_per_cubic_meter;
+use uom::si::pressure::pascal;
+use uom::si::ratio::ratio;
+use uom::si::thermodynamic_temperature::kelvin;
+use uom::si::velocity::meter_per_second;
+
+
+/// Atmosphere model of a body
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum AtmosphereModel {
+    AtmNone,                // vacuum
+    AtmExponential,         // single exponential, using the body's atm_r0, atm_p0 and atm_a
+    AtmUsStandard1976,      // Earth, layered
+    AtmKerbin,              // KSP, tabulated
+    AtmMarsExponential,     // Mars, NASA Glenn model
+}
+
+
+/// Atmosphere properties at a given altitude
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize)]
+pub struct Atmosphere {
+    pub density: MassDensity,
+    pub pressure: Pressure,
+    pub temperature: ThermodynamicTemperature,
+    pub speed_of_sound: Velocity,
+}
+
+
+// Specific gas constant (J/(kg*K)) and heat capacity ratio
+const AIR_R: f64 = 287.053;
+const AIR_GAMMA: f64 = 1.4;
+const CO2_R: f64 = 188.92;
+const CO2_GAMMA: f64 = 1.29;
+
+
+impl AtmosphereModel {
+    /// Inputs:
+    ///     altitude: m
+    ///     r0, p0, a: only used with AtmExponential
+    pub fn at(&self, altitude: Length, r0: MassDensity, p0: Pressure, a: Length) -> Atmosphere {
+        let h = altitude.get::<meter>().max(0.0);
+
+        match self {
+            AtmosphereModel::AtmNone => Atmosphere {
+                density: MassDensity::new::<kilogram_per_cubic_meter>(0.0),
+                pressure: Pressure::new::<pascal>(0.0),
+                temperature: ThermodynamicTemperature::new::<kelvin>(0.0),
+                speed_of_sound: Velocity::new::<meter_per_second>(0.0),
+            },
+            AtmosphereModel::AtmExponential => {
+                // isothermal
+                // Source: http://scipp.ucsc.edu/outreach/balloon/glost/environment3.html
+                let exp: f64 = (-altitude/a).get::<ratio>();
+                let p = p0.get::<pascal>() * E.powf(exp);
+                let r = r0.get::<kilogram_per_cubic_meter>() * E.powf(exp);
+                let t = if r > 0.0 { p0.get::<pascal>()/(r0.get::<kilogram_per_cubic_meter>()*AIR_R) } else { 0.0 };
+                atmosphere(p, t, AIR_R, AIR_GAMMA)
+            },
+            AtmosphereModel::AtmUsStandard1976 => {
+                let (p, t) = us_standard_1976(h);
+                atmosphere(p, t, AIR_R, AIR_GAMMA)
+            },
+            AtmosphereModel::AtmKerbin => {
+                let (p, t) = kerbin(h);
+                atmosphere(p, t, AIR_R, AIR_GAMMA)
+            },
+            AtmosphereModel::AtmMarsExponential => {
+                let (p, t) = mars(h);
+                atmosphere(p, t, CO2_R, CO2_GAMMA)
+            },
+        }
+    }
+}
+
+
+/// Ideal gas, from pressure (Pa) and temperature (K)
+fn atmosphere(p: f64, t: f64, r: f64, gamma: f64) -> Atmosphere {
+    let (density, speed_of_sound) = if t > 0.0 {
+        (p/(r*t), (gamma*r*t).sqrt())
+    } else {
+        (0.0, 0.0)
+    };
+
+    Atmosphere {
+        density: MassDensity::new::<kilogram_per_cubic_meter>(density),
+        pressure: Pressure::new::<pascal>(p),
+        temperature: ThermodynamicTemperature::new::<kelvin>(t),
+        speed_of_sound: Velocity::new::<meter_per_second>(speed_of_sound),
+    }
+}
+
+
+// US Standard Atmosphere 1976, up to 86 km, isothermal above
+// base geopotential altitude (m), base temperature (K), lapse rate (K/m), base pressure (Pa)
+const US1976_LAYERS: [(f64, f64, f64, f64); 8] = [
+    (0.0, 288.15, -0.0065, 101_325.0),
+    (11_000.0, 216.65, 0.0, 22_632.06),
+    (20_000.0, 216.65, 0.001, 5_474.889),
+    (32_000.0, 228.65, 0.0028, 868.0187),
+    (47_000.0, 270.65, 0.0, 110.9063),
+    (51_000.0, 270.65, -0.0028, 66.93887),
+    (71_000.0, 214.65, -0.002, 3.956420),
+    (84_852.0, 186.946, 0.0, 0.3734),
+];
+const US1976_EARTH_RADIUS: f64 = 6_356_766.0;  // m, for geopotential altitude
+const US1976_G0_M_R: f64 = 9.80665*0.028_964_4/8.314_459_8;  // g0*M/R*, K/m
+
+/// Returns pressure (Pa) and temperature (K)
+///
+/// Source: https://en.wikipedia.org/wiki/U.S._Standard_Atmosphere
+fn us_standard_1976(altitude: f64) -> (f64, f64) {
+    let h = US1976_EARTH_RADIUS*altitude/(US1976_EARTH_RADIUS+altitude);
+
+    let (hb, tb, lb, pb) = *US1976_LAYERS.iter().rev()
+        .find(|layer| layer.0 <= h)
+        .unwrap_or(&US1976_LAYERS[0]);
+
+    if lb == 0.0 {
+        (pb*E.powf(-US1976_G0_M_R*(h-hb)/tb), tb)
+    } else {
+        let t = tb + lb*(h-hb);
+        (pb*(tb/t).powf(US1976_G0_M_R/lb), t)
+    }
+}
+
+
+// KSP Kerbin atmosphere (approximate values of the game's curves, KSP 1.x)
+// altitude (m), pressure (Pa), temperature (K)
+const KERBIN_TABLE: [(f64, f64, f64); 13] = [
+    (0.0, 101_325.0, 288.15),
+    (2_500.0, 69_140.0, 272.0),
+    (5_000.0, 47_000.0, 256.0),
+    (7_500.0, 31_900.0, 240.0),
+    (10_000.0, 21_700.0, 225.0),
+    (15_000.0, 10_000.0, 205.0),
+    (20_000.0, 4_600.0, 200.0),
+    (25_000.0, 2_100.0, 215.0),
+    (30_000.0, 970.0, 230.0),
+    (40_000.0, 210.0, 265.0),
+    (50_000.0, 45.0, 250.0),
+    (60_000.0, 9.5, 215.0),
+    (70_000.0, 0.0, 190.0),  // edge of the atmosphere
+];
+
+/// Returns pressure (Pa) and temperature (K)
+///
+/// Pressure is interpolated exponentially (linearly for the last segment, which
+/// ends at 0), temperature linearly.
+fn kerbin(altitude: f64) -> (f64, f64) {
+    let last = KERBIN_TABLE[KERBIN_TABLE.len()-1];
+    if altitude >= last.0 {
+        return (0.0, last.2);
+    }
+
+    let i = KERBIN_TABLE.iter().rposition(|row| row.0 <= altitude).unwrap_or(0);
+    let (h0, p0, t0) = KERBIN_TABLE[i];
+    let (h1, p1, t1) = KERBIN_TABLE[i+1];
+    let k = (altitude-h0)/(h1-h0);
+
+    let p = if p1 > 0.0 {
+        p0*(p1/p0).powf(k)
+    } else {
+        p0 + k*(p1-p0)
+    };
+
+    (p, t0 + k*(t1-t0))
+}
+
+
+/// Returns pressure (Pa) and temperature (K)
+///
+/// Source: https://www.grc.nasa.gov/www/k-12/airplane/atmosmrm.html
+fn mars(altitude: f64) -> (f64, f64) {
+    let t_celsius = if altitude > 7_000.0 {
+        -23.4 - 0.00222*altitude
+    } else {
+        -31.0 - 0.000998*altitude
+    };
+    let p = 699.0*E.powf(-0.00009*altitude);
+
+    (p, t_celsius + 273.15)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_approx_eq;
+
+    fn at(model: AtmosphereModel, altitude: f64) -> Atmosphere {
+        model.at(
+            Length::new::<meter>(altitude),
+            MassDensity::new::<kilogram_per_cubic_meter>(1.225),
+            Pressure::new::<pascal>(101_325.0),
+            Length::new::<meter>(8_420.0),
+        )
+    }
+
+    #[test]
+    fn test_atmosphere_1_us_standard_1976() {
+        let sea = at(AtmosphereModel::AtmUsStandard1976, 0.0);
+        assert_approx_eq!(sea.density.get::<kilogram_per_cubic_meter>(), 1.225, 1e-3);
+        assert_approx_eq!(sea.temperature.get::<kelvin>(), 288.15, 1e-9);
+        assert_approx_eq!(sea.speed_of_sound.get::<meter_per_second>(), 340.3, 0.1);
+
+        // reference values, at geometric altitudes
+        let tropopause = at(AtmosphereModel::AtmUsStandard1976, 11_019.0);
+        assert_approx_eq!(tropopause.temperature.get::<kelvin>(), 216.65, 1e-2);
+        assert_approx_eq!(tropopause.pressure.get::<pascal>(), 22_632.0, 5.0);
+
+        let stratosphere = at(AtmosphereModel::AtmUsStandard1976, 20_063.0);
+        assert_approx_eq!(stratosphere.pressure.get::<pascal>(), 5_474.9, 5.0);
+
+        // continuous across layers
+        let below = at(AtmosphereModel::AtmUsStandard1976, 32_162.0 - 1.0);
+        let above = at(AtmosphereModel::AtmUsStandard1976, 32_162.0 + 1.0);
+        assert_approx_eq!(below.pressure.get::<pascal>(), above.pressure.get::<pascal>(), 1.0);
+    }
+
+    #[test]
+    fn test_atmosphere_2_kerbin_mars() {
+        let sea = at(AtmosphereModel::AtmKerbin, 0.0);
+        assert_approx_eq!(sea.pressure.get::<pascal>(), 101_325.0, 1e-9);
+
+        // pressure decreases up to the edge of the atmosphere
+        let mut p_prev = sea.pressure;
+        for i in 1..80 {
+            let cur = at(AtmosphereModel::AtmKerbin, 1_000.0*(i as f64));
+            assert!(cur.pressure < p_prev || cur.pressure.get::<pascal>() == 0.0);
+            p_prev = cur.pressure;
+        }
+        assert_eq!(at(AtmosphereModel::AtmKerbin, 70_000.0).density.get::<kilogram_per_cubic_meter>(), 0.0);
+
+        let mars = at(AtmosphereModel::AtmMarsExponential, 0.0);
+        assert_approx_eq!(mars.pressure.get::<pascal>(), 699.0, 1e-9);
+        assert_approx_eq!(mars.density.get::<kilogram_per_cubic_meter>(), 0.0153, 1e-4);
+        assert!(mars.speed_of_sound < sea.speed_of_sound);
+
+        let vacuum = at(AtmosphereModel::AtmNone, 0.0);
+        assert_eq!(vacuum.density.get::<kilogram_per_cubic_meter>(), 0.0);
+    }
+}
@@ -1,36 +1,83 @@
-use std::f64::consts::E;
-use serde::{Serialize, Deserialize};
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use serde::{Serialize, Deserialize, Deserializer};
 use uom::si::f64::*;
 use uom::si::acceleration::meter_per_second_squared;
+use uom::si::angular_velocity::radian_per_second;
 use uom::si::length::meter;
-use uom::si::ratio::ratio;
+use uom::si::mass::kilogram;
+use uom::si::mass_density::kilogram_per_cubic_meter;
+use uom::si::pressure::pascal;
+use uom::si::time::second;
 use crate::squared;
+use crate::utils::atmosphere::{AtmosphereModel, Atmosphere};
+use crate::utils::math::Vec3;
+
+
+/// Gravitational constant, m**3/(kg*s**2)
+pub const G: f64 = 6.67430e-11;
+
+/// Relative tolerance on mu = G*mass, for the presets and the yaml bodies
+pub const MU_MASS_TOL: f64 = 1e-4;
+
+
+/// Built-in celestial bodies
+///
+/// Sources:
+///     https://nssdc.gsfc.nasa.gov/planetary/planetfact.html
+///     https://wiki.kerbalspaceprogram.com/wiki/Kerbol_System
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum BodyPreset {
+    Moon,
+    Mun,
+    Kerbin,
+    Earth,
+    Mars,
+    Minmus,
+}
 
 
 /// Orbited body
 ///
 /// Yaml serialized equivalents:
 ///
-/// # Moon
+/// # Preset
+/// body: Mun
+///
+/// # Preset, with some fields overridden
+/// body:
+///     preset: Kerbin
+///     atm_a: 5600.0
+///
+/// # Custom body (all fields, mass or mu can be omitted, atm_model defaults
+/// # to AtmExponential)
 /// body:
 ///     radius: 1737.4e3
-///     mass: 7.342e22
-///     mu: 4.9048695e12
+///     mass: 7.346e22
+///     atm_model: AtmNone
 ///     atm_r0: 0.0
 ///     atm_p0: 0.0
 ///     atm_a: 1.0
+///     rotation_period: 2360591.5
+///     j2: 2.033e-4
+///     third_body: {mu: 3.986004418e14, distance: 384.4e6, period: 2360591.5}
 ///
-/// # Earth
-/// body:
-///     radius: 6371.0e3
-///     mass: 5.97237e24
-///     mu: 3.986004418e14
-///     atm_r0: 1.225
-///     atm_p0: 101_325.0
-///     atm_a: 8420.0
+/// mu is kept consistent with mass: if only one of them is given (or
+/// overridden), the other one is computed. If both are given, they must agree
+/// within MU_MASS_TOL.
 ///
+/// The rotation axis is perpendicular to the reference point (cf.
+/// utils::frames), a non-rotating body has an infinite period (`.inf`).
+///
+/// j2 (defaults to 0) and third_body (defaults to none, `third_body: null`
+/// removes the preset one) are only used by the simulator, the GNC assumes a
+/// point mass.
 #[derive(Clone, Copy)]
 #[derive(Serialize, Deserialize)]
+#[serde(try_from = "BodyDef")]
 pub struct Body {
     pub radius: Length,             // mean radius
     pub mass: Mass,
@@ -38,31 +85,305 @@ pub struct Body {
     pub mu: f64,                    // m**3/s**2 - standard gravitational parameter
                                     //    Note: uom is not used because unit is not supported
 
-    pub atm_r0: MassDensity,        // Air density at sea level
-    pub atm_p0: Pressure,           // Air pressure at sea level
-    pub atm_a: Length,              // Effective height of atmosphere
+    pub atm_model: AtmosphereModel,
+    pub atm_r0: MassDensity,        // Air density at sea level, used only with AtmExponential
+    pub atm_p0: Pressure,           // Air pressure at sea level, used only with AtmExponential
+    pub atm_a: Length,              // Effective height of atmosphere, used only with AtmExponential
+
+    pub rotation_period: Time,      // sidereal
+
+    pub j2: f64,                    // zonal harmonic, around the rotation axis
+    pub third_body: Option<ThirdBody>,  // perturbing body (Sun, parent planet)
+}
+
+
+/// Third body, on a circular orbit in the equatorial plane of the orbited
+/// body, prograde (same direction as the rotation), on the +X axis at t=0
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThirdBody {
+    pub mu: f64,                    // m**3/s**2
+    pub distance: Length,           // from the center of the orbited body
+    pub period: Time,               // sidereal
+}
+
+
+/// Yaml representation of a Body: either a preset name, or fields (and an optional preset)
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BodyDef {
+    Preset(BodyPreset),
+    Fields(BodyFields),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BodyFields {
+    preset: Option<BodyPreset>,
+    radius: Option<Length>,
+    mass: Option<Mass>,
+    mu: Option<f64>,
+    atm_model: Option<AtmosphereModel>,
+    atm_r0: Option<MassDensity>,
+    atm_p0: Option<Pressure>,
+    atm_a: Option<Length>,
+    rotation_period: Option<Time>,
+    j2: Option<f64>,
+    #[serde(default, deserialize_with = "some")]
+    third_body: Option<Option<ThirdBody>>,
+}
+
+
+/// Distinguishes an explicit null (Some(None)) from a missing field (None)
+fn some<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+
+impl BodyPreset {
+    pub fn body(self) -> Body {
+        use AtmosphereModel::*;
+
+        // radius (m), mass (kg), mu (m**3/s**2), atm_model, atm_r0 (kg/m**3), atm_p0 (Pa), atm_a (m), rotation_period (s)
+        let (radius, mass, mu, atm_model, atm_r0, atm_p0, atm_a, rotation_period) = match self {
+            BodyPreset::Moon => (1737.4e3, 7.346e22, 4.9028e12, AtmNone, 0.0, 0.0, 1.0, 2_360_591.5),
+            BodyPreset::Mun => (200.0e3, 9.7599066e20, 6.5138398e10, AtmNone, 0.0, 0.0, 1.0, 138_984.38),
+            BodyPreset::Kerbin => (600.0e3, 5.2915158e22, 3.5316e12, AtmKerbin, 1.225, 101_325.0, 5850.0, 21_549.425),
+            BodyPreset::Earth => (6371.0e3, 5.97237e24, 3.986004418e14, AtmUsStandard1976, 1.225, 101_325.0, 8420.0, 86_164.1),
+            BodyPreset::Mars => (3389.5e3, 6.4171e23, 4.282837e13, AtmMarsExponential, 0.020, 699.0, 11_100.0, 88_642.7),
+            BodyPreset::Minmus => (60.0e3, 2.6457580e19, 1.7658e9, AtmNone, 0.0, 0.0, 1.0, 40_400.0),
+        };
+
+        // j2, third body: mu (m**3/s**2), distance (m), period (s)
+        // KSP bodies are perfect spheres
+        let (j2, (tb_mu, tb_distance, tb_period)) = match self {
+            BodyPreset::Moon => (2.033e-4, (3.986004418e14, 384.4e6, 2_360_591.5)),   // Earth
+            BodyPreset::Mun => (0.0, (3.5316e12, 12.0e6, 138_984.38)),             // Kerbin
+            BodyPreset::Kerbin => (0.0, (1.1723328e18, 13_599_840_256.0, 9_203_545.0)),  // Kerbol
+            BodyPreset::Earth => (1.08263e-3, (1.32712440018e20, 149.598e9, 31_558_149.8)), // Sun
+            BodyPreset::Mars => (1.96045e-3, (1.32712440018e20, 227.956e9, 59_355_072.0)),  // Sun
+            BodyPreset::Minmus => (0.0, (3.5316e12, 47.0e6, 1_077_310.5)),         // Kerbin
+        };
+
+        Body {
+            radius: Length::new::<meter>(radius),
+            mass: Mass::new::<kilogram>(mass),
+            mu: mu,
+            atm_model: atm_model,
+            atm_r0: MassDensity::new::<kilogram_per_cubic_meter>(atm_r0),
+            atm_p0: Pressure::new::<pascal>(atm_p0),
+            atm_a: Length::new::<meter>(atm_a),
+            rotation_period: Time::new::<second>(rotation_period),
+            j2: j2,
+            third_body: Some(ThirdBody {
+                mu: tb_mu,
+                distance: Length::new::<meter>(tb_distance),
+                period: Time::new::<second>(tb_period),
+            }),
+        }
+    }
+}
+
+
+impl TryFrom<BodyDef> for Body {
+    type Error = String;
+
+    fn try_from(def: BodyDef) -> Result<Self, Self::Error> {
+        let f = match def {
+            BodyDef::Preset(preset) => return Ok(preset.body()),
+            BodyDef::Fields(f) => f,
+        };
+
+        let preset = f.preset.map(|p| p.body());
+
+        fn field<T>(name: &str, val: Option<T>, preset: Option<T>) -> Result<T, String> {
+            val.or(preset).ok_or(format!("body: missing field `{}` (and no preset)", name))
+        }
+
+        // keep mu and mass consistent
+        let (mass, mu) = match (f.mass, f.mu) {
+            (Some(mass), Some(mu)) => {
+                if (G*mass.get::<kilogram>()/mu - 1.0).abs() > MU_MASS_TOL {
+                    return Err(format!("body: `mass` and `mu` are inconsistent, mu != G*mass ({:e} != {:e})",
+                                       mu, G*mass.get::<kilogram>()));
+                }
+                (mass, mu)
+            },
+            (Some(mass), None) => (mass, G*mass.get::<kilogram>()),
+            (None, Some(mu)) => (Mass::new::<kilogram>(mu/G), mu),
+            (None, None) => match preset {
+                Some(p) => (p.mass, p.mu),
+                None => return Err("body: missing field `mass` or `mu` (and no preset)".to_string()),
+            },
+        };
+
+        Ok(Body {
+            radius: field("radius", f.radius, preset.map(|p| p.radius))?,
+            mass: mass,
+            mu: mu,
+            atm_model: f.atm_model.or(preset.map(|p| p.atm_model)).unwrap_or(AtmosphereModel::AtmExponential),
+            atm_r0: field("atm_r0", f.atm_r0, preset.map(|p| p.atm_r0))?,
+            atm_p0: field("atm_p0", f.atm_p0, preset.map(|p| p.atm_p0))?,
+            atm_a: field("atm_a", f.atm_a, preset.map(|p| p.atm_a))?,
+            rotation_period: field("rotation_period", f.rotation_period, preset.map(|p| p.rotation_period))?,
+            j2: f.j2.or(preset.map(|p| p.j2)).unwrap_or(0.0),
+            third_body: f.third_body.unwrap_or(preset.and_then(|p| p.third_body)),
+        })
+    }
 }
 
 
 impl Body {
+    /// Point mass gravity, as assumed by the GNC
     pub fn gravity(&self, altitude: Length) -> Acceleration {
         let ra: f64 = (self.radius+altitude).get::<meter>();
         Acceleration::new::<meter_per_second_squared>(self.mu/ra.powi(2))
     }
 
+    /// Gravity at a BCI position, at time t: point mass, J2 and third body
+    ///
+    /// J2, with the pole on the Z axis (cf. utils::frames):
+    ///     a = -3/2*J2*mu*R**2/r**5 * (x*(1-5*z**2/r**2), y*(1-5*z**2/r**2), z*(3-5*z**2/r**2))
+    /// Third body, relative to the (accelerated) center of the orbited body:
+    ///     a = mu_3*((d-r)/|d-r|**3 - d/|d|**3)
+    ///
+    /// Source: Vallado, Fundamentals of Astrodynamics and Applications, 8.6
+    pub fn gravity_bci(&self, t: Time, pos: Vec3<Length>) -> Vec3<Acceleration> {
+        let r = pos.value();
+        let r_norm = r.norm();
+
+        let mut acc = r*(-self.mu/r_norm.powi(3));
+
+        if self.j2 != 0.0 {
+            let k = -1.5*self.j2*self.mu*self.radius.get::<meter>().powi(2)/r_norm.powi(5);
+            let z2 = (r.z/r_norm).powi(2);
+            acc += Vec3 {
+                x: k*r.x*(1.0-5.0*z2),
+                y: k*r.y*(1.0-5.0*z2),
+                z: k*r.z*(3.0-5.0*z2),
+            };
+        }
+
+        if let Some(tb) = self.third_body {
+            let angle = 2.0*PI*(t/tb.period).value;
+            let d = Vec3 {x: angle.cos(), y: -angle.sin(), z: 0.0}*tb.distance.get::<meter>();
+            let dr = d - r;
+            acc += (dr*(1.0/dr.norm().powi(3)) - d*(1.0/d.norm().powi(3)))*tb.mu;
+        }
+
+        acc.map(Acceleration::new::<meter_per_second_squared>)
+    }
+
+    pub fn rotation_rate(&self) -> AngularVelocity {
+        AngularVelocity::new::<radian_per_second>(2.0*PI/self.rotation_period.get::<second>())
+    }
+
     pub fn centrifugal(&self, vel: Velocity, altitude: Length) -> Acceleration {
         squared!(vel)/(self.radius+altitude)
     }
 
-    /// Inputs:
-    ///     h: altitude: m
-    /// Ouputs:
-    ///     rho: density: Kg/m**3
-    /// Source: http://scipp.ucsc.edu/outreach/balloon/glost/environment3.html
-    pub fn atmosphere_density(&self, altitude: Length) -> MassDensity {
-        let exp: f64 = (-altitude/self.atm_a).get::<ratio>();
-        let _p = self.atm_p0 * E.powf(exp);  // presure
-        let r = self.atm_r0 * E.powf(exp);  // density
-        r
+    /// Atmosphere properties (density, pressure, temperature, speed of sound)
+    pub fn atmosphere(&self, altitude: Length) -> Atmosphere {
+        self.atm_model.at(altitude, self.atm_r0, self.atm_p0, self.atm_a)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bodies_1_presets_consistent() {
+        let presets = [
+            BodyPreset::Moon, BodyPreset::Mun, BodyPreset::Kerbin,
+            BodyPreset::Earth, BodyPreset::Mars, BodyPreset::Minmus,
+        ];
+
+        for preset in presets.iter() {
+            let body = preset.body();
+
+            // mu = G*M
+            let err = (G*body.mass.get::<kilogram>()/body.mu - 1.0).abs();
+            assert!(err < MU_MASS_TOL, "{:?}: mu and mass are inconsistent ({:e})", preset, err);
+
+            assert!(body.radius > Length::new::<meter>(0.0), "{:?}", preset);
+            assert!(body.atm_a > Length::new::<meter>(0.0), "{:?}", preset);
+            assert!(body.rotation_period > Time::new::<second>(0.0), "{:?}", preset);
+        }
+    }
+
+    #[test]
+    fn test_bodies_2_yaml() {
+        // preset
+        let body: Body = serde_yaml::from_str("Mun").unwrap();
+        assert_eq!(body.radius, BodyPreset::Mun.body().radius);
+
+        // preset with overrides, mu follows mass
+        let body: Body = serde_yaml::from_str("{preset: Mun, radius: 210.0e3, mass: 1.0e21}").unwrap();
+        assert_eq!(body.radius, Length::new::<meter>(210.0e3));
+        assert_eq!(body.mu, G*1.0e21);
+        assert_eq!(body.rotation_period, BodyPreset::Mun.body().rotation_period);
+
+        // custom body, mass follows mu
+        let body: Body = serde_yaml::from_str(
+            "{radius: 1.0e3, mu: 1.0e9, atm_r0: 0.0, atm_p0: 0.0, atm_a: 1.0, rotation_period: .inf}"
+        ).unwrap();
+        assert_eq!(body.mass, Mass::new::<kilogram>(1.0e9/G));
+        assert_eq!(body.rotation_rate().value, 0.0);
+
+        // both mass and mu, consistent or not
+        let body: Body = serde_yaml::from_str("{preset: Mun, mass: 1.0e21, mu: 6.6743e10}").unwrap();
+        assert_eq!(body.mu, 6.6743e10);
+        assert!(serde_yaml::from_str::<Body>("{preset: Mun, mass: 1.0e21, mu: 1.0e9}").is_err());
+
+        // incomplete custom body
+        assert!(serde_yaml::from_str::<Body>("{radius: 1.0e3, mu: 1.0e9}").is_err());
+
+        // perturbations: from the preset, removed, or none for a custom body
+        let body: Body = serde_yaml::from_str("{preset: Moon, j2: 0.0}").unwrap();
+        assert_eq!(body.j2, 0.0);
+        assert!(body.third_body.is_some());
+        let body: Body = serde_yaml::from_str("{preset: Moon, third_body: null}").unwrap();
+        assert!(body.third_body.is_none());
+        let body: Body = serde_yaml::from_str(
+            "{radius: 1.0e3, mu: 1.0e9, atm_r0: 0.0, atm_p0: 0.0, atm_a: 1.0, rotation_period: .inf}"
+        ).unwrap();
+        assert_eq!(body.j2, 0.0);
+        assert!(body.third_body.is_none());
+    }
+
+    #[test]
+    fn test_bodies_3_gravity_bci() {
+        let mut body = BodyPreset::Earth.body();
+        let t = Time::new::<second>(0.0);
+        let r = body.radius.get::<meter>();
+        let g0 = body.mu/r.powi(2);
+
+        let pos = |x: f64, y: f64, z: f64| Vec3::from_value(Vec3 {x: x, y: y, z: z});
+
+        // J2 only: stronger at the equator, weaker at the poles
+        body.third_body = None;
+        let equator = body.gravity_bci(t, pos(0.0, r, 0.0)).value();
+        let pole = body.gravity_bci(t, pos(0.0, 0.0, r)).value();
+        assert!((equator.y/(-g0) - (1.0 + 1.5*body.j2)).abs() < 1e-12);
+        assert!((pole.z/(-g0) - (1.0 - 3.0*body.j2)).abs() < 1e-12);
+
+        // third body only: tidal acceleration, 2*mu_3*r/d**3 toward the Sun at the
+        // sub-solar point (on +X at t=0), and toward the center 90 deg away
+        body.j2 = 0.0;
+        body.third_body = BodyPreset::Earth.body().third_body;
+        let tb = body.third_body.unwrap();
+        let tidal = tb.mu*r/tb.distance.get::<meter>().powi(3);
+        let sub_solar = body.gravity_bci(t, pos(r, 0.0, 0.0)).value();
+        let side = body.gravity_bci(t, pos(0.0, r, 0.0)).value();
+        assert!((sub_solar.x + g0 - 2.0*tidal).abs()/tidal < 1e-3);
+        assert!((side.y + g0 + tidal).abs()/tidal < 1e-3);
     }
 }
@@ -0,0 +1,256 @@
+use uom::si::f64::*;
+use uom::si::angle::radian;
+use uom::si::angular_velocity::radian_per_second;
+use uom::si::length::meter;
+use uom::si::velocity::meter_per_second;
+
+use crate::utils::bodies::Body;
+use crate::utils::math::{Vec3, Quaternion};
+
+
+/// Reference frames
+///
+/// BCI: body-centred inertial frame. Origin at the center of the body, at
+///     t=0 the reference point (local x = z = 0 on the ground) is on the +Y
+///     axis, and local x (downrange) points toward +X at the reference point.
+///     The body rotates around -Z (x is east, z is south), hence the
+///     reference point moves with time.
+///
+/// LVLH: local-vertical local-horizontal frame, the "local frame" used by the
+///     GNC. x is downrange, y is the local vertical (altitude), z is
+///     cross-range. Positions are ground distances from the reference point
+///     (arc lengths, fixed to the surface), and altitude above the mean
+///     radius. Velocities are relative to the surface (as KSP reports them),
+///     and expressed in the local basis at the spacecraft.
+///
+/// With theta = x/R (downrange angle) and phi = z/R (cross-range angle):
+///     e_y = (sin(theta)*cos(phi), cos(theta)*cos(phi), sin(phi))
+///     e_x = (cos(theta), -sin(theta), 0)
+///     e_z = e_x*e_y = (-sin(theta)*sin(phi), -cos(theta)*sin(phi), cos(phi))
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct Lvlh {
+    pub pos: Vec3<Length>,
+    pub vel: Vec3<Velocity>,        // surface-relative
+}
+
+
+/// Rotation from the body-fixed frame to the BCI frame, at time t
+fn body_rotation(body: &Body, t: Time) -> Quaternion {
+    let angle: Angle = (body.rotation_rate()*t).into();
+    Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: -1.0}, angle)
+}
+
+
+/// Rotation from the LVLH basis at a given position (LVLH) to the BCI basis, at time t
+pub fn lvlh_to_bci_rotation(body: &Body, t: Time, pos: Vec3<Length>) -> Quaternion {
+    let theta = (pos.x/body.radius).value;
+    let phi = (pos.z/body.radius).value;
+
+    body_rotation(body, t)
+        * Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: -1.0}, Angle::new::<radian>(theta))
+        * Quaternion::from_axis_angle(Vec3 {x: 1.0, y: 0.0, z: 0.0}, Angle::new::<radian>(phi))
+}
+
+
+/// Velocity of the surface (co-rotating with the body), at a given position
+/// (LVLH), relative to the BCI frame and expressed in the LVLH basis
+pub fn surface_velocity(body: &Body, pos: Vec3<Length>) -> Vec3<Velocity> {
+    let r = body.radius + pos.y;
+    let phi = (pos.z/body.radius).value;
+    let speed: Velocity = body.rotation_rate()*r*phi.cos();
+
+    Vec3 {
+        x: speed,
+        y: Velocity::new::<meter_per_second>(0.0),
+        z: Velocity::new::<meter_per_second>(0.0),
+    }
+}
+
+
+/// Inertial velocity, expressed in the LVLH basis
+pub fn inertial_velocity(body: &Body, lvlh: &Lvlh) -> Vec3<Velocity> {
+    lvlh.vel + surface_velocity(body, lvlh.pos)
+}
+
+
+/// Convert a LVLH position and velocity to BCI, at time t
+pub fn lvlh_to_bci(body: &Body, t: Time, lvlh: &Lvlh) -> (Vec3<Length>, Vec3<Velocity>) {
+    let rot = lvlh_to_bci_rotation(body, t, lvlh.pos);
+
+    let r: f64 = (body.radius + lvlh.pos.y).get::<meter>();
+    let pos = rot.rotate(Vec3 {x: 0.0, y: r, z: 0.0});
+    let vel = rot.rotate(inertial_velocity(body, lvlh).value());
+
+    (Vec3::from_value(pos), Vec3::from_value(vel))
+}
+
+
+/// Convert a BCI position and velocity to LVLH, at time t
+pub fn bci_to_lvlh(body: &Body, t: Time, pos: Vec3<Length>, vel: Vec3<Velocity>) -> Lvlh {
+    let r = body_rotation(body, t).rotate_inv(pos.value());
+    let r_norm = r.norm();
+    let up = r.unit();
+
+    let theta = up.x.atan2(up.y);
+    let phi = up.z.clamp(-1.0, 1.0).asin();
+
+    let pos_lvlh = Vec3 {
+        x: body.radius*theta,
+        y: Length::new::<meter>(r_norm) - body.radius,
+        z: body.radius*phi,
+    };
+
+    let rot = lvlh_to_bci_rotation(body, t, pos_lvlh);
+    let vel_inertial: Vec3<Velocity> = Vec3::from_value(rot.rotate_inv(vel.value()));
+
+    Lvlh {
+        pos: pos_lvlh,
+        vel: vel_inertial - surface_velocity(body, pos_lvlh),
+    }
+}
+
+
+/// Angular velocity of the LVLH frame relative to the BCI frame, expressed in
+/// the LVLH frame
+///
+/// The body rotation, and moving on a sphere (transport rate) both rotate the
+/// local vertical.
+pub fn transport_rate(body: &Body, lvlh: &Lvlh) -> Vec3<AngularVelocity> {
+    let r: f64 = (body.radius + lvlh.pos.y).get::<meter>();
+    let phi = (lvlh.pos.z/body.radius).value;
+    let vel = lvlh.vel.value();
+    let w = body.rotation_rate().get::<radian_per_second>();
+
+    Vec3 {
+        x: AngularVelocity::new::<radian_per_second>(vel.z/r),
+        y: AngularVelocity::new::<radian_per_second>(-vel.x*phi.tan()/r - w*phi.sin()),
+        z: AngularVelocity::new::<radian_per_second>(-vel.x/r - w*phi.cos()),
+    }
+}
+
+
+/// Rate of change of the LVLH position (ground distances and altitude) for a
+/// given LVLH (surface-relative) velocity
+pub fn lvlh_pos_rate(body: &Body, lvlh: &Lvlh) -> Vec3<Velocity> {
+    let k = (body.radius/(body.radius + lvlh.pos.y)).value;
+    let phi = (lvlh.pos.z/body.radius).value;
+
+    Vec3 {
+        x: lvlh.vel.x*k/phi.cos(),
+        y: lvlh.vel.y,
+        z: lvlh.vel.z*k,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::time::second;
+
+    use crate::assert_approx_eq;
+    use crate::conf::Scenario;
+
+    fn body() -> Body {
+        let f = std::fs::File::open("conf/Apollo-descent.yaml").unwrap();
+        let scenario: Scenario = serde_yaml::from_reader(f).unwrap();
+        scenario.body
+    }
+
+    #[test]
+    fn test_frames_1_round_trip() {
+        let body = body();
+
+        let lvlh = Lvlh {
+            pos: Vec3 {
+                x: Length::new::<meter>(-420_000.0),
+                y: Length::new::<meter>(15_000.0),
+                z: Length::new::<meter>(3_000.0),
+            },
+            vel: Vec3 {
+                x: Velocity::new::<meter_per_second>(1_673.0),
+                y: Velocity::new::<meter_per_second>(-10.0),
+                z: Velocity::new::<meter_per_second>(5.0),
+            },
+        };
+
+        let t = Time::new::<second>(1_000.0);
+
+        let (pos, vel) = lvlh_to_bci(&body, t, &lvlh);
+        assert_approx_eq!(pos.norm(), body.radius + lvlh.pos.y, Length::new::<meter>(1e-6));
+        assert_approx_eq!(vel.norm(), inertial_velocity(&body, &lvlh).norm(), Velocity::new::<meter_per_second>(1e-9));
+
+        let back = bci_to_lvlh(&body, t, pos, vel);
+        assert_approx_eq!(back.pos.x, lvlh.pos.x, Length::new::<meter>(1e-6));
+        assert_approx_eq!(back.pos.y, lvlh.pos.y, Length::new::<meter>(1e-6));
+        assert_approx_eq!(back.pos.z, lvlh.pos.z, Length::new::<meter>(1e-6));
+        assert_approx_eq!(back.vel.x, lvlh.vel.x, Velocity::new::<meter_per_second>(1e-9));
+        assert_approx_eq!(back.vel.y, lvlh.vel.y, Velocity::new::<meter_per_second>(1e-9));
+        assert_approx_eq!(back.vel.z, lvlh.vel.z, Velocity::new::<meter_per_second>(1e-9));
+    }
+
+    #[test]
+    fn test_frames_2_pos_rate() {
+        let body = body();
+
+        // moving horizontally for a short time: ground distance grows slower than
+        // the distance flown, by R/(R+h)
+        let lvlh = Lvlh {
+            pos: Vec3 {
+                x: Length::new::<meter>(0.0),
+                y: Length::new::<meter>(15_000.0),
+                z: Length::new::<meter>(0.0),
+            },
+            vel: Vec3 {
+                x: Velocity::new::<meter_per_second>(1_000.0),
+                y: Velocity::new::<meter_per_second>(0.0),
+                z: Velocity::new::<meter_per_second>(0.0),
+            },
+        };
+        let dt = Time::new::<second>(1e-3);
+
+        let t = Time::new::<second>(0.0);
+
+        let (pos, vel) = lvlh_to_bci(&body, t, &lvlh);
+        let next = bci_to_lvlh(&body, t+dt, pos + vel*dt, vel);
+
+        let rate = lvlh_pos_rate(&body, &lvlh);
+        assert_approx_eq!((next.pos.x-lvlh.pos.x)/dt, rate.x, Velocity::new::<meter_per_second>(1e-3));
+
+        // and the local vertical rotates backward (around -z) as we move forward
+        let w = transport_rate(&body, &lvlh);
+        assert!(w.z.value < 0.0);
+        assert_approx_eq!(w.z.value, -(1_000.0+surface_velocity(&body, lvlh.pos).x.value)/(body.radius.value + 15_000.0), 1e-12);
+    }
+
+    #[test]
+    fn test_frames_3_surface() {
+        let body = body();
+
+        // landed, away from the reference point
+        let lvlh = Lvlh {
+            pos: Vec3 {
+                x: Length::new::<meter>(50_000.0),
+                y: Length::new::<meter>(0.0),
+                z: Length::new::<meter>(-20_000.0),
+            },
+            vel: Vec3::from_value(Vec3::zero()),
+        };
+
+        // moves in the BCI frame, with the body
+        let (pos_0, vel_0) = lvlh_to_bci(&body, Time::new::<second>(0.0), &lvlh);
+        let (pos_1, _vel_1) = lvlh_to_bci(&body, Time::new::<second>(3_600.0), &lvlh);
+        assert!(vel_0.norm() > Velocity::new::<meter_per_second>(4.0));
+        assert!((pos_1-pos_0).norm() > Length::new::<meter>(10_000.0));
+
+        // but stays still in the LVLH frame
+        let dt = Time::new::<second>(1e-3);
+        let next = bci_to_lvlh(&body, dt, pos_0 + vel_0*dt, vel_0);
+        assert_approx_eq!(next.pos.x, lvlh.pos.x, Length::new::<meter>(1e-6));
+        assert_approx_eq!(next.pos.y, lvlh.pos.y, Length::new::<meter>(1e-6));
+        assert_approx_eq!(next.pos.z, lvlh.pos.z, Length::new::<meter>(1e-6));
+        assert_approx_eq!(next.vel.norm(), Velocity::new::<meter_per_second>(0.0), Velocity::new::<meter_per_second>(1e-6));
+    }
+}
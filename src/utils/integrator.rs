@@ -0,0 +1,330 @@
+use std::ops::{Add, Mul};
+
+use uom::si::f64::*;
+use uom::si::time::second;
+
+use crate::conf::SimIntegrator;
+
+
+/// State that can be propagated by an integrator
+///
+/// The derivative type is what the dynamics function returns. It only needs to
+/// be summed and scaled, the state itself is then moved forward by `step()`.
+pub trait IntegrableState: Copy {
+    type Derivative: Copy + Add<Output = Self::Derivative> + Mul<f64, Output = Self::Derivative>;
+
+    /// Returns self + derivative*dt
+    fn step(&self, derivative: Self::Derivative, dt: Time) -> Self;
+
+    /// Raw values (SI units), used to estimate the error of adaptive integrators
+    fn to_raw(&self) -> Vec<f64>;
+}
+
+
+/// Propagate `state` from `t` to `t+dt`, with `f(t, state)` giving the state derivative
+///
+/// Inputs (actuators) are assumed constant during dt (zero-order hold), hence
+/// the adaptive integrator can freely sub-step inside one GNC cycle.
+pub fn integrate<S, F>(method: SimIntegrator, tol: f64, f: F, t: Time, state: S, dt: Time) -> S
+where
+    S: IntegrableState,
+    F: Fn(Time, &S) -> S::Derivative,
+{
+    match method {
+        SimIntegrator::SimEuler => euler(&f, t, state, dt),
+        SimIntegrator::SimRk4 => rk4(&f, t, state, dt),
+        SimIntegrator::SimDormandPrince => dormand_prince(&f, tol, t, state, dt),
+    }
+}
+
+
+/// Explicit Euler (first order)
+pub fn euler<S, F>(f: &F, t: Time, state: S, dt: Time) -> S
+where
+    S: IntegrableState,
+    F: Fn(Time, &S) -> S::Derivative,
+{
+    state.step(f(t, &state), dt)
+}
+
+
+/// Classical Runge-Kutta (fourth order)
+///
+/// Source: https://en.wikipedia.org/wiki/Runge%E2%80%93Kutta_methods
+pub fn rk4<S, F>(f: &F, t: Time, state: S, dt: Time) -> S
+where
+    S: IntegrableState,
+    F: Fn(Time, &S) -> S::Derivative,
+{
+    let k1 = f(t, &state);
+    let k2 = f(t + dt/2.0, &state.step(k1, dt/2.0));
+    let k3 = f(t + dt/2.0, &state.step(k2, dt/2.0));
+    let k4 = f(t + dt, &state.step(k3, dt));
+
+    state.step(k1*(1.0/6.0) + k2*(2.0/6.0) + k3*(2.0/6.0) + k4*(1.0/6.0), dt)
+}
+
+
+// Dormand-Prince 5(4) Butcher tableau
+// Source: https://en.wikipedia.org/wiki/Dormand%E2%80%93Prince_method
+
+const DP_C: [f64; 7] = [0.0, 1.0/5.0, 3.0/10.0, 4.0/5.0, 8.0/9.0, 1.0, 1.0];
+
+const DP_A: [[f64; 6]; 7] = [
+    [0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [1.0/5.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    [3.0/40.0, 9.0/40.0, 0.0, 0.0, 0.0, 0.0],
+    [44.0/45.0, -56.0/15.0, 32.0/9.0, 0.0, 0.0, 0.0],
+    [19372.0/6561.0, -25360.0/2187.0, 64448.0/6561.0, -212.0/729.0, 0.0, 0.0],
+    [9017.0/3168.0, -355.0/33.0, 46732.0/5247.0, 49.0/176.0, -5103.0/18656.0, 0.0],
+    [35.0/384.0, 0.0, 500.0/1113.0, 125.0/192.0, -2187.0/6784.0, 11.0/84.0],
+];
+
+// 5th order solution (same as the last row of A: First Same As Last)
+const DP_B5: [f64; 7] = [35.0/384.0, 0.0, 500.0/1113.0, 125.0/192.0, -2187.0/6784.0, 11.0/84.0, 0.0];
+
+// 4th order solution, used for the error estimate
+const DP_B4: [f64; 7] = [
+    5179.0/57600.0, 0.0, 7571.0/16695.0, 393.0/640.0, -92097.0/339200.0, 187.0/2100.0, 1.0/40.0,
+];
+
+// never sub-step more than this, to bound the runtime if tol is unreachable
+const DP_MAX_STEPS: usize = 10_000;
+
+
+/// Adaptive Dormand-Prince 5(4)
+///
+/// Sub-steps inside dt until the estimated local error is below tol (used as
+/// both relative and absolute tolerance). If DP_MAX_STEPS are not enough, the
+/// rest of dt is done with one RK4 step.
+pub fn dormand_prince<S, F>(f: &F, tol: f64, t: Time, state: S, dt: Time) -> S
+where
+    S: IntegrableState,
+    F: Fn(Time, &S) -> S::Derivative,
+{
+    let t_end = t + dt;
+
+    let mut t_cur = t;
+    let mut s_cur = state;
+    let mut h = dt;
+
+    for _ in 0..DP_MAX_STEPS {
+        if t_cur >= t_end {
+            break;
+        }
+
+        // do not overshoot the end of the GNC cycle
+        if t_cur + h > t_end {
+            h = t_end - t_cur;
+        }
+
+        let (s5, s4) = dormand_prince_step(f, t_cur, &s_cur, h);
+
+        let err = error_norm(&s_cur, &s5, &s4, tol);
+
+        if err <= 1.0 {
+            t_cur += h;
+            s_cur = s5;
+        }
+
+        // standard step size controller, with a safety factor and bounded growth
+        let factor = if err == 0.0 {
+            5.0
+        } else {
+            (0.9*err.powf(-1.0/5.0)).clamp(0.2, 5.0)
+        };
+        h *= factor;
+
+        // round-off: consider we reached the end
+        if (t_end - t_cur) < Time::new::<second>(1e-12) {
+            return s_cur;
+        }
+    }
+
+    if t_cur >= t_end {
+        return s_cur;
+    }
+
+    println!(
+        "[LOGD:dormand_prince] t={:.3} tol={:e} not reached in {} steps, RK4 for the last {:.3e} s",
+        t.get::<second>(), tol, DP_MAX_STEPS, (t_end - t_cur).get::<second>(),
+    );
+
+    rk4(f, t_cur, s_cur, t_end - t_cur)
+}
+
+
+fn dormand_prince_step<S, F>(f: &F, t: Time, state: &S, h: Time) -> (S, S)
+where
+    S: IntegrableState,
+    F: Fn(Time, &S) -> S::Derivative,
+{
+    let mut k: Vec<S::Derivative> = Vec::with_capacity(7);
+
+    for i in 0..7 {
+        let si = if i == 0 {
+            *state
+        } else {
+            state.step(weighted_sum(&k, &DP_A[i][..i]), h)
+        };
+        k.push(f(t + DP_C[i]*h, &si));
+    }
+
+    let s5 = state.step(weighted_sum(&k, &DP_B5), h);
+    let s4 = state.step(weighted_sum(&k, &DP_B4), h);
+
+    (s5, s4)
+}
+
+
+fn weighted_sum<D>(k: &[D], weights: &[f64]) -> D
+where
+    D: Copy + Add<Output = D> + Mul<f64, Output = D>,
+{
+    let mut sum = k[0]*weights[0];
+    for (ki, wi) in k.iter().zip(weights.iter()).skip(1) {
+        sum = sum + *ki*(*wi);
+    }
+    sum
+}
+
+
+fn error_norm<S: IntegrableState>(s0: &S, s5: &S, s4: &S, tol: f64) -> f64 {
+    let (r0, r5, r4) = (s0.to_raw(), s5.to_raw(), s4.to_raw());
+
+    r0.iter().zip(r5.iter()).zip(r4.iter())
+        .map(|((v0, v5), v4)| (v5-v4).abs() / (tol + tol*v0.abs().max(v5.abs())))
+        .fold(0.0, f64::max)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::length::meter;
+    use uom::si::velocity::meter_per_second;
+    use uom::si::acceleration::meter_per_second_squared;
+
+    use crate::{assert_approx_eq, squared};
+
+    /// Harmonic oscillator: x'' = -x
+    #[derive(Clone, Copy)]
+    struct Oscillator {
+        x: Length,
+        v: Velocity,
+    }
+
+    #[derive(Clone, Copy)]
+    struct OscillatorDerivative {
+        x: Velocity,
+        v: Acceleration,
+    }
+
+    impl Add for OscillatorDerivative {
+        type Output = Self;
+
+        fn add(self, other: Self) -> Self {
+            Self {
+                x: self.x + other.x,
+                v: self.v + other.v,
+            }
+        }
+    }
+
+    impl Mul<f64> for OscillatorDerivative {
+        type Output = Self;
+
+        fn mul(self, k: f64) -> Self {
+            Self {
+                x: self.x*k,
+                v: self.v*k,
+            }
+        }
+    }
+
+    impl IntegrableState for Oscillator {
+        type Derivative = OscillatorDerivative;
+
+        fn step(&self, d: OscillatorDerivative, dt: Time) -> Self {
+            Self {
+                x: self.x + d.x*dt,
+                v: self.v + d.v*dt,
+            }
+        }
+
+        fn to_raw(&self) -> Vec<f64> {
+            vec![self.x.value, self.v.value]
+        }
+    }
+
+    fn propagate(method: SimIntegrator, dt: f64) -> Oscillator {
+        let f = |_t: Time, s: &Oscillator| OscillatorDerivative {
+            x: s.v,
+            v: -s.x/squared!(Time::new::<second>(1.0)),
+        };
+
+        let mut s = Oscillator {
+            x: Length::new::<meter>(1.0),
+            v: Velocity::new::<meter_per_second>(0.0),
+        };
+        let mut t = Time::new::<second>(0.0);
+        let dt = Time::new::<second>(dt);
+
+        while t < Time::new::<second>(10.0-1e-9) {
+            s = integrate(method, 1e-10, f, t, s, dt);
+            t += dt;
+        }
+
+        s
+    }
+
+    #[test]
+    fn test_integrator_1_accuracy() {
+        let expected = 10.0_f64.cos();
+
+        let euler = propagate(SimIntegrator::SimEuler, 0.01);
+        let rk4 = propagate(SimIntegrator::SimRk4, 0.1);
+        let dp = propagate(SimIntegrator::SimDormandPrince, 1.0);
+
+        assert_approx_eq!(euler.x.value, expected, 1e-1);
+        assert_approx_eq!(rk4.x.value, expected, 1e-5);
+        assert_approx_eq!(dp.x.value, expected, 1e-8);
+    }
+
+    #[test]
+    fn test_integrator_2_rk4_order() {
+        let expected = 10.0_f64.cos();
+
+        let err_1 = (propagate(SimIntegrator::SimRk4, 0.2).x.value - expected).abs();
+        let err_2 = (propagate(SimIntegrator::SimRk4, 0.1).x.value - expected).abs();
+
+        // fourth order: halving dt divides the error by 2**4
+        assert!(12.0 < err_1/err_2);
+        assert!(err_1/err_2 < 20.0);
+    }
+
+    #[test]
+    fn test_integrator_3_dp_max_steps() {
+        // x is a clock, v' changes sign at every call: the error estimate never converges
+        let calls = std::cell::Cell::new(0_usize);
+        let f = |_t: Time, _s: &Oscillator| {
+            calls.set(calls.get() + 1);
+            OscillatorDerivative {
+                x: Velocity::new::<meter_per_second>(1.0),
+                v: Acceleration::new::<meter_per_second_squared>(if calls.get().is_multiple_of(2) { 1e3 } else { -1e3 }),
+            }
+        };
+
+        let s = Oscillator {
+            x: Length::new::<meter>(0.0),
+            v: Velocity::new::<meter_per_second>(0.0),
+        };
+
+        let s = dormand_prince(&f, 1e-10, Time::new::<second>(0.0), s, Time::new::<second>(0.1));
+
+        // still propagated to the end of dt
+        assert!(calls.get() > 7*DP_MAX_STEPS);
+        assert_approx_eq!(s.x.get::<meter>(), 0.1, 1e-9);
+    }
+}
@@ -1,45 +1,381 @@
-use std::ops::{Add, Sub, AddAssign};
+use std::marker::PhantomData;
+use std::ops::{Add, Sub, AddAssign, Mul, Div, Neg};
 
 use serde::{Serialize, Deserialize};
+use uom::si::{Dimension, Quantity, SI};
+use uom::si::f64::*;
+use uom::si::angle::radian;
 
 
+/// 3D vector
+///
+/// In the local frame: x is downrange (horizontal), y is the local vertical,
+/// z is cross-range (horizontal, x*y=z).
 #[derive(Debug)]
 #[derive(Clone, Copy)]
 #[derive(Serialize, Deserialize)]
-pub struct Vec2<T> {
-    pub x: T,   // horizontal axis
-    pub y: T,   // vertical axis
+pub struct Vec3<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
 }
 
 
-impl<T: std::ops::Add<Output = T>> Add for Vec2<T> {
+impl<T: std::ops::Add<Output = T>> Add for Vec3<T> {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
         Self {
             x: self.x + other.x,
             y: self.y + other.y,
+            z: self.z + other.z,
         }
     }
 }
 
 
-impl<T: std::ops::Sub<Output = T>> Sub for Vec2<T> {
+impl<T: std::ops::Sub<Output = T>> Sub for Vec3<T> {
     type Output = Self;
 
     fn sub(self, other: Self) -> Self {
         Self {
             x: self.x - other.x,
             y: self.y - other.y,
+            z: self.z - other.z,
         }
     }
 }
 
 
-impl<T: std::ops::AddAssign> AddAssign for Vec2<T> {
+impl<T: std::ops::AddAssign> AddAssign for Vec3<T> {
     fn add_assign(&mut self, other: Self) {
         self.x += other.x;
         self.y += other.y;
+        self.z += other.z;
+    }
+}
+
+
+impl<T: std::ops::Neg<Output = T>> Neg for Vec3<T> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+}
+
+
+/// Scalar multiplication, the scalar can be a uom quantity: Vec3<Velocity>*Time = Vec3<Length>
+impl<T: std::ops::Mul<K>, K: Copy> Mul<K> for Vec3<T> {
+    type Output = Vec3<T::Output>;
+
+    fn mul(self, k: K) -> Vec3<T::Output> {
+        Vec3 {
+            x: self.x * k,
+            y: self.y * k,
+            z: self.z * k,
+        }
+    }
+}
+
+
+impl<T: std::ops::Div<K>, K: Copy> Div<K> for Vec3<T> {
+    type Output = Vec3<T::Output>;
+
+    fn div(self, k: K) -> Vec3<T::Output> {
+        Vec3 {
+            x: self.x / k,
+            y: self.y / k,
+            z: self.z / k,
+        }
+    }
+}
+
+
+impl<T> Vec3<T> {
+    pub fn map<U, F: Fn(T) -> U>(self, f: F) -> Vec3<U> {
+        Vec3 {
+            x: f(self.x),
+            y: f(self.y),
+            z: f(self.z),
+        }
+    }
+}
+
+
+impl Vec3<f64> {
+    pub fn zero() -> Self {
+        Self {x: 0.0, y: 0.0, z: 0.0}
+    }
+
+    pub fn dot(&self, other: &Self) -> f64 {
+        self.x*other.x + self.y*other.y + self.z*other.z
+    }
+
+    pub fn cross(&self, other: &Self) -> Self {
+        Self {
+            x: self.y*other.z - self.z*other.y,
+            y: self.z*other.x - self.x*other.z,
+            z: self.x*other.y - self.y*other.x,
+        }
+    }
+
+    pub fn norm(&self) -> f64 {
+        self.dot(self).sqrt()
+    }
+
+    /// Unit vector, or zero if the vector is null
+    pub fn unit(&self) -> Self {
+        let n = self.norm();
+        if n == 0.0 {
+            *self
+        } else {
+            *self / n
+        }
+    }
+}
+
+
+/// Helpers for vectors of uom quantities (Vec3<Length>, Vec3<Velocity>, ...)
+impl<D: Dimension + ?Sized> Vec3<Quantity<D, SI<f64>, f64>> {
+    /// Raw values, in SI units
+    pub fn value(&self) -> Vec3<f64> {
+        Vec3 {
+            x: self.x.value,
+            y: self.y.value,
+            z: self.z.value,
+        }
+    }
+
+    /// Build from raw values, in SI units
+    pub fn from_value(v: Vec3<f64>) -> Self {
+        v.map(|value| Quantity {dimension: PhantomData, units: PhantomData, value: value})
+    }
+
+    pub fn norm(&self) -> Quantity<D, SI<f64>, f64> {
+        Quantity {dimension: PhantomData, units: PhantomData, value: self.value().norm()}
+    }
+
+    pub fn horizontal_norm(&self) -> Quantity<D, SI<f64>, f64> {
+        Quantity {dimension: PhantomData, units: PhantomData, value: self.x.value.hypot(self.z.value)}
+    }
+}
+
+
+/// Unit quaternion, representing a rotation (attitude)
+///
+/// Convention: Hamilton product, q rotates a vector from the body frame to the
+/// local frame: v_local = q * v_body * q.conj()
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+
+impl Add for Quaternion {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            w: self.w + other.w,
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+
+impl Mul<f64> for Quaternion {
+    type Output = Self;
+
+    fn mul(self, k: f64) -> Self {
+        Self {
+            w: self.w*k,
+            x: self.x*k,
+            y: self.y*k,
+            z: self.z*k,
+        }
+    }
+}
+
+
+/// Hamilton product
+impl Mul for Quaternion {
+    type Output = Self;
+
+    fn mul(self, o: Self) -> Self {
+        Self {
+            w: self.w*o.w - self.x*o.x - self.y*o.y - self.z*o.z,
+            x: self.w*o.x + self.x*o.w + self.y*o.z - self.z*o.y,
+            y: self.w*o.y - self.x*o.z + self.y*o.w + self.z*o.x,
+            z: self.w*o.z + self.x*o.y - self.y*o.x + self.z*o.w,
+        }
+    }
+}
+
+
+impl Quaternion {
+    pub fn from_axis_angle(axis: Vec3<f64>, angle: Angle) -> Self {
+        let a = axis.unit();
+        let (s, c) = (angle.get::<radian>()/2.0).sin_cos();
+        Self {w: c, x: a.x*s, y: a.y*s, z: a.z*s}
+    }
+
+    /// Build an attitude from Euler angles, see `to_euler()`
+    pub fn from_euler(heading: Angle, pitch: Angle, roll: Angle) -> Self {
+        let q_heading = Self::from_axis_angle(Vec3 {x: 0.0, y: 1.0, z: 0.0}, -heading);
+        let q_pitch = Self::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, pitch);
+        let q_roll = Self::from_axis_angle(Vec3 {x: 1.0, y: 0.0, z: 0.0}, roll);
+        q_heading * q_pitch * q_roll
+    }
+
+    /// Decompose an attitude into Euler angles (heading, pitch, roll)
+    ///
+    /// The body x axis is the thrust axis, and the rotation sequence is:
+    ///     heading: around the local vertical (y), from x toward z, range ]-90; 90]
+    ///     pitch: around the body z axis, from x toward y (same as the 2D ang_pos)
+    ///     roll: around the body x axis
+    /// Hence a 2D attitude (heading = roll = 0) gives back pitch = ang_pos.
+    /// When the thrust axis is vertical, heading is set to 0 and roll takes it all.
+    pub fn to_euler(self) -> (Angle, Angle, Angle) {
+        let (heading, pitch) = heading_pitch(self.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0}));
+
+        let q_heading_pitch = Self::from_euler(heading, pitch, Angle::new::<radian>(0.0));
+        let body_y = (q_heading_pitch.conj() * self).rotate(Vec3 {x: 0.0, y: 1.0, z: 0.0});
+        let roll = body_y.z.atan2(body_y.y);
+
+        (heading, pitch, Angle::new::<radian>(roll))
+    }
+
+    pub fn conj(&self) -> Self {
+        Self {w: self.w, x: -self.x, y: -self.y, z: -self.z}
+    }
+
+    pub fn norm(&self) -> f64 {
+        (self.w*self.w + self.x*self.x + self.y*self.y + self.z*self.z).sqrt()
+    }
+
+    pub fn normalize(&self) -> Self {
+        *self * (1.0/self.norm())
+    }
+
+    /// Rotate a vector from the body frame to the local frame
+    pub fn rotate(&self, v: Vec3<f64>) -> Vec3<f64> {
+        let p = Self {w: 0.0, x: v.x, y: v.y, z: v.z};
+        let r = *self * p * self.conj();
+        Vec3 {x: r.x, y: r.y, z: r.z}
+    }
+
+    /// Rotate a vector from the local frame to the body frame
+    pub fn rotate_inv(&self, v: Vec3<f64>) -> Vec3<f64> {
+        self.conj().rotate(v)
+    }
+
+    /// Time derivative (per second), for a body frame angular velocity (rad/s)
+    pub fn derivative(&self, ang_vel_body: Vec3<f64>) -> Self {
+        let omega = Self {w: 0.0, x: ang_vel_body.x, y: ang_vel_body.y, z: ang_vel_body.z};
+        (*self * omega) * 0.5
+    }
+}
+
+const PI_2: f64 = std::f64::consts::FRAC_PI_2;
+
+
+/// Heading and pitch of a direction (local frame), cf. Quaternion::to_euler()
+pub fn heading_pitch(dir: Vec3<f64>) -> (Angle, Angle) {
+    let mut heading = if (dir.x.abs() < 1e-12) && (dir.z.abs() < 1e-12) {
+        0.0
+    } else {
+        dir.z.atan2(dir.x)
+    };
+    if heading > PI_2 {
+        heading -= 2.0*PI_2;
+    } else if heading <= -PI_2 {
+        heading += 2.0*PI_2;
+    }
+
+    let pitch = dir.y.atan2(dir.x*heading.cos() + dir.z*heading.sin());
+
+    (Angle::new::<radian>(heading), Angle::new::<radian>(pitch))
+}
+
+
+/// Unit direction (local frame) for a heading and pitch, cf. Quaternion::to_euler()
+pub fn direction(heading: Angle, pitch: Angle) -> Vec3<f64> {
+    let (h, p) = (heading.get::<radian>(), pitch.get::<radian>());
+    Vec3 {
+        x: p.cos()*h.cos(),
+        y: p.sin(),
+        z: p.cos()*h.sin(),
+    }
+}
+
+
+/// 3x3 matrix (row major), used for the inertia tensor
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+pub struct Mat3 {
+    pub m: [[f64; 3]; 3],
+}
+
+
+impl Mat3 {
+    pub fn diag(a: f64, b: f64, c: f64) -> Self {
+        Self {
+            m: [
+                [a, 0.0, 0.0],
+                [0.0, b, 0.0],
+                [0.0, 0.0, c],
+            ],
+        }
+    }
+
+    pub fn mul_vec(&self, v: Vec3<f64>) -> Vec3<f64> {
+        let m = &self.m;
+        Vec3 {
+            x: m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+            y: m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+            z: m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z,
+        }
+    }
+
+    pub fn det(&self) -> f64 {
+        let m = &self.m;
+        (
+            m[0][0]*(m[1][1]*m[2][2] - m[1][2]*m[2][1])
+            - m[0][1]*(m[1][0]*m[2][2] - m[1][2]*m[2][0])
+            + m[0][2]*(m[1][0]*m[2][1] - m[1][1]*m[2][0])
+        )
+    }
+
+    /// Inverse (cofactor method), the matrix must not be singular
+    pub fn inverse(&self) -> Self {
+        let m = &self.m;
+        let det = self.det();
+        assert!(det != 0.0);
+
+        let mut inv = [[0.0; 3]; 3];
+        for (i, row) in inv.iter_mut().enumerate() {
+            for (j, val) in row.iter_mut().enumerate() {
+                // cofactor of m[j][i] (transposed)
+                let (r1, r2) = ((j+1) % 3, (j+2) % 3);
+                let (c1, c2) = ((i+1) % 3, (i+2) % 3);
+                *val = (m[r1][c1]*m[r2][c2] - m[r1][c2]*m[r2][c1]) / det;
+            }
+        }
+
+        Self {m: inv}
     }
 }
 
@@ -80,3 +416,49 @@ macro_rules! assert_approx_eq {
         );
     }};
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::angle::degree;
+
+    #[test]
+    fn test_math_1_quaternion_euler() {
+        // 2D attitude
+        let q = Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, Angle::new::<degree>(120.0));
+        let (heading, pitch, roll) = q.to_euler();
+        assert_approx_eq!(heading.get::<degree>(), 0.0, 1e-9);
+        assert_approx_eq!(pitch.get::<degree>(), 120.0, 1e-9);
+        assert_approx_eq!(roll.get::<degree>(), 0.0, 1e-9);
+
+        // round trip
+        let q = Quaternion::from_euler(Angle::new::<degree>(30.0), Angle::new::<degree>(45.0), Angle::new::<degree>(-10.0));
+        let (heading, pitch, roll) = q.to_euler();
+        assert_approx_eq!(heading.get::<degree>(), 30.0, 1e-9);
+        assert_approx_eq!(pitch.get::<degree>(), 45.0, 1e-9);
+        assert_approx_eq!(roll.get::<degree>(), -10.0, 1e-9);
+
+        // thrust axis direction
+        let axis = q.rotate(Vec3 {x: 1.0, y: 0.0, z: 0.0});
+        assert_approx_eq!(axis.y, 45.0_f64.to_radians().sin(), 1e-9);
+        assert_approx_eq!(axis.z.atan2(axis.x), 30.0_f64.to_radians(), 1e-9);
+    }
+
+    #[test]
+    fn test_math_2_mat3_inverse() {
+        let m = Mat3 {
+            m: [
+                [2.0, 0.5, 0.0],
+                [0.5, 3.0, 0.1],
+                [0.0, 0.1, 4.0],
+            ],
+        };
+        let v = Vec3 {x: 1.0, y: -2.0, z: 3.0};
+        let r = m.inverse().mul_vec(m.mul_vec(v));
+        assert_approx_eq!(r.x, v.x, 1e-12);
+        assert_approx_eq!(r.y, v.y, 1e-12);
+        assert_approx_eq!(r.z, v.z, 1e-12);
+    }
+}
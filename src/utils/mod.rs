@@ -1,5 +1,12 @@
+pub mod aero;
+pub mod atmosphere;
 pub mod bodies;
+pub mod frames;
+pub mod integrator;
 pub mod math;
+pub mod sensors;
+pub mod socp;
 pub mod space;
 pub mod spacecraft;
+pub mod terrain;
 pub mod uom;
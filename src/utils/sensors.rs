@@ -0,0 +1,533 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, StandardNormal};
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::angle::radian;
+use uom::si::length::meter;
+use uom::si::ratio::ratio;
+use uom::si::time::second;
+use uom::si::velocity::meter_per_second;
+
+use crate::utils::math::{Vec3, Quaternion};
+
+
+/// Simulated sensors errors
+///
+/// The same seed gives the same errors, hence reproducible runs. A null sensor
+/// is ideal (no error).
+///
+/// Yaml serialized equivalent:
+///
+/// sensors:
+///     seed: 42
+///     accelerometer:
+///         noise_density: 3.43e-4      # 35 ug/sqrt(Hz)
+///         bias: 1.0e-4
+///         bias_instability: 5.0e-5
+///         bias_time: 300.0
+///         scale_factor: 1.0e-4        # 100 ppm
+///         misalignment: 1.0e-4
+///         quantization: 1.0e-5
+///     gyro:
+///         noise_density: 2.9e-6       # angle random walk, 0.01 deg/sqrt(h)
+///         bias: 4.8e-7                # 0.1 deg/h
+///         bias_instability: 4.8e-8    # 0.01 deg/h
+///         bias_time: 300.0
+///         scale_factor: 1.0e-5
+///         misalignment: 1.0e-4
+///         quantization: 0.0
+///     radar_altimeter:
+///         rate: 10.0
+///         max_range: 12000.0
+///         beam_width: 0.10472         # 6 deg
+///         max_tilt: 1.0472            # 60 deg
+///         boresight: 0.0
+///         noise: 1.0
+///         noise_ratio: 0.01
+///     doppler_velocimeter:
+///         rate: 5.0
+///         max_range: 8000.0
+///         max_speed: 1000.0
+///         max_tilt: 0.7854            # 45 deg
+///         boresight: 0.0
+///         noise: 0.3
+///         noise_ratio: 0.01
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorsConf {
+    pub seed: u64,
+    pub accelerometer: Option<ImuErrorConf>,            // m/s**2
+    pub gyro: Option<ImuErrorConf>,                     // rad/s
+    pub radar_altimeter: Option<RadarAltimeterConf>,
+    pub doppler_velocimeter: Option<DopplerVelocimeterConf>,
+}
+
+/// Error model of a 3-axis inertial sensor, mounted along the body axes
+///
+/// Values are 1-sigma, in SI units of the measured quantity (unit). The
+/// turn-on bias, the scale factor and the misalignment are drawn once, per
+/// axis, when the sensor is created.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImuErrorConf {
+    pub noise_density: f64,         // white noise, unit/sqrt(Hz), sampled at 1/dt
+    pub bias: f64,                  // turn-on bias, unit
+    pub bias_instability: f64,      // first-order Gauss-Markov bias, unit (steady state)
+    pub bias_time: Time,            // correlation time of the bias instability
+    pub scale_factor: Ratio,
+    pub misalignment: Angle,        // per axis, small rotation of the sensor axes
+    pub quantization: f64,          // resolution (LSB), unit, 0 for none
+}
+
+
+/// Radar altimeter, measuring the range to the terrain along its beam
+///
+/// The antenna boresight is the body -x axis (below the engine), tilted toward
+/// the body +y axis by `boresight`. The nearest echo inside the beam is the
+/// vertical while the boresight is less than `beam_width` from it, then the
+/// slant range at the edge of the beam. The echo gets weaker with the tilt:
+/// the probability of a dropout grows linearly from 0 at `beam_width` to 1 at
+/// `max_tilt`. No echo beyond `max_range`.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RadarAltimeterConf {
+    pub rate: Frequency,            // measurements per second
+    pub max_range: Length,
+    pub beam_width: Angle,          // half-angle of the beam cone
+    pub max_tilt: Angle,            // boresight from the vertical
+    pub boresight: Angle,           // antenna tilt, from the body -x axis toward +y
+    pub noise: Length,              // 1-sigma
+    pub noise_ratio: Ratio,         // 1-sigma, proportional to the range
+}
+
+
+/// Doppler velocimeter, measuring the surface-relative velocity (body frame)
+///
+/// The beams are not modeled: the measurement is the true 3 axis velocity, plus
+/// a white noise drawn independently on each axis (1-sigma `noise`, plus
+/// `noise_ratio` times the speed). It needs an echo along the boresight (same
+/// convention as the radar altimeter): less than `max_tilt` from the vertical,
+/// and slant range below `max_range`. No measurement above `max_speed`.
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DopplerVelocimeterConf {
+    pub rate: Frequency,            // measurements per second
+    pub max_range: Length,
+    pub max_speed: Velocity,
+    pub max_tilt: Angle,            // boresight from the vertical
+    pub boresight: Angle,           // antenna tilt, from the body -x axis toward +y
+    pub noise: Velocity,            // 1-sigma, per axis
+    pub noise_ratio: Ratio,         // 1-sigma, proportional to the speed
+}
+
+
+/// Sensor errors, drawn from an ImuErrorConf
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct ImuError {
+    conf: ImuErrorConf,
+    bias: Vec3<f64>,                // turn-on bias
+    bias_gm: Vec3<f64>,             // Gauss-Markov bias, changing
+    scale_factor: Vec3<f64>,
+    misalignment: Quaternion,
+}
+
+/// Error models of all the simulated sensors, sharing a seeded random generator
+pub struct Sensors {
+    rng: StdRng,
+    accelerometer: Option<ImuError>,
+    gyro: Option<ImuError>,
+    radar_altimeter: Option<RadarAltimeterConf>,
+    radar_altimeter_t: Time,        // since the last measurement
+    doppler_velocimeter: Option<DopplerVelocimeterConf>,
+    doppler_velocimeter_t: Time,    // since the last measurement
+}
+
+
+fn normal(rng: &mut StdRng) -> f64 {
+    StandardNormal.sample(rng)
+}
+
+fn normal_vec(rng: &mut StdRng, sigma: f64) -> Vec3<f64> {
+    Vec3 {
+        x: sigma*normal(rng),
+        y: sigma*normal(rng),
+        z: sigma*normal(rng),
+    }
+}
+
+
+impl ImuError {
+    pub fn new(conf: &ImuErrorConf, rng: &mut StdRng) -> Self {
+        let bias = normal_vec(rng, conf.bias);
+        let bias_gm = normal_vec(rng, conf.bias_instability);
+        let scale_factor = normal_vec(rng, conf.scale_factor.get::<ratio>());
+        let misalignment = normal_vec(rng, conf.misalignment.get::<radian>());
+
+        ImuError {
+            conf: *conf,
+            bias: bias,
+            bias_gm: bias_gm,
+            scale_factor: scale_factor,
+            misalignment: Quaternion::from_axis_angle(misalignment, Angle::new::<radian>(misalignment.norm())),
+        }
+    }
+
+    /// Measurement of a true value (body frame), sampled every dt
+    ///
+    /// measured = quantize((1+scale_factor) * misalignment(true) + bias + bias_gm + noise)
+    pub fn measure(&mut self, rng: &mut StdRng, dt: Time, val: Vec3<f64>) -> Vec3<f64> {
+        let dt = dt.get::<second>();
+
+        // first-order Gauss-Markov
+        let tau = self.conf.bias_time.get::<second>();
+        if self.conf.bias_instability > 0.0 && tau > 0.0 {
+            let k = (-dt/tau).exp();
+            let sigma = self.conf.bias_instability*(1.0-k*k).sqrt();
+            self.bias_gm = self.bias_gm*k + normal_vec(rng, sigma);
+        }
+
+        let noise = normal_vec(rng, self.conf.noise_density/dt.sqrt());
+
+        let v = self.misalignment.rotate(val);
+        let v = Vec3 {
+            x: v.x*(1.0+self.scale_factor.x),
+            y: v.y*(1.0+self.scale_factor.y),
+            z: v.z*(1.0+self.scale_factor.z),
+        };
+        let v = v + self.bias + self.bias_gm + noise;
+
+        let q = self.conf.quantization;
+        if q > 0.0 {
+            v.map(|x| (x/q).round()*q)
+        } else {
+            v
+        }
+    }
+}
+
+
+impl Sensors {
+    pub fn new(conf: &SensorsConf) -> Self {
+        let mut rng = StdRng::seed_from_u64(conf.seed);
+
+        let accelerometer = conf.accelerometer.map(|c| ImuError::new(&c, &mut rng));
+        let gyro = conf.gyro.map(|c| ImuError::new(&c, &mut rng));
+
+        Sensors {
+            rng: rng,
+            accelerometer: accelerometer,
+            gyro: gyro,
+            radar_altimeter: conf.radar_altimeter,
+            radar_altimeter_t: Time::new::<second>(0.0),
+            doppler_velocimeter: conf.doppler_velocimeter,
+            doppler_velocimeter_t: Time::new::<second>(0.0),
+        }
+    }
+
+    /// Specific force (acceleration less gravity) measured by the accelerometers, body frame
+    pub fn accelerometer(&mut self, dt: Time, acc: Vec3<Acceleration>) -> Vec3<Acceleration> {
+        match self.accelerometer.as_mut() {
+            None => acc,
+            Some(s) => Vec3::from_value(s.measure(&mut self.rng, dt, acc.value())),
+        }
+    }
+
+    /// Angular velocity (inertial) measured by the gyroscopes, body frame
+    ///
+    /// The noise density of the gyroscopes is the angle random walk (rad/sqrt(s)).
+    pub fn gyro(&mut self, dt: Time, ang_vel: Vec3<AngularVelocity>) -> Vec3<AngularVelocity> {
+        match self.gyro.as_mut() {
+            None => ang_vel,
+            Some(s) => Vec3::from_value(s.measure(&mut self.rng, dt, ang_vel.value())),
+        }
+    }
+
+    /// Range measured by the radar altimeter, None if no measurement this step
+    ///
+    /// Inputs:
+    ///     altitude: height above the terrain, below the spacecraft
+    ///     att: attitude of the spacecraft (body to LVLH)
+    pub fn radar_altimeter(&mut self, dt: Time, altitude: Option<Length>, att: Quaternion) -> Option<Length> {
+        let conf = match self.radar_altimeter {
+            None => return altitude,
+            Some(conf) => conf,
+        };
+        let altitude = altitude?;
+
+        // measurement rate
+        self.radar_altimeter_t += dt;
+        if self.radar_altimeter_t*conf.rate < Ratio::new::<ratio>(1.0 - 1e-9) {
+            return None;
+        }
+        self.radar_altimeter_t = Time::new::<second>(0.0);
+
+        // beam geometry
+        let tilt = boresight_tilt(conf.boresight, att);
+        let beam_width = conf.beam_width.get::<radian>();
+        let max_tilt = conf.max_tilt.get::<radian>();
+
+        if tilt >= max_tilt {
+            return None;
+        }
+        let p_dropout = ((tilt-beam_width)/(max_tilt-beam_width)).max(0.0);
+        if self.rng.gen::<f64>() < p_dropout {
+            return None;
+        }
+
+        let range = if tilt <= beam_width {
+            altitude
+        } else {
+            altitude/(tilt-beam_width).cos()
+        };
+        if range > conf.max_range {
+            return None;
+        }
+
+        let sigma = conf.noise.get::<meter>() + conf.noise_ratio.get::<ratio>()*range.get::<meter>();
+        Some(range + Length::new::<meter>(sigma*normal(&mut self.rng)))
+    }
+
+    /// Velocity measured by the Doppler velocimeter (body frame), None if no
+    /// measurement this step
+    ///
+    /// Inputs:
+    ///     vel: surface-relative velocity, body frame
+    ///     altitude: height above the terrain, below the spacecraft (range not
+    ///         checked if unknown)
+    ///     att: attitude of the spacecraft (body to LVLH)
+    pub fn doppler_velocimeter(&mut self, dt: Time, vel: Option<Vec3<Velocity>>, altitude: Option<Length>, att: Quaternion) -> Option<Vec3<Velocity>> {
+        let conf = match self.doppler_velocimeter {
+            None => return vel,
+            Some(conf) => conf,
+        };
+        let vel = vel?;
+
+        // measurement rate
+        self.doppler_velocimeter_t += dt;
+        if self.doppler_velocimeter_t*conf.rate < Ratio::new::<ratio>(1.0 - 1e-9) {
+            return None;
+        }
+        self.doppler_velocimeter_t = Time::new::<second>(0.0);
+
+        // valid range
+        let tilt = boresight_tilt(conf.boresight, att);
+        if tilt >= conf.max_tilt.get::<radian>() {
+            return None;
+        }
+        if let Some(altitude) = altitude {
+            if altitude/tilt.cos() > conf.max_range {
+                return None;
+            }
+        }
+        let speed = vel.norm();
+        if speed > conf.max_speed {
+            return None;
+        }
+
+        let sigma = conf.noise.get::<meter_per_second>() + conf.noise_ratio.get::<ratio>()*speed.get::<meter_per_second>();
+        Some(vel + Vec3::from_value(normal_vec(&mut self.rng, sigma)))
+    }
+}
+
+
+/// Angle between the antenna boresight and the local vertical (down)
+///
+/// The boresight is the body -x axis, tilted toward the body +y axis.
+pub fn boresight_tilt(boresight: Angle, att: Quaternion) -> f64 {
+    let b = boresight.get::<radian>();
+    let dir = att.rotate(Vec3 {x: -b.cos(), y: b.sin(), z: 0.0});
+    (-dir.y).clamp(-1.0, 1.0).acos()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::acceleration::meter_per_second_squared;
+    use uom::si::angle::degree;
+    use uom::si::frequency::hertz;
+
+    use crate::assert_approx_eq;
+
+    fn conf(seed: u64, imu: ImuErrorConf) -> SensorsConf {
+        SensorsConf {
+            seed: seed,
+            accelerometer: Some(imu),
+            gyro: None,
+            radar_altimeter: None,
+            doppler_velocimeter: None,
+        }
+    }
+
+    fn imu_ideal() -> ImuErrorConf {
+        ImuErrorConf {
+            noise_density: 0.0,
+            bias: 0.0,
+            bias_instability: 0.0,
+            bias_time: Time::new::<second>(0.0),
+            scale_factor: Ratio::new::<ratio>(0.0),
+            misalignment: Angle::new::<radian>(0.0),
+            quantization: 0.0,
+        }
+    }
+
+    fn acc(x: f64, y: f64, z: f64) -> Vec3<Acceleration> {
+        Vec3::from_value(Vec3 {x: x, y: y, z: z})
+    }
+
+    #[test]
+    fn test_sensors_1_reproducible() {
+        let dt = Time::new::<second>(0.1);
+        let imu = ImuErrorConf {
+            noise_density: 1e-3,
+            bias: 1e-3,
+            bias_instability: 1e-4,
+            bias_time: Time::new::<second>(100.0),
+            scale_factor: Ratio::new::<ratio>(1e-3),
+            misalignment: Angle::new::<radian>(1e-3),
+            quantization: 0.0,
+        };
+
+        let mut a = Sensors::new(&conf(42, imu));
+        let mut b = Sensors::new(&conf(42, imu));
+        let mut c = Sensors::new(&conf(43, imu));
+
+        for _ in 0..10 {
+            let va = a.accelerometer(dt, acc(1.0, 2.0, 3.0));
+            let vb = b.accelerometer(dt, acc(1.0, 2.0, 3.0));
+            let vc = c.accelerometer(dt, acc(1.0, 2.0, 3.0));
+            assert_eq!(va.value().x, vb.value().x);
+            assert_eq!(va.value().z, vb.value().z);
+            assert!(va.value().x != vc.value().x);
+        }
+
+        // ideal sensors
+        let mut ideal = Sensors::new(&conf(42, imu_ideal()));
+        assert_eq!(ideal.accelerometer(dt, acc(1.0, 2.0, 3.0)).value().y, 2.0);
+        assert_eq!(ideal.gyro(dt, Vec3::from_value(Vec3 {x: 1.0, y: 2.0, z: 3.0})).value().z, 3.0);
+    }
+
+    #[test]
+    fn test_sensors_2_error_terms() {
+        let dt = Time::new::<second>(0.01);
+        let n = 10_000;
+
+        // white noise: std = noise_density/sqrt(dt)
+        let mut s = Sensors::new(&conf(1, ImuErrorConf {noise_density: 1e-3, ..imu_ideal()}));
+        let samples: Vec<f64> = (0..n).map(|_| s.accelerometer(dt, acc(0.0, 0.0, 0.0)).x.get::<meter_per_second_squared>()).collect();
+        let mean = samples.iter().sum::<f64>()/(n as f64);
+        let std = (samples.iter().map(|x| (x-mean).powi(2)).sum::<f64>()/(n as f64)).sqrt();
+        assert_approx_eq!(mean, 0.0, 1e-3);
+        assert_approx_eq!(std, 1e-2, 5e-4);
+
+        // scale factor and misalignment, keep the norm close
+        let mut s = Sensors::new(&conf(1, ImuErrorConf {
+            scale_factor: Ratio::new::<ratio>(1e-3),
+            misalignment: Angle::new::<radian>(1e-3),
+            ..imu_ideal()
+        }));
+        let v = s.accelerometer(dt, acc(10.0, 0.0, 0.0)).value();
+        assert!(v.x != 10.0 && v.y != 0.0);
+        assert_approx_eq!(v.norm(), 10.0, 0.05);
+
+        // quantization
+        let mut s = Sensors::new(&conf(1, ImuErrorConf {noise_density: 1e-3, quantization: 0.01, ..imu_ideal()}));
+        let v = s.accelerometer(dt, acc(1.234, 0.0, 0.0)).value();
+        assert_approx_eq!((v.x/0.01).round()*0.01, v.x, 1e-12);
+    }
+
+    #[test]
+    fn test_sensors_3_radar_altimeter() {
+        let dt = Time::new::<second>(0.1);
+        let radar = RadarAltimeterConf {
+            rate: Frequency::new::<hertz>(5.0),
+            max_range: Length::new::<meter>(1000.0),
+            beam_width: Angle::new::<degree>(10.0),
+            max_tilt: Angle::new::<degree>(40.0),
+            boresight: Angle::new::<degree>(0.0),
+            noise: Length::new::<meter>(0.0),
+            noise_ratio: Ratio::new::<ratio>(0.0),
+        };
+        let mut s = Sensors::new(&SensorsConf {
+            seed: 1,
+            accelerometer: None,
+            gyro: None,
+            radar_altimeter: Some(radar),
+            doppler_velocimeter: None,
+        });
+
+        // upright (pitch 90 deg): the boresight is the vertical, every other step at 5 Hz
+        let upright = Quaternion::from_euler(Angle::new::<degree>(0.0), Angle::new::<degree>(90.0), Angle::new::<degree>(0.0));
+        let meas: Vec<Option<Length>> = (0..10).map(|_| s.radar_altimeter(dt, Some(Length::new::<meter>(500.0)), upright)).collect();
+        assert_eq!(meas.iter().filter(|m| m.is_some()).count(), 5);
+        assert_approx_eq!(meas[1].unwrap(), Length::new::<meter>(500.0), Length::new::<meter>(1e-9));
+
+        // out of range
+        s.radar_altimeter(dt, Some(Length::new::<meter>(1500.0)), upright);
+        assert!(s.radar_altimeter(dt, Some(Length::new::<meter>(1500.0)), upright).is_none());
+
+        // tilted 20 deg: slant range at the edge of the beam (10 deg), or a dropout
+        let tilted = Quaternion::from_euler(Angle::new::<degree>(0.0), Angle::new::<degree>(70.0), Angle::new::<degree>(0.0));
+        let meas: Vec<Option<Length>> = (0..1000).map(|_| s.radar_altimeter(dt, Some(Length::new::<meter>(500.0)), tilted)).collect();
+        let echoes: Vec<Length> = meas.iter().filter_map(|m| *m).collect();
+        assert!(echoes.len() > 250 && echoes.len() < 450);  // 2/3 of 500
+        assert_approx_eq!(echoes[0], Length::new::<meter>(500.0/(10.0_f64).to_radians().cos()), Length::new::<meter>(1e-9));
+
+        // beyond the max tilt: never
+        let horizontal = Quaternion::from_euler(Angle::new::<degree>(0.0), Angle::new::<degree>(180.0), Angle::new::<degree>(0.0));
+        assert!((0..10).all(|_| s.radar_altimeter(dt, Some(Length::new::<meter>(500.0)), horizontal).is_none()));
+    }
+
+    #[test]
+    fn test_sensors_4_doppler_velocimeter() {
+        let dt = Time::new::<second>(0.1);
+        let doppler = DopplerVelocimeterConf {
+            rate: Frequency::new::<hertz>(10.0),
+            max_range: Length::new::<meter>(1000.0),
+            max_speed: Velocity::new::<meter_per_second>(100.0),
+            max_tilt: Angle::new::<degree>(45.0),
+            boresight: Angle::new::<degree>(0.0),
+            noise: Velocity::new::<meter_per_second>(0.1),
+            noise_ratio: Ratio::new::<ratio>(0.0),
+        };
+        let mut s = Sensors::new(&SensorsConf {
+            seed: 1,
+            accelerometer: None,
+            gyro: None,
+            radar_altimeter: None,
+            doppler_velocimeter: Some(doppler),
+        });
+
+        let upright = Quaternion::from_euler(Angle::new::<degree>(0.0), Angle::new::<degree>(90.0), Angle::new::<degree>(0.0));
+        let vel: Vec3<Velocity> = Vec3::from_value(Vec3 {x: -10.0, y: 2.0, z: 0.0});
+        let altitude = Some(Length::new::<meter>(500.0));
+
+        // noisy, around the true velocity
+        let n = 1000;
+        let sum = (0..n).fold(0.0, |acc, _| acc + s.doppler_velocimeter(dt, Some(vel), altitude, upright).unwrap().x.value);
+        assert_approx_eq!(sum/(n as f64), -10.0, 0.02);
+        assert!(s.doppler_velocimeter(dt, Some(vel), altitude, upright).unwrap().x.value != -10.0);
+
+        // out of range, too tilted, too fast
+        let tilted = Quaternion::from_euler(Angle::new::<degree>(0.0), Angle::new::<degree>(30.0), Angle::new::<degree>(0.0));
+        let fast: Vec3<Velocity> = Vec3::from_value(Vec3 {x: -150.0, y: 0.0, z: 0.0});
+        assert!(s.doppler_velocimeter(dt, Some(vel), Some(Length::new::<meter>(1500.0)), upright).is_none());
+        assert!(s.doppler_velocimeter(dt, Some(vel), altitude, tilted).is_none());
+        assert!(s.doppler_velocimeter(dt, Some(fast), altitude, upright).is_none());
+
+        // ideal
+        let mut ideal = Sensors::new(&conf(1, imu_ideal()));
+        assert_eq!(ideal.doppler_velocimeter(dt, Some(vel), altitude, tilted).unwrap().x.value, -10.0);
+    }
+}
@@ -0,0 +1,575 @@
+/// Cone of a block of rows of G*x - h
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum Cone {
+    NonNegative(usize),             // rows
+    SecondOrder(usize),             // rows (t, w): t >= |w|
+}
+
+
+/// Sparse row: (column, value)
+pub type Row = Vec<(usize, f64)>;
+
+
+/// Second-order cone program
+///
+///     min c'x
+///     s.t. E*x = f
+///          G*x - h in K (cartesian product of the cones, in the order of the rows)
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Socp {
+    pub c: Vec<f64>,
+    pub e: Vec<Row>,
+    pub f: Vec<f64>,
+    pub g: Vec<Row>,
+    pub h: Vec<f64>,
+    pub cones: Vec<Cone>,
+}
+
+
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(PartialEq)]
+pub enum SocpStatus {
+    Solved,
+    MaxIterations,                  // infeasible (diverges), or not converged
+}
+
+
+const IPM_ITERATIONS: usize = 50;
+const IPM_EPS_FEAS: f64 = 1e-7;     // relative residuals
+const IPM_EPS_ABS: f64 = 1e-7;      // duality gap
+const IPM_EPS_REL: f64 = 1e-6;      // duality gap, relative to the cost
+const IPM_STEP: f64 = 0.99;         // of the step to the boundary of the cone
+const IPM_REG: f64 = 1e-10;         // regularization of the KKT system
+
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x*y).sum()
+}
+
+
+fn norm_inf(v: &[f64]) -> f64 {
+    v.iter().fold(0.0, |acc: f64, a| acc.max(a.abs()))
+}
+
+
+/// LU factorization with partial pivoting, in place (row major, n x n)
+fn lu_factor(a: &mut [f64], n: usize) -> Vec<usize> {
+    let mut piv: Vec<usize> = (0..n).collect();
+
+    for k in 0..n {
+        let p = (k..n).max_by(|&i, &j| a[i*n+k].abs().total_cmp(&a[j*n+k].abs())).unwrap();
+        if p != k {
+            for j in 0..n {
+                a.swap(k*n+j, p*n+j);
+            }
+            piv.swap(k, p);
+        }
+
+        let pivot = a[k*n+k];
+        for i in (k+1)..n {
+            let l = a[i*n+k]/pivot;
+            a[i*n+k] = l;
+            if l != 0.0 {
+                for j in (k+1)..n {
+                    a[i*n+j] -= l*a[k*n+j];
+                }
+            }
+        }
+    }
+
+    piv
+}
+
+
+fn lu_solve(lu: &[f64], piv: &[usize], n: usize, b: &[f64]) -> Vec<f64> {
+    let mut x: Vec<f64> = piv.iter().map(|&p| b[p]).collect();
+
+    for i in 0..n {
+        for j in 0..i {
+            x[i] -= lu[i*n+j]*x[j];
+        }
+    }
+    for i in (0..n).rev() {
+        for j in (i+1)..n {
+            x[i] -= lu[i*n+j]*x[j];
+        }
+        x[i] /= lu[i*n+i];
+    }
+
+    x
+}
+
+
+/// Nesterov-Todd scaling of a cone block: W*z = W^-1*s = lambda
+enum Scaling {
+    NonNegative(Vec<f64>),              // diagonal
+    SecondOrder(usize, Vec<f64>, Vec<f64>), // rows, W, W^-1 (row major)
+}
+
+
+/// The cones of a problem, and their (Jordan) algebra
+struct Cones {
+    blocks: Vec<(usize, usize, Cone)>,  // first row, rows, cone
+    rows: usize,
+}
+
+
+impl Cones {
+    fn new(cones: &[Cone]) -> Self {
+        let mut blocks = vec![];
+        let mut start = 0;
+        for cone in cones {
+            let rows = match cone {
+                Cone::NonNegative(rows) | Cone::SecondOrder(rows) => *rows,
+            };
+            blocks.push((start, rows, *cone));
+            start += rows;
+        }
+        Cones {
+            blocks: blocks,
+            rows: start,
+        }
+    }
+
+    /// Degree of the cone (of its barrier)
+    fn degree(&self) -> f64 {
+        self.blocks.iter().map(|(_, rows, cone)| match cone {
+            Cone::NonNegative(_) => *rows,
+            Cone::SecondOrder(_) => 1,
+        }).sum::<usize>() as f64
+    }
+
+    /// Identity element
+    fn identity(&self) -> Vec<f64> {
+        let mut e = vec![0.0; self.rows];
+        for &(start, rows, cone) in &self.blocks {
+            match cone {
+                Cone::NonNegative(_) => {
+                    for a in &mut e[start..start+rows] {
+                        *a = 1.0;
+                    }
+                },
+                Cone::SecondOrder(_) => e[start] = 1.0,
+            }
+        }
+        e
+    }
+
+    /// Largest distance outside of the cone, along the identity (< 0 inside)
+    fn outside(&self, s: &[f64]) -> f64 {
+        let mut out = f64::NEG_INFINITY;
+        for &(start, rows, cone) in &self.blocks {
+            match cone {
+                Cone::NonNegative(_) => {
+                    for a in &s[start..start+rows] {
+                        out = out.max(-a);
+                    }
+                },
+                Cone::SecondOrder(_) => {
+                    let w = &s[start+1..start+rows];
+                    out = out.max(dot(w, w).sqrt() - s[start]);
+                },
+            }
+        }
+        out
+    }
+
+    /// Jordan product u o v
+    fn product(&self, u: &[f64], v: &[f64]) -> Vec<f64> {
+        let mut out = vec![0.0; self.rows];
+        for &(start, rows, cone) in &self.blocks {
+            match cone {
+                Cone::NonNegative(_) => {
+                    for i in start..start+rows {
+                        out[i] = u[i]*v[i];
+                    }
+                },
+                Cone::SecondOrder(_) => {
+                    out[start] = dot(&u[start..start+rows], &v[start..start+rows]);
+                    for i in start+1..start+rows {
+                        out[i] = u[start]*v[i] + v[start]*u[i];
+                    }
+                },
+            }
+        }
+        out
+    }
+
+    /// Solve u o x = v
+    fn divide(&self, u: &[f64], v: &[f64]) -> Vec<f64> {
+        let mut out = vec![0.0; self.rows];
+        for &(start, rows, cone) in &self.blocks {
+            match cone {
+                Cone::NonNegative(_) => {
+                    for i in start..start+rows {
+                        out[i] = v[i]/u[i];
+                    }
+                },
+                Cone::SecondOrder(_) => {
+                    let (u0, u1) = (u[start], &u[start+1..start+rows]);
+                    let (v0, v1) = (v[start], &v[start+1..start+rows]);
+                    let x0 = (u0*v0 - dot(u1, v1))/(u0*u0 - dot(u1, u1));
+                    out[start] = x0;
+                    for i in 1..rows {
+                        out[start+i] = (v1[i-1] - x0*u1[i-1])/u0;
+                    }
+                },
+            }
+        }
+        out
+    }
+
+    /// Largest step a such that s + a*ds stays in the cone
+    fn max_step(&self, s: &[f64], ds: &[f64]) -> f64 {
+        let mut step = f64::INFINITY;
+        for &(start, rows, cone) in &self.blocks {
+            match cone {
+                Cone::NonNegative(_) => {
+                    for i in start..start+rows {
+                        if ds[i] < 0.0 {
+                            step = step.min(-s[i]/ds[i]);
+                        }
+                    }
+                },
+                Cone::SecondOrder(_) => {
+                    // (s0 + a*d0)**2 - |s1 + a*d1|**2 >= 0, and s0 + a*d0 >= 0
+                    let (s0, s1) = (s[start], &s[start+1..start+rows]);
+                    let (d0, d1) = (ds[start], &ds[start+1..start+rows]);
+                    let a = d0*d0 - dot(d1, d1);
+                    let b = s0*d0 - dot(s1, d1);
+                    let c = s0*s0 - dot(s1, s1);
+
+                    if d0 < 0.0 {
+                        step = step.min(-s0/d0);
+                    }
+                    if a.abs() < 1e-14 {
+                        if b < 0.0 {
+                            step = step.min(-c/(2.0*b));
+                        }
+                    } else if b*b - a*c >= 0.0 {
+                        let delta = (b*b - a*c).sqrt();
+                        for root in &[(-b - delta)/a, (-b + delta)/a] {
+                            if *root > 0.0 {
+                                step = step.min(*root);
+                            }
+                        }
+                    }
+                },
+            }
+        }
+        step
+    }
+
+    fn scaling(&self, s: &[f64], z: &[f64]) -> Vec<Scaling> {
+        self.blocks.iter().map(|&(start, rows, cone)| match cone {
+            Cone::NonNegative(_) => {
+                Scaling::NonNegative((start..start+rows).map(|i| (s[i]/z[i]).sqrt()).collect())
+            },
+            Cone::SecondOrder(_) => {
+                let (s, z) = (&s[start..start+rows], &z[start..start+rows]);
+                let j = |i: usize| if i == 0 { 1.0 } else { -1.0 };
+                let jnorm = |v: &[f64]| (v[0]*v[0] - dot(&v[1..], &v[1..])).sqrt();
+                let (sn, zn) = (jnorm(s), jnorm(z));
+                let gamma = ((1.0 + dot(s, z)/(sn*zn))/2.0).sqrt();
+                let w: Vec<f64> = (0..rows).map(|i| (s[i]/sn + j(i)*z[i]/zn)/(2.0*gamma)).collect();
+                let beta = (sn/zn).sqrt();
+
+                // square root of w: v = (w + e)/sqrt(2*(w0 + 1))
+                let norm = (2.0*(w[0] + 1.0)).sqrt();
+                let w: Vec<f64> = (0..rows).map(|i| (w[i] + if i == 0 { 1.0 } else { 0.0 })/norm).collect();
+
+                // W = beta*(2*v*v' - J), W^-1 = (2*J*v*v'*J - J)/beta
+                let mut fwd = vec![0.0; rows*rows];
+                let mut inv = vec![0.0; rows*rows];
+                for a in 0..rows {
+                    for b in 0..rows {
+                        let diag = if a == b { j(a) } else { 0.0 };
+                        fwd[a*rows+b] = beta*(2.0*w[a]*w[b] - diag);
+                        inv[a*rows+b] = (2.0*j(a)*w[a]*w[b]*j(b) - diag)/beta;
+                    }
+                }
+                Scaling::SecondOrder(rows, fwd, inv)
+            },
+        }).collect()
+    }
+
+    /// W*v, or W^-1*v
+    fn scale(&self, scaling: &[Scaling], v: &[f64], inverse: bool) -> Vec<f64> {
+        let mut out = vec![0.0; self.rows];
+        for (&(start, _, _), sc) in self.blocks.iter().zip(scaling) {
+            match sc {
+                Scaling::NonNegative(w) => {
+                    for (i, wi) in w.iter().enumerate() {
+                        out[start+i] = if inverse { v[start+i]/wi } else { v[start+i]*wi };
+                    }
+                },
+                Scaling::SecondOrder(rows, fwd, inv) => {
+                    let m = if inverse { inv } else { fwd };
+                    for i in 0..*rows {
+                        out[start+i] = (0..*rows).map(|j| m[i*rows+j]*v[start+j]).sum();
+                    }
+                },
+            }
+        }
+        out
+    }
+}
+
+
+/// Primal-dual interior point solver
+///
+/// Infeasible start, Mehrotra predictor-corrector and Nesterov-Todd scaling.
+/// Dense linear algebra, for small problems (a few hundreds of variables)
+/// solved in a few tens of iterations. An infeasible problem is not detected
+/// as such, it does not converge.
+///
+/// Source: Vandenberghe, The CVXOPT linear and quadratic cone program solvers,
+/// 2010 (coneqp, without the quadratic term)
+pub fn solve(p: &Socp) -> (SocpStatus, Vec<f64>) {
+    let n = p.c.len();
+    let ne = p.e.len();
+    let cones = Cones::new(&p.cones);
+    let m = cones.rows;
+    assert_eq!(m, p.g.len(), "the cones do not cover G");
+
+    // G*x + s = h with s in K, as in the source
+    let g: Vec<Row> = p.g.iter().map(|row| row.iter().map(|&(j, a)| (j, -a)).collect()).collect();
+    let h: Vec<f64> = p.h.iter().map(|a| -a).collect();
+
+    let mul = |rows: &[Row], x: &[f64]| -> Vec<f64> {
+        rows.iter().map(|row| row.iter().map(|&(j, a)| a*x[j]).sum()).collect()
+    };
+    let mul_t = |rows: &[Row], y: &[f64]| -> Vec<f64> {
+        let mut out = vec![0.0; n];
+        for (row, yi) in rows.iter().zip(y) {
+            for &(j, a) in row {
+                out[j] += a*yi;
+            }
+        }
+        out
+    };
+
+    // KKT matrix [G'*W^-2*G, E'; E, 0], W = I for the initial point
+    let factor = |scaling: Option<&[Scaling]>| -> (Vec<f64>, Vec<usize>) {
+        let size = n + ne;
+        let mut k = vec![0.0; size*size];
+
+        for (b, &(start, rows, _)) in cones.blocks.iter().enumerate() {
+            let mut block = vec![vec![0.0; n]; rows];
+            for (i, row) in g[start..start+rows].iter().enumerate() {
+                for &(j, a) in row {
+                    block[i][j] = a;
+                }
+            }
+            match scaling.map(|scaling| &scaling[b]) {
+                None => (),
+                Some(Scaling::NonNegative(w)) => {
+                    for (row, wi) in block.iter_mut().zip(w) {
+                        for a in row.iter_mut() {
+                            *a /= wi;
+                        }
+                    }
+                },
+                Some(Scaling::SecondOrder(_, _, inv)) => {
+                    block = (0..rows).map(|i| (0..n).map(|j| {
+                        (0..rows).map(|l| inv[i*rows+l]*block[l][j]).sum()
+                    }).collect()).collect();
+                },
+            }
+
+            for row in &block {
+                let nz: Vec<(usize, f64)> = row.iter().cloned().enumerate().filter(|&(_, a)| a != 0.0).collect();
+                for &(i, a) in &nz {
+                    for &(j, b) in &nz {
+                        k[i*size+j] += a*b;
+                    }
+                }
+            }
+        }
+        for i in 0..n {
+            k[i*size+i] += IPM_REG;
+        }
+        for (r, row) in p.e.iter().enumerate() {
+            for &(j, a) in row {
+                k[(n+r)*size+j] = a;
+                k[j*size+n+r] = a;
+            }
+            k[(n+r)*size+n+r] = -IPM_REG;
+        }
+
+        let piv = lu_factor(&mut k, size);
+        (k, piv)
+    };
+
+    // initial point: least squares s = h - G*x, shifted inside the cone
+
+    let (lu, piv) = factor(None);
+    let mut rhs = mul_t(&g, &h);
+    rhs.extend_from_slice(&p.f);
+    let mut x = lu_solve(&lu, &piv, n+ne, &rhs)[..n].to_vec();
+    let mut y = vec![0.0; ne];
+
+    let e = cones.identity();
+    let gx = mul(&g, &x);
+    let mut s: Vec<f64> = (0..m).map(|i| h[i] - gx[i]).collect();
+    let shift = cones.outside(&s);
+    if shift >= -1e-8 {
+        for i in 0..m {
+            s[i] += (1.0 + shift)*e[i];
+        }
+    }
+    let mut z = e.clone();
+
+    let c_norm = norm_inf(&p.c).max(1.0);
+    let f_norm = norm_inf(&p.f).max(1.0);
+    let h_norm = norm_inf(&h).max(1.0);
+
+    for _ in 0..IPM_ITERATIONS {
+        let gtz = mul_t(&g, &z);
+        let ety = mul_t(&p.e, &y);
+        let rx: Vec<f64> = (0..n).map(|i| p.c[i] + ety[i] + gtz[i]).collect();
+        let ex = mul(&p.e, &x);
+        let ry: Vec<f64> = (0..ne).map(|i| ex[i] - p.f[i]).collect();
+        let gx = mul(&g, &x);
+        let rz: Vec<f64> = (0..m).map(|i| gx[i] + s[i] - h[i]).collect();
+        let gap = dot(&s, &z);
+        let mu = gap/cones.degree();
+
+        if (norm_inf(&rx) < IPM_EPS_FEAS*c_norm)
+            && (norm_inf(&ry) < IPM_EPS_FEAS*f_norm)
+            && (norm_inf(&rz) < IPM_EPS_FEAS*h_norm)
+            && ((gap < IPM_EPS_ABS) || (gap < IPM_EPS_REL*dot(&p.c, &x).abs()))
+        {
+            return (SocpStatus::Solved, x);
+        }
+
+        // Newton directions, with the same factorization
+        //     E'*dy + G'*dz = bx
+        //     E*dx = by
+        //     G*dx + ds = bz
+        //     lambda o (W*dz + W^-1*ds) = bs
+
+        let scaling = cones.scaling(&s, &z);
+        let lambda = cones.scale(&scaling, &z, false);
+        let (lu, piv) = factor(Some(&scaling));
+
+        let newton = |bx: &[f64], by: &[f64], bz: &[f64], bs: &[f64]| {
+            let wt = cones.scale(&scaling, &cones.divide(&lambda, bs), false);
+            let bz: Vec<f64> = (0..m).map(|i| bz[i] - wt[i]).collect();
+            let w2bz = cones.scale(&scaling, &cones.scale(&scaling, &bz, true), true);
+            let gtw = mul_t(&g, &w2bz);
+
+            let mut rhs: Vec<f64> = (0..n).map(|i| bx[i] + gtw[i]).collect();
+            rhs.extend_from_slice(by);
+            let sol = lu_solve(&lu, &piv, n+ne, &rhs);
+            let (dx, dy) = (sol[..n].to_vec(), sol[n..].to_vec());
+
+            // dz = W^-2*(G*dx - bz), and ds from the linear equation (more
+            // accurate than W*t - W**2*dz close to the solution)
+            let gdx = mul(&g, &dx);
+            let r: Vec<f64> = (0..m).map(|i| gdx[i] - bz[i]).collect();
+            let dz = cones.scale(&scaling, &cones.scale(&scaling, &r, true), true);
+            let ds: Vec<f64> = (0..m).map(|i| bz[i] + wt[i] - gdx[i]).collect();
+
+            (dx, dy, dz, ds)
+        };
+
+        let bx: Vec<f64> = rx.iter().map(|a| -a).collect();
+        let by: Vec<f64> = ry.iter().map(|a| -a).collect();
+        let bz: Vec<f64> = rz.iter().map(|a| -a).collect();
+
+        // predictor (affine scaling)
+
+        let ll = cones.product(&lambda, &lambda);
+        let bs: Vec<f64> = ll.iter().map(|a| -a).collect();
+        let (_, _, dz, ds) = newton(&bx, &by, &bz, &bs);
+
+        let step = cones.max_step(&s, &ds).min(cones.max_step(&z, &dz)).min(1.0);
+        let gap_affine: f64 = (0..m).map(|i| (s[i] + step*ds[i])*(z[i] + step*dz[i])).sum();
+        let sigma = (gap_affine/gap).clamp(0.0, 1.0).powi(3);
+
+        // corrector (centering and second order term)
+
+        let cross = cones.product(&cones.scale(&scaling, &ds, true), &cones.scale(&scaling, &dz, false));
+        let bs: Vec<f64> = (0..m).map(|i| -ll[i] - cross[i] + sigma*mu*e[i]).collect();
+        let (dx, dy, dz, ds) = newton(&bx, &by, &bz, &bs);
+
+        let step = (IPM_STEP*cones.max_step(&s, &ds).min(cones.max_step(&z, &dz))).min(1.0);
+        for i in 0..n {
+            x[i] += step*dx[i];
+        }
+        for i in 0..ne {
+            y[i] += step*dy[i];
+        }
+        for i in 0..m {
+            s[i] += step*ds[i];
+            z[i] += step*dz[i];
+        }
+
+        if !x.iter().chain(&s).chain(&z).all(|a| a.is_finite()) {
+            break;
+        }
+    }
+
+    (SocpStatus::MaxIterations, x)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::assert_approx_eq;
+
+    #[test]
+    fn test_socp_1_lp() {
+        // min -x0 - x1, x0 + 2*x1 <= 4, 3*x0 + x1 <= 6, x >= 0: (1.6, 1.2)
+        let p = Socp {
+            c: vec![-1.0, -1.0],
+            e: vec![],
+            f: vec![],
+            g: vec![
+                vec![(0, -1.0), (1, -2.0)],
+                vec![(0, -3.0), (1, -1.0)],
+                vec![(0, 1.0)],
+                vec![(1, 1.0)],
+            ],
+            h: vec![-4.0, -6.0, 0.0, 0.0],
+            cones: vec![Cone::NonNegative(4)],
+        };
+
+        let (status, x) = solve(&p);
+        assert_eq!(status, SocpStatus::Solved);
+        assert_approx_eq!(x[0], 1.6, 1e-6);
+        assert_approx_eq!(x[1], 1.2, 1e-6);
+    }
+
+    #[test]
+    fn test_socp_2_soc() {
+        // min x0 + x1, x2 = 1, |(x0, x1)| <= x2: -(1, 1)/sqrt(2)
+        let p = Socp {
+            c: vec![1.0, 1.0, 0.0],
+            e: vec![vec![(2, 1.0)]],
+            f: vec![1.0],
+            g: vec![vec![(2, 1.0)], vec![(0, 1.0)], vec![(1, 1.0)]],
+            h: vec![0.0, 0.0, 0.0],
+            cones: vec![Cone::SecondOrder(3)],
+        };
+
+        let (status, x) = solve(&p);
+        assert_eq!(status, SocpStatus::Solved);
+        assert_approx_eq!(x[0], -0.5f64.sqrt(), 1e-6);
+        assert_approx_eq!(x[1], -0.5f64.sqrt(), 1e-6);
+        assert_approx_eq!(x[2], 1.0, 1e-6);
+
+        // infeasible: x2 = -1
+        let mut infeasible = p;
+        infeasible.f = vec![-1.0];
+        let (status, _x) = solve(&infeasible);
+        assert_eq!(status, SocpStatus::MaxIterations);
+    }
+}
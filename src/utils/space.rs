@@ -9,6 +9,8 @@ use uom::si::ratio::{percent, ratio};
 use uom::si::time::second;
 use uom::si::velocity::meter_per_second;
 
+use crate::adapters::common::Touchdown;
+use crate::conf::Scenario;
 use crate::gnc::common::Spacecraft;
 use crate::squared;
 
@@ -18,43 +20,66 @@ lazy_static! {
     pub static ref G0: Acceleration = Acceleration::new::<meter_per_second_squared>(9.806);
 }
 
+// landing gear design limits of the Apollo LM: 10 ft/s vertical, 4 ft/s horizontal
+const TOUCHDOWN_VEL_Y_MAX: f64 = 3.0;
+const TOUCHDOWN_VEL_H_MAX: f64 = 1.2;
 
-/// Estimate TGO for current spacecraft properties and pos and vel goals
+
+/// Full throttle mass flow, for a given thrust and Isp
+///
+/// thrust/(Isp*g0), scaled from the nominal mass flow (the nominal values are
+/// not always exactly consistent).
+pub fn mass_flow(conf: &Scenario, thrust: Force, isp: Time) -> MassRate {
+    conf.sc_nominal_mass_flow * (thrust/conf.sc_nominal_thrust) * (conf.sc_nominal_isp/isp)
+}
+
+
+/// Estimate TGO for current spacecraft properties (estimated thrust and Isp)
+/// and pos and vel goals
 ///
 /// dv_x = diff vel_x
+/// dv_z = diff vel_z
 /// dv_y_vel = diff vel_y
 /// dv_y_gravity = integral from 0 to tgo of body_gravity()-body_centrifugal()
 ///     Note: vx = vx0 - t*vx0/tgo = vx0/tgo * (tgo-t)
-pub fn tgo_estimate(craft: &Spacecraft, final_vel_x_goal: Velocity, final_vel_y_goal: Velocity, thrust_mul: Ratio) -> Time {
-    let conf = craft.conf.s;
+pub fn tgo_estimate(craft: &Spacecraft, final_vel_x_goal: Velocity, final_vel_y_goal: Velocity, final_vel_z_goal: Velocity, thrust_mul: Ratio) -> Time {
+    let conf = &craft.conf.s;
 
     let mass = conf.sc_dry_mass + craft.cur.fuel_mass;
 
     let dv_x = craft.cur.vel.x-final_vel_x_goal;
     let dv_y_vel = craft.cur.vel.y-final_vel_y_goal;
+    let dv_z = craft.cur.vel.z-final_vel_z_goal;
+
+    let dv_horiz = (squared!(dv_x) + squared!(dv_z)).sqrt();
 
     let mut tgo = Time::new::<second>(0.0);  // initial estimate
     for _ in 0..5 {  // 5 loops are more than enough
         let dv_y_gravity = tgo * (
             conf.body.gravity(craft.cur.pos.y)
-            - (squared!(craft.cur.vel.x)) / (3.0 * conf.body.radius)
+            - (squared!(craft.cur.vel_inertial.horizontal_norm())) / (3.0 * conf.body.radius)
         );
         // x**2+y**2 underestimate ; x+y overestimate ; it is better to over estimate
-        let dv = dv_x.abs() + (dv_y_gravity-dv_y_vel).abs();
-        let epow: Ratio = dv/(conf.sc_nominal_isp*(*G0));
+        let dv = dv_horiz + (dv_y_gravity-dv_y_vel).abs();
+        let epow: Ratio = dv/(craft.cur.isp*(*G0));
         tgo = mass * (
             1.0 - 1.0 / E.powf(epow.get::<ratio>())
-        ) / (conf.sc_nominal_mass_flow*thrust_mul);
+        ) / (mass_flow(conf, craft.cur.thrust, craft.cur.isp)*thrust_mul);
     }
 
     tgo
 }
 
 
+/// Touchdown sensed (cf. GuiSpacecraft::GuiVerticalDescent), or close enough to the target
+///
+/// From the navigation state, for the adapters without a true touchdown (KSP),
+/// cf. has_safely_touched_down()
+#[allow(clippy::needless_bool)]
 pub fn has_softly_landed(craft: &Spacecraft) -> bool {
     if (
-        (craft.cur.pos.y < Length::new::<meter>(1.0))
-        && (craft.cur.vel.y < Velocity::new::<meter_per_second>(0.2)) && (craft.cur.vel.x < Velocity::new::<meter_per_second>(1.0))
+        (craft.cur.contact || (craft.cur.pos.y < Length::new::<meter>(1.0)))
+        && (craft.cur.vel.y < Velocity::new::<meter_per_second>(0.2)) && (craft.cur.vel.horizontal_norm() < Velocity::new::<meter_per_second>(1.0))
         && (craft.cur.eng_throttle < Ratio::new::<percent>(30.0)) && (craft.cur.ang_pos < Angle::new::<degree>(100.0))
     ) {
         true
@@ -62,3 +87,12 @@ pub fn has_softly_landed(craft: &Spacecraft) -> bool {
         false
     }
 }
+
+
+/// True touchdown (simulator) on a safe slope, upright, at rest, and within the
+/// landing gear speed limits
+pub fn has_safely_touched_down(touchdown: &Touchdown) -> bool {
+    touchdown.safe && !touchdown.tipped_over && touchdown.t_rest.is_some()
+    && (touchdown.vel.y.abs() <= Velocity::new::<meter_per_second>(TOUCHDOWN_VEL_Y_MAX))
+    && (touchdown.vel.horizontal_norm() <= Velocity::new::<meter_per_second>(TOUCHDOWN_VEL_H_MAX))
+}
@@ -1,17 +1,25 @@
 use serde::{Serialize, Deserialize};
 use uom::si::f64::*;
 use uom::si::acceleration::meter_per_second_squared;
+use uom::si::angle::radian;
 use uom::si::angular_acceleration::radian_per_second_squared;
 use uom::si::angular_velocity::radian_per_second;
 use uom::si::ratio::ratio;
 use uom::si::time::second;
 use uom::si::velocity::meter_per_second;
 
-use crate::conf::Scenario;
-use crate::utils::math::Vec2;
+use crate::conf::{Scenario, NavSpacecraft};
+use crate::gnc::engine::{self, EngineCovariance, ENGINE_STATES};
+use crate::gnc::kalman::{Covariance, NAV_STATES, init};
+use crate::utils::frames::{Lvlh, inertial_velocity};
+use crate::utils::math::{Vec3, Quaternion};
 
 
 /// changing properties
+///
+/// Translation is in the local frame (x downrange, y up, z cross-range),
+/// angular velocity and acceleration are in the body frame (x is the thrust
+/// axis).
 #[derive(Debug)]
 #[derive(Clone, Copy)]
 #[derive(Serialize, Deserialize)]
@@ -23,6 +31,8 @@ pub struct SpacecraftDynamic {
     pub dt: Time,
 
     pub fuel_mass: Mass,
+    pub thrust: Force,                  // full throttle, estimated with NavEngine (cf. gnc::engine)
+    pub isp: Time,
 
     // nav acc
 
@@ -33,26 +43,42 @@ pub struct SpacecraftDynamic {
 
     // nav trans
 
-    pub pos: Vec2<Length>,
-    pub vel: Vec2<Velocity>,
-    pub acc: Vec2<Acceleration>,
+    pub pos: Vec3<Length>,              // fixed to the surface, cf. utils::frames
+    pub vel: Vec3<Velocity>,            // surface-relative
+    pub vel_inertial: Vec3<Velocity>,   // inertial, expressed in the local frame
+    pub acc: Vec3<Acceleration>,        // of the surface-relative velocity
 
     pub dv: Velocity,                   // dv expended until now
 
     // nav ang
 
-    pub ang_pos: Angle,
-    pub ang_vel: AngularVelocity,
-    pub ang_acc: AngularAcceleration,
+    pub att: Quaternion,                // body to local frame
+    pub ang_pos: Angle,                 // pitch, cf. Quaternion::to_euler()
+    pub heading: Angle,
+    pub roll: Angle,
+    pub ang_vel: Vec3<AngularVelocity>,
+    pub ang_acc: Vec3<AngularAcceleration>,
+
+    pub contact: bool,                  // touchdown sensed
+
+    // nav error covariance (pos, vel, att), cf. gnc::kalman. Null with NavDeadReckoning
+
+    pub nav_cov: Covariance,
+
+    // engine estimate covariance (fuel mass, thrust, isp), cf. gnc::engine. Null without nav_engine
+
+    pub eng_cov: EngineCovariance,
 
     // gui
 
-    pub gui: Vec2<Acceleration>,
+    pub gui: Vec3<Acceleration>,
 
     // ctr
 
     pub eng_throttle: Ratio,            // range: [0; 1]
-    pub eng_gimbal: Ratio,              // range: [-1; +1]
+    pub eng_gimbal: Ratio,              // range: [-1; +1], pitch
+    pub eng_gimbal_yaw: Ratio,          // range: [-1; +1]
+    pub rcs_roll: Ratio,                // range: [-1; +1]
 }
 
 
@@ -64,8 +90,12 @@ impl SpacecraftDynamic {
 
             eng_throttle: Ratio::new::<ratio>(0.0),
             fuel_mass: conf.initial_sc_fuel_mass,
+            thrust: conf.sc_nominal_thrust,
+            isp: conf.sc_nominal_isp,
 
             eng_gimbal: Ratio::new::<ratio>(0.0),
+            eng_gimbal_yaw: Ratio::new::<ratio>(0.0),
+            rcs_roll: Ratio::new::<ratio>(0.0),
 
             acc_thrust: Acceleration::new::<meter_per_second_squared>(0.0),
             acc_gravity: Acceleration::new::<meter_per_second_squared>(0.0),
@@ -74,20 +104,45 @@ impl SpacecraftDynamic {
 
             pos: conf.initial_sc_pos,
             vel: conf.initial_sc_vel,
-            acc: Vec2 {
+            vel_inertial: inertial_velocity(&conf.body, &Lvlh {pos: conf.initial_sc_pos, vel: conf.initial_sc_vel}),
+            acc: Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
 
             dv: Velocity::new::<meter_per_second>(0.0),
 
+            att: Quaternion::from_axis_angle(Vec3 {x: 0.0, y: 0.0, z: 1.0}, conf.initial_sc_ang_pos),
             ang_pos: conf.initial_sc_ang_pos,
-            ang_vel: AngularVelocity::new::<radian_per_second>(0.0),
-            ang_acc: AngularAcceleration::new::<radian_per_second_squared>(0.0),
+            heading: Angle::new::<radian>(0.0),
+            roll: Angle::new::<radian>(0.0),
+            ang_vel: Vec3 {
+                x: AngularVelocity::new::<radian_per_second>(0.0),
+                y: AngularVelocity::new::<radian_per_second>(0.0),
+                z: AngularVelocity::new::<radian_per_second>(0.0),
+            },
+            ang_acc: Vec3 {
+                x: AngularAcceleration::new::<radian_per_second_squared>(0.0),
+                y: AngularAcceleration::new::<radian_per_second_squared>(0.0),
+                z: AngularAcceleration::new::<radian_per_second_squared>(0.0),
+            },
+
+            contact: false,
+
+            nav_cov: match (conf.nav_spacecraft, conf.nav_kalman.as_ref()) {
+                (NavSpacecraft::NavKalman, Some(kalman)) => init(kalman),
+                _ => [[0.0; NAV_STATES]; NAV_STATES],
+            },
+            eng_cov: match conf.nav_engine.as_ref() {
+                Some(estimator) => engine::init(conf, estimator),
+                None => [[0.0; ENGINE_STATES]; ENGINE_STATES],
+            },
 
-            gui: Vec2 {
+            gui: Vec3 {
                 x: Acceleration::new::<meter_per_second_squared>(0.0),
                 y: Acceleration::new::<meter_per_second_squared>(0.0),
+                z: Acceleration::new::<meter_per_second_squared>(0.0),
             },
         }
     }
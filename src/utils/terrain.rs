@@ -0,0 +1,408 @@
+use serde::{Serialize, Deserialize};
+use uom::si::f64::*;
+use uom::si::angle::{degree, radian};
+use uom::si::length::meter;
+
+use crate::utils::math::Vec3;
+
+
+/// Terrain of the simulator
+///
+/// Height above the mean radius of the body, function of the ground position
+/// (LVLH x and z, cf. utils::frames). Sum of a heightmap, a constant slope,
+/// and procedural craters.
+///
+/// Yaml serialized equivalent:
+///
+/// sim_terrain:
+///     heightmap:
+///         path: conf/terrain/site.pgm
+///         origin_x: -5000.0       # ground position of the first pixel
+///         origin_z: -5000.0
+///         resolution: 10.0        # pixel size
+///         height_min: -100.0      # height of the 0 gray level, used only with PGM
+///         height_max: 100.0       # height of the max gray level, used only with PGM
+///     slope_x: 0.01
+///     slope_z: 0.0
+///     craters:
+///         - {x: 1500.0, z: 200.0, radius: 300.0, depth: 40.0, rim: 8.0}
+///     max_slope: 0.2094           # 12 deg, steepest safe touchdown
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TerrainConf {
+    pub heightmap: Option<HeightmapConf>,
+    pub slope_x: Ratio,                 // height gained per ground distance
+    pub slope_z: Ratio,
+    #[serde(default)]
+    pub craters: Vec<Crater>,
+    pub max_slope: Angle,
+}
+
+/// Heightmap file, rows along +z, columns along +x
+///
+/// Formats:
+///     PGM (P2 ascii, or P5 binary), gray levels are scaled between height_min
+///     and height_max
+///     Binary grid: width and height (u32, little endian), then width*height
+///     heights (f32, little endian, meters)
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeightmapConf {
+    pub path: String,
+    pub origin_x: Length,
+    pub origin_z: Length,
+    pub resolution: Length,
+    pub height_min: Length,
+    pub height_max: Length,
+}
+
+/// Bowl shaped crater, with a raised rim
+///
+/// With d = distance to the center / radius:
+///     inside (d < 1): rim - depth*(1 - d**2)
+///     outside: rim / d**3
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Crater {
+    pub x: Length,
+    pub z: Length,
+    pub radius: Length,
+    pub depth: Length,                  // from the rim to the floor
+    pub rim: Length,                    // height of the rim
+}
+
+
+pub struct Terrain {
+    conf: Option<TerrainConf>,
+    heightmap: Option<Heightmap>,
+}
+
+struct Heightmap {
+    width: usize,
+    height: usize,
+    heights: Vec<f64>,                  // m, row-major
+}
+
+
+impl Terrain {
+    /// Load the terrain, a flat terrain (the mean radius) if conf is None
+    pub fn new(conf: &Option<TerrainConf>) -> Result<Terrain, String> {
+        let heightmap = match conf.as_ref().and_then(|c| c.heightmap.as_ref()) {
+            Some(hm_conf) => Some(Heightmap::load(hm_conf)?),
+            None => None,
+        };
+
+        Ok(Terrain {
+            conf: conf.clone(),
+            heightmap: heightmap,
+        })
+    }
+
+    /// Height of the terrain at a ground position
+    pub fn height(&self, x: Length, z: Length) -> Length {
+        let conf = match &self.conf {
+            Some(conf) => conf,
+            None => return Length::new::<meter>(0.0),
+        };
+
+        let mut h = conf.slope_x*x + conf.slope_z*z;
+
+        if let (Some(hm), Some(hm_conf)) = (&self.heightmap, &conf.heightmap) {
+            let i = ((x-hm_conf.origin_x)/hm_conf.resolution).value;
+            let j = ((z-hm_conf.origin_z)/hm_conf.resolution).value;
+            h += Length::new::<meter>(hm.at(i, j));
+        }
+
+        for c in conf.craters.iter() {
+            let d = ((x-c.x).value.hypot((z-c.z).value)/c.radius.value).max(1e-9);
+            h += if d < 1.0 {
+                c.rim - c.depth*(1.0 - d*d)
+            } else {
+                c.rim/d.powi(3)
+            };
+        }
+
+        h
+    }
+
+    /// Local slope of the terrain at a ground position (angle between the
+    /// terrain normal and the local vertical)
+    pub fn slope(&self, x: Length, z: Length) -> Angle {
+        let (dh_dx, dh_dz) = self.gradient(x, z);
+        Angle::new::<radian>((dh_dx*dh_dx + dh_dz*dh_dz).sqrt().atan())
+    }
+
+    /// Unit normal of the terrain at a ground position, LVLH frame
+    pub fn normal(&self, x: Length, z: Length) -> Vec3<f64> {
+        let (dh_dx, dh_dz) = self.gradient(x, z);
+        Vec3 {x: -dh_dx, y: 1.0, z: -dh_dz}.unit()
+    }
+
+    /// Central differences
+    fn gradient(&self, x: Length, z: Length) -> (f64, f64) {
+        let step = Length::new::<meter>(0.5);
+        let dh_dx = ((self.height(x+step, z) - self.height(x-step, z))/(2.0*step)).value;
+        let dh_dz = ((self.height(x, z+step) - self.height(x, z-step))/(2.0*step)).value;
+
+        (dh_dx, dh_dz)
+    }
+
+    /// Steepest slope allowed for a safe touchdown
+    pub fn max_slope(&self) -> Angle {
+        match &self.conf {
+            Some(conf) => conf.max_slope,
+            None => Angle::new::<degree>(90.0),
+        }
+    }
+}
+
+
+impl Heightmap {
+    fn load(conf: &HeightmapConf) -> Result<Heightmap, String> {
+        let data = std::fs::read(&conf.path)
+            .map_err(|e| format!("terrain: cannot read `{}`: {}", conf.path, e))?;
+
+        let hm = if data.starts_with(b"P2") || data.starts_with(b"P5") {
+            Heightmap::from_pgm(&data, conf.height_min.get::<meter>(), conf.height_max.get::<meter>())
+        } else {
+            Heightmap::from_grid(&data)
+        };
+
+        hm.map_err(|e| format!("terrain: `{}`: {}", conf.path, e))
+    }
+
+    fn from_pgm(data: &[u8], height_min: f64, height_max: f64) -> Result<Heightmap, String> {
+        // header: magic, width, height, maxval, separated by whitespaces or comments
+        let mut tokens = vec![];
+        let mut pos = 0;
+        while tokens.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let start = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if start == pos {
+                return Err("truncated PGM header".to_string());
+            }
+            tokens.push(String::from_utf8_lossy(&data[start..pos]).to_string());
+        }
+        pos += 1;  // single whitespace before the raster
+
+        let parse = |s: &str| s.parse::<usize>().map_err(|_| format!("invalid PGM header value `{}`", s));
+        let (width, height, maxval) = (parse(&tokens[1])?, parse(&tokens[2])?, parse(&tokens[3])?);
+        if maxval == 0 || maxval > 65535 {
+            return Err(format!("invalid PGM maxval {}", maxval));
+        }
+
+        let levels: Vec<usize> = if tokens[0] == "P2" {
+            String::from_utf8_lossy(&data[pos.min(data.len())..])
+                .split_ascii_whitespace()
+                .map(parse)
+                .collect::<Result<_, _>>()?
+        } else if maxval < 256 {
+            data[pos.min(data.len())..].iter().map(|b| *b as usize).collect()
+        } else {
+            data[pos.min(data.len())..].chunks_exact(2).map(|b| ((b[0] as usize) << 8) + b[1] as usize).collect()
+        };
+
+        let pixels = width.checked_mul(height).ok_or_else(|| format!("PGM too large ({}x{})", width, height))?;
+        if levels.len() < pixels {
+            return Err(format!("expected {}x{} pixels, got {}", width, height, levels.len()));
+        }
+
+        let k = (height_max-height_min)/(maxval as f64);
+        Heightmap::new(width, height, levels.iter().take(pixels).map(|l| height_min + k*(*l as f64)).collect())
+    }
+
+    fn from_grid(data: &[u8]) -> Result<Heightmap, String> {
+        let u32_at = |i: usize| -> Result<usize, String> {
+            data.get(i..i+4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+                .ok_or_else(|| "truncated grid header".to_string())
+        };
+        let (width, height) = (u32_at(0)?, u32_at(4)?);
+
+        // from the untrusted header
+        let count = width.checked_mul(height).filter(|count| count.checked_mul(4).is_some())
+            .ok_or_else(|| format!("grid too large ({}x{})", width, height))?;
+
+        let raster = &data[8..];
+        if raster.len() < 4*count {
+            return Err(format!("expected {}x{} heights, got {}", width, height, raster.len()/4));
+        }
+
+        Heightmap::new(width, height, raster.chunks_exact(4).take(count).map(
+            |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64
+        ).collect())
+    }
+
+    fn new(width: usize, height: usize, heights: Vec<f64>) -> Result<Heightmap, String> {
+        if width < 2 || height < 2 {
+            return Err(format!("heightmap too small ({}x{})", width, height));
+        }
+
+        Ok(Heightmap {
+            width: width,
+            height: height,
+            heights: heights,
+        })
+    }
+
+    /// Bilinear interpolation at (fractional) pixel coordinates, clamped to the edges
+    fn at(&self, i: f64, j: f64) -> f64 {
+        let i = i.clamp(0.0, (self.width-1) as f64);
+        let j = j.clamp(0.0, (self.height-1) as f64);
+        let (i0, j0) = ((i.floor() as usize).min(self.width-2), (j.floor() as usize).min(self.height-2));
+        let (ki, kj) = (i - i0 as f64, j - j0 as f64);
+
+        let h = |i: usize, j: usize| self.heights[j*self.width + i];
+
+        let h0 = h(i0, j0)*(1.0-ki) + h(i0+1, j0)*ki;
+        let h1 = h(i0, j0+1)*(1.0-ki) + h(i0+1, j0+1)*ki;
+
+        h0*(1.0-kj) + h1*kj
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use uom::si::ratio::ratio;
+
+    use crate::assert_approx_eq;
+
+    fn meters(m: f64) -> Length {
+        Length::new::<meter>(m)
+    }
+
+    fn terrain_conf() -> TerrainConf {
+        TerrainConf {
+            heightmap: None,
+            slope_x: Ratio::new::<ratio>(0.0),
+            slope_z: Ratio::new::<ratio>(0.0),
+            craters: vec![],
+            max_slope: Angle::new::<degree>(12.0),
+        }
+    }
+
+    fn heightmap_conf(path: &std::path::Path) -> HeightmapConf {
+        HeightmapConf {
+            path: path.to_str().unwrap().to_string(),
+            origin_x: meters(-10.0),
+            origin_z: meters(0.0),
+            resolution: meters(10.0),
+            height_min: meters(-50.0),
+            height_max: meters(50.0),
+        }
+    }
+
+    #[test]
+    fn test_terrain_1_procedural() {
+        let flat = Terrain::new(&None).unwrap();
+        assert_eq!(flat.height(meters(1e3), meters(-1e3)), meters(0.0));
+        assert_eq!(flat.slope(meters(1e3), meters(-1e3)), Angle::new::<degree>(0.0));
+
+        // constant slope
+        let mut conf = terrain_conf();
+        conf.slope_x = Ratio::new::<ratio>(0.1);
+        let terrain = Terrain::new(&Some(conf)).unwrap();
+        assert_approx_eq!(terrain.height(meters(100.0), meters(50.0)), meters(10.0), meters(1e-9));
+        assert_approx_eq!(terrain.slope(meters(100.0), meters(50.0)).get::<radian>(), 0.1f64.atan(), 1e-9);
+
+        // crater: floor at the center, rim at the edge, flat far away
+        let mut conf = terrain_conf();
+        conf.craters.push(Crater {x: meters(500.0), z: meters(0.0), radius: meters(100.0), depth: meters(20.0), rim: meters(5.0)});
+        let terrain = Terrain::new(&Some(conf)).unwrap();
+        assert_approx_eq!(terrain.height(meters(500.0), meters(0.0)), meters(-15.0), meters(1e-9));
+        assert_approx_eq!(terrain.height(meters(600.0), meters(0.0)), meters(5.0), meters(1e-9));
+        assert!(terrain.height(meters(0.0), meters(0.0)).abs() < meters(0.05));
+        assert!(terrain.slope(meters(590.0), meters(0.0)) > terrain.max_slope());
+        assert!(terrain.slope(meters(500.0), meters(0.0)) < Angle::new::<degree>(0.1));
+    }
+
+    #[test]
+    fn test_terrain_2_heightmap() {
+        let dir = std::env::temp_dir();
+
+        // 3x2 PGM, ascii, with a comment
+        let path = dir.join("moon_lander_test_terrain_2.pgm");
+        std::fs::write(&path, "P2\n# test\n3 2\n100\n0 50 100\n50 50 50\n").unwrap();
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&path));
+        let terrain = Terrain::new(&Some(conf)).unwrap();
+
+        assert_approx_eq!(terrain.height(meters(-10.0), meters(0.0)), meters(-50.0), meters(1e-9));
+        assert_approx_eq!(terrain.height(meters(5.0), meters(0.0)), meters(25.0), meters(1e-9));
+        assert_approx_eq!(terrain.height(meters(5.0), meters(5.0)), meters(12.5), meters(1e-9));
+        // clamped to the edges
+        assert_approx_eq!(terrain.height(meters(1e3), meters(-1e3)), meters(50.0), meters(1e-9));
+
+        // same, binary PGM
+        let path = dir.join("moon_lander_test_terrain_2b.pgm");
+        let mut data = b"P5 3 2 100\n".to_vec();
+        data.extend_from_slice(&[0, 50, 100, 50, 50, 50]);
+        std::fs::write(&path, data).unwrap();
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&path));
+        let terrain = Terrain::new(&Some(conf)).unwrap();
+        assert_approx_eq!(terrain.height(meters(5.0), meters(0.0)), meters(25.0), meters(1e-9));
+
+        // binary grid
+        let path = dir.join("moon_lander_test_terrain_2.bin");
+        let mut data = vec![];
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&2u32.to_le_bytes());
+        for h in [1.0f32, 2.0, 3.0, 4.0].iter() {
+            data.extend_from_slice(&h.to_le_bytes());
+        }
+        std::fs::write(&path, data).unwrap();
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&path));
+        let terrain = Terrain::new(&Some(conf)).unwrap();
+        assert_approx_eq!(terrain.height(meters(-5.0), meters(5.0)), meters(2.5), meters(1e-6));
+
+        // errors
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&dir.join("moon_lander_test_terrain_2_missing.pgm")));
+        assert!(Terrain::new(&Some(conf)).is_err());
+
+        let path = dir.join("moon_lander_test_terrain_2c.pgm");
+        std::fs::write(&path, "P2\n3 2\n100\n0 50\n").unwrap();
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&path));
+        assert!(Terrain::new(&Some(conf)).is_err());
+
+        // sizes overflowing
+        let path = dir.join("moon_lander_test_terrain_2d.pgm");
+        std::fs::write(&path, format!("P2\n{} 2\n100\n0 50\n", usize::MAX)).unwrap();
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&path));
+        assert!(Terrain::new(&Some(conf)).is_err());
+
+        let path = dir.join("moon_lander_test_terrain_2e.bin");
+        let mut data = vec![];
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        data.extend_from_slice(&1.0f32.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+        let mut conf = terrain_conf();
+        conf.heightmap = Some(heightmap_conf(&path));
+        assert!(Terrain::new(&Some(conf)).is_err());
+    }
+}
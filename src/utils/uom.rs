@@ -12,23 +12,6 @@ macro_rules! sqrt {
     }};
 }
 
-#[macro_export]
-macro_rules! mul {
-    ($a:expr, $b:expr) => {{
-        Vec2 {
-            x: $a.x * $b,
-            y: $a.y * $b,
-        }
-    }};
-}
-
-#[macro_export]
-macro_rules! norm {
-    ($quantity:expr) => {{
-        sqrt!(squared!($quantity.x) + squared!($quantity.y))
-    }};
-}
-
 
 #[macro_export]
 macro_rules! modulo {